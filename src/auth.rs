@@ -0,0 +1,423 @@
+use std::collections::{HashMap, HashSet};
+use std::fmt::Write;
+use std::path::Path;
+use std::sync::Arc;
+
+use actix_web::body::{EitherBody, MessageBody};
+use actix_web::dev::{ServiceRequest, ServiceResponse};
+use actix_web::http::header::{HeaderMap, AUTHORIZATION, WWW_AUTHENTICATE};
+use actix_web::http::StatusCode;
+use actix_web::middleware::Next;
+use actix_web::{web, HttpMessage, HttpResponse, Result};
+use ring::digest::{digest, SHA256};
+use ring::rand::{SecureRandom, SystemRandom};
+use serde::Deserialize;
+
+use crate::config::parse_document;
+use crate::rate_limit::Limit;
+use crate::tls::ClientIdentity;
+use crate::{AppState, ErrorResponse};
+
+/// Endpoints that need an API key when a key file is configured, named by the
+/// first segment of their route. `/health`, `/ready` and `/metrics` stay open
+/// for probes and scrapers.
+pub const API_ENDPOINTS: [&str; 3] = ["/id", "/ids", "/decode"];
+
+/// Prefix of generated tokens, so secret scanners can recognise them.
+const TOKEN_PREFIX: &str = "idg_";
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+struct KeyFile {
+    keys: Vec<ApiKey>,
+}
+
+/// An entry in the key file. Only the SHA-256 of the token is stored; tokens
+/// are random, so an unsalted hash is as strong as the token itself.
+#[derive(Debug, Clone, Deserialize, PartialEq)]
+#[serde(deny_unknown_fields)]
+pub struct ApiKey {
+    /// Names the key in errors and metrics.
+    pub id: String,
+    /// Hex-encoded SHA-256 of the bearer token.
+    pub secret_sha256: String,
+    /// Largest count for `/ids/{count}`, capped by `limits.max_ids_per_request`.
+    #[serde(default)]
+    pub max_ids_per_request: Option<u64>,
+    /// Endpoints from [`API_ENDPOINTS`] the key may call; all of them if unset.
+    #[serde(default)]
+    pub endpoints: Option<Vec<String>>,
+    /// Namespaces the key may use. When set, requests must name one with
+    /// `?namespace=`, or `namespace` metadata over gRPC. The namespace only
+    /// authorizes the request and has no effect on the IDs returned.
+    #[serde(default)]
+    pub namespaces: Option<Vec<String>>,
+    /// Rate limit for this key, replacing the default from `rate_limit`.
+    #[serde(default)]
+    pub ids_per_sec: Option<f64>,
+    /// Bucket size for `ids_per_sec`; one second's worth by default.
+    #[serde(default)]
+    pub burst: Option<u64>,
+    /// Only accept the key over mutual TLS from a client certificate with this
+    /// subject, e.g. `CN=billing, O=Example`.
+    #[serde(default)]
+    pub client_subject: Option<String>,
+}
+
+impl ApiKey {
+    /// Checks whether this key may call `endpoint` with `namespace`, returning
+    /// the reason it may not.
+    pub fn authorize(
+        &self,
+        endpoint: &str,
+        namespace: Option<&str>,
+        client: Option<&ClientIdentity>,
+    ) -> Result<(), String> {
+        if let Some(subject) = &self.client_subject {
+            if client.map(|client| &client.subject) != Some(subject) {
+                return Err(format!(
+                    "API key '{}' must be used with the client certificate '{}'",
+                    self.id, subject
+                ));
+            }
+        }
+
+        if let Some(endpoints) = &self.endpoints {
+            if !endpoints.iter().any(|allowed| allowed == endpoint) {
+                return Err(format!("API key '{}' may not call {}", self.id, endpoint));
+            }
+        }
+
+        if let Some(namespaces) = &self.namespaces {
+            match namespace {
+                None => return Err(format!("API key '{}' must name a namespace", self.id)),
+                Some(namespace) if !namespaces.iter().any(|allowed| allowed == namespace) => {
+                    return Err(format!("API key '{}' may not use namespace '{}'", self.id, namespace));
+                }
+                Some(_) => {}
+            }
+        }
+        Ok(())
+    }
+
+    /// The key's bulk limit, never above `default`.
+    pub fn max_ids_per_request(key: Option<&ApiKey>, default: u64) -> u64 {
+        key.and_then(|key| key.max_ids_per_request).map_or(default, |max| max.min(default))
+    }
+
+    /// The key's own rate limit, if it sets one.
+    pub fn rate_limit(&self) -> Option<Limit> {
+        self.ids_per_sec.map(|ids_per_sec| Limit::new(ids_per_sec, self.burst))
+    }
+
+    fn validate(&self) -> Result<(), String> {
+        check_key_id(&self.id)?;
+        if let Some(max) = self.max_ids_per_request {
+            if max == 0 {
+                return Err("max_ids_per_request must be at least 1".to_string());
+            }
+        }
+        if let Some(unknown) = self
+            .endpoints
+            .iter()
+            .flatten()
+            .find(|endpoint| !API_ENDPOINTS.contains(&endpoint.as_str()))
+        {
+            return Err(format!(
+                "endpoints must be among {}, got: '{}'",
+                API_ENDPOINTS.join(", "),
+                unknown
+            ));
+        }
+        match (self.ids_per_sec, self.burst) {
+            (Some(rate), _) if !rate.is_finite() || rate <= 0.0 => {
+                return Err(format!("ids_per_sec must be a positive number, got: {}", rate))
+            }
+            (_, Some(0)) => return Err("burst must be at least 1".to_string()),
+            (None, Some(_)) => return Err("burst needs ids_per_sec".to_string()),
+            _ => {}
+        }
+        if self.namespaces.iter().flatten().any(String::is_empty) {
+            return Err("namespaces must not be empty strings".to_string());
+        }
+        Ok(())
+    }
+}
+
+/// Key IDs end up in metric labels, errors and generated key file entries, so
+/// they are limited to `[A-Za-z0-9_.-]+`.
+pub fn check_key_id(id: &str) -> Result<(), String> {
+    if id.is_empty() {
+        return Err("id must not be empty".to_string());
+    }
+    if let Some(invalid) = id
+        .chars()
+        .find(|c| !(c.is_ascii_alphanumeric() || matches!(c, '_' | '.' | '-')))
+    {
+        return Err(format!(
+            "id may only contain letters, digits, '_', '.' and '-', got: {:?}",
+            invalid
+        ));
+    }
+    Ok(())
+}
+
+/// API keys indexed by the hash of their token.
+#[derive(Debug)]
+pub struct ApiKeys {
+    by_hash: HashMap<[u8; 32], Arc<ApiKey>>,
+}
+
+impl ApiKeys {
+    /// Reads and validates a TOML or YAML key file.
+    pub fn load(path: &Path) -> Result<Self, String> {
+        let error = |message: String| format!("Key file {}: {}", path.display(), message);
+
+        let contents = std::fs::read_to_string(path).map_err(|e| error(e.to_string()))?;
+        let extension = path.extension().and_then(|extension| extension.to_str()).unwrap_or("");
+        let file: KeyFile = parse_document(&contents, extension).map_err(error)?;
+        Self::new(file.keys).map_err(error)
+    }
+
+    pub fn new(keys: Vec<ApiKey>) -> Result<Self, String> {
+        let mut ids = HashSet::new();
+        let mut by_hash = HashMap::new();
+
+        for key in keys {
+            key.validate().map_err(|e| format!("key '{}': {}", key.id, e))?;
+            let hash = parse_hex_digest(&key.secret_sha256)
+                .ok_or_else(|| format!("key '{}': secret_sha256 must be 64 hex characters", key.id))?;
+            if !ids.insert(key.id.clone()) {
+                return Err(format!("key '{}' is listed more than once", key.id));
+            }
+            if let Some(other) = by_hash.insert(hash, Arc::new(key)) {
+                return Err(format!("key '{}' has the same secret as another key", other.id));
+            }
+        }
+        Ok(Self { by_hash })
+    }
+
+    /// Looks up the key for a bearer token.
+    pub fn authenticate(&self, token: &str) -> Option<&Arc<ApiKey>> {
+        let hash = digest(&SHA256, token.as_bytes());
+        self.by_hash.get(hash.as_ref())
+    }
+
+    pub fn len(&self) -> usize {
+        self.by_hash.len()
+    }
+}
+
+fn parse_hex_digest(hex: &str) -> Option<[u8; 32]> {
+    if hex.len() != 64 || !hex.bytes().all(|byte| byte.is_ascii_hexdigit()) {
+        return None;
+    }
+    let mut bytes = [0; 32];
+    for (byte, pair) in bytes.iter_mut().zip(hex.as_bytes().chunks(2)) {
+        *byte = u8::from_str_radix(std::str::from_utf8(pair).ok()?, 16).ok()?;
+    }
+    Some(bytes)
+}
+
+fn to_hex(bytes: &[u8]) -> String {
+    bytes.iter().fold(String::with_capacity(bytes.len() * 2), |mut hex, byte| {
+        // Writing to a String cannot fail
+        let _ = write!(hex, "{:02x}", byte);
+        hex
+    })
+}
+
+/// Hex-encoded SHA-256 of `token`, as stored in `secret_sha256`.
+pub fn hash_token(token: &str) -> String {
+    to_hex(digest(&SHA256, token.as_bytes()).as_ref())
+}
+
+/// A new random token with 256 bits of entropy.
+pub fn generate_token() -> Result<String, String> {
+    let mut bytes = [0u8; 32];
+    SystemRandom::new()
+        .fill(&mut bytes)
+        .map_err(|_| "Failed to read random bytes".to_string())?;
+    Ok(format!("{}{}", TOKEN_PREFIX, to_hex(&bytes)))
+}
+
+/// The endpoint a route pattern belongs to, if it needs an API key.
+fn endpoint(pattern: &str) -> Option<&'static str> {
+    let first = pattern.split('/').nth(1)?;
+    API_ENDPOINTS.into_iter().find(|endpoint| endpoint[1..] == *first)
+}
+
+fn bearer_token(headers: &HeaderMap) -> Option<&str> {
+    parse_bearer(headers.get(AUTHORIZATION)?.to_str().ok()?)
+}
+
+/// The token from an `Authorization: Bearer <token>` value.
+pub fn parse_bearer(value: &str) -> Option<&str> {
+    let (scheme, token) = value.split_once(' ')?;
+    let token = token.trim();
+    (scheme.eq_ignore_ascii_case("bearer") && !token.is_empty()).then_some(token)
+}
+
+#[derive(Deserialize)]
+struct NamespaceQuery {
+    namespace: Option<String>,
+}
+
+/// Requires a valid API key for [`API_ENDPOINTS`] when a key file is
+/// configured, and stores the key in the request extensions for handlers.
+pub async fn authenticate(
+    req: ServiceRequest,
+    next: Next<impl MessageBody>,
+) -> Result<ServiceResponse<EitherBody<impl MessageBody>>> {
+    let keys = req.app_data::<web::Data<AppState>>().and_then(|data| data.api_keys.clone());
+    let endpoint = req.match_pattern().and_then(|pattern| endpoint(&pattern));
+    let (Some(keys), Some(endpoint)) = (keys, endpoint) else {
+        return next.call(req).await.map(ServiceResponse::map_into_left_body);
+    };
+
+    let key = match bearer_token(req.headers()) {
+        None => return Ok(reject(req, StatusCode::UNAUTHORIZED, "An API key is required as 'Authorization: Bearer <key>'")),
+        Some(token) => match keys.authenticate(token) {
+            Some(key) => key.clone(),
+            None => return Ok(reject(req, StatusCode::UNAUTHORIZED, "Invalid API key")),
+        },
+    };
+
+    // A malformed query string is left for the handler to report
+    let namespace = web::Query::<NamespaceQuery>::from_query(req.query_string())
+        .ok()
+        .and_then(|query| query.into_inner().namespace);
+    let authorized = key.authorize(endpoint, namespace.as_deref(), req.conn_data::<ClientIdentity>());
+    if let Err(e) = authorized {
+        return Ok(reject(req, StatusCode::FORBIDDEN, e));
+    }
+
+    req.extensions_mut().insert(key);
+    next.call(req).await.map(ServiceResponse::map_into_left_body)
+}
+
+fn reject<B>(req: ServiceRequest, status: StatusCode, message: impl Into<String>) -> ServiceResponse<EitherBody<B>> {
+    let mut response = HttpResponse::build(status);
+    if status == StatusCode::UNAUTHORIZED {
+        response.insert_header((WWW_AUTHENTICATE, "Bearer"));
+    }
+    req.into_response(response.json(ErrorResponse::new(message))).map_into_right_body()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use actix_web::http::header::HeaderValue;
+
+    fn key(id: &str, token: &str) -> ApiKey {
+        ApiKey {
+            id: id.to_string(),
+            secret_sha256: hash_token(token),
+            max_ids_per_request: None,
+            endpoints: None,
+            namespaces: None,
+            ids_per_sec: None,
+            burst: None,
+            client_subject: None,
+        }
+    }
+
+    #[test]
+    fn test_authenticate_by_hash() {
+        let keys = ApiKeys::new(vec![key("billing", "billing-token"), key("orders", "orders-token")]).unwrap();
+        assert_eq!(keys.len(), 2);
+        assert_eq!(keys.authenticate("orders-token").unwrap().id, "orders");
+        assert!(keys.authenticate("orders-token ").is_none());
+        assert!(keys.authenticate(&hash_token("orders-token")).is_none(), "The hash isn't a token");
+
+        let token = generate_token().unwrap();
+        assert!(token.starts_with(TOKEN_PREFIX));
+        assert_eq!(token.len(), TOKEN_PREFIX.len() + 64);
+        assert_ne!(token, generate_token().unwrap());
+        assert_eq!(
+            hash_token("abc"),
+            "ba7816bf8f01cfea414140de5dae2223b00361a396177a9cb410ff61f20015ad"
+        );
+    }
+
+    #[test]
+    fn test_key_file_validation() {
+        let invalid = |keys: Vec<ApiKey>| ApiKeys::new(keys).unwrap_err();
+
+        assert!(invalid(vec![key("", "a")]).contains("id must not be empty"));
+        assert!(invalid(vec![key("a\n[[keys]]", "a")]).contains("got: '\\n'"));
+        assert!(invalid(vec![key("a\"b", "a")]).contains("may only contain"));
+        assert!(invalid(vec![ApiKey { secret_sha256: "abc".into(), ..key("a", "a") }]).contains("64 hex characters"));
+        assert!(invalid(vec![ApiKey { secret_sha256: format!("+{}", &hash_token("a")[1..]), ..key("a", "a") }])
+            .contains("64 hex characters"));
+        assert!(invalid(vec![ApiKey { max_ids_per_request: Some(0), ..key("a", "a") }]).contains("at least 1"));
+        assert!(invalid(vec![ApiKey { endpoints: Some(vec!["/metrics".into()]), ..key("a", "a") }])
+            .contains("got: '/metrics'"));
+        assert!(invalid(vec![ApiKey { namespaces: Some(vec![String::new()]), ..key("a", "a") }])
+            .contains("namespaces"));
+        assert!(invalid(vec![ApiKey { ids_per_sec: Some(0.0), ..key("a", "a") }]).contains("ids_per_sec"));
+        assert!(invalid(vec![ApiKey { burst: Some(10), ..key("a", "a") }]).contains("burst needs ids_per_sec"));
+        assert!(invalid(vec![key("a", "a"), key("a", "b")]).contains("more than once"));
+        assert!(invalid(vec![key("a", "same"), key("b", "same")]).contains("same secret"));
+
+        let err = ApiKeys::load(Path::new("/nonexistent/keys.toml")).unwrap_err();
+        assert!(err.starts_with("Key file /nonexistent/keys.toml: "), "{}", err);
+    }
+
+    #[test]
+    fn test_authorize() {
+        let open = key("open", "a");
+        assert_eq!(open.authorize("/ids", None, None), Ok(()));
+        assert_eq!(open.authorize("/ids", Some("anything"), None), Ok(()));
+
+        let restricted = ApiKey {
+            endpoints: Some(vec!["/id".into(), "/ids".into()]),
+            namespaces: Some(vec!["orders".into()]),
+            ..key("orders", "b")
+        };
+        assert_eq!(restricted.authorize("/ids", Some("orders"), None), Ok(()));
+        assert!(restricted.authorize("/decode", Some("orders"), None).unwrap_err().contains("may not call /decode"));
+        assert!(restricted.authorize("/id", None, None).unwrap_err().contains("must name a namespace"));
+        assert!(restricted.authorize("/id", Some("billing"), None).unwrap_err().contains("namespace 'billing'"));
+
+        let bound = ApiKey {
+            client_subject: Some("CN=billing".into()),
+            ..key("billing", "c")
+        };
+        let client = |subject: &str| ClientIdentity {
+            subject: subject.to_string(),
+        };
+        assert_eq!(bound.authorize("/id", None, Some(&client("CN=billing"))), Ok(()));
+        assert!(bound.authorize("/id", None, Some(&client("CN=orders"))).is_err());
+        assert!(bound.authorize("/id", None, None).unwrap_err().contains("client certificate"));
+    }
+
+    #[test]
+    fn test_max_ids_per_request() {
+        let limited = ApiKey { max_ids_per_request: Some(10), ..key("a", "a") };
+        assert_eq!(ApiKey::max_ids_per_request(Some(&limited), 1000), 10);
+        assert_eq!(ApiKey::max_ids_per_request(Some(&limited), 5), 5);
+        assert_eq!(ApiKey::max_ids_per_request(Some(&key("b", "b")), 1000), 1000);
+        assert_eq!(ApiKey::max_ids_per_request(None, 1000), 1000);
+    }
+
+    #[test]
+    fn test_endpoint_and_bearer_token() {
+        assert_eq!(endpoint("/id"), Some("/id"));
+        assert_eq!(endpoint("/ids/{count}"), Some("/ids"));
+        assert_eq!(endpoint("/decode/{id}"), Some("/decode"));
+        assert_eq!(endpoint("/health"), None);
+        assert_eq!(endpoint("/identity"), None);
+
+        let token = |value: &'static str| {
+            let mut headers = HeaderMap::new();
+            headers.insert(AUTHORIZATION, HeaderValue::from_static(value));
+            bearer_token(&headers).map(str::to_string)
+        };
+        assert_eq!(token("Bearer abc"), Some("abc".to_string()));
+        assert_eq!(token("bearer abc"), Some("abc".to_string()));
+        assert_eq!(token("Basic abc"), None);
+        assert_eq!(token("Bearer "), None);
+        assert_eq!(bearer_token(&HeaderMap::new()), None);
+    }
+}
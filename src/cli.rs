@@ -0,0 +1,327 @@
+use std::io::{self, BufWriter, Write};
+use std::net::IpAddr;
+use std::path::PathBuf;
+
+use clap::{Args, Parser, Subcommand};
+use id_generator::encoding::Encoding;
+use id_generator::{GeneratorOptions, Layout, SnowflakeGenerator};
+
+use crate::auth;
+use crate::config::Listener;
+use crate::DecodeResponse;
+
+/// Snowflake ID service. Flags take precedence over the environment variables
+/// documented in the README, which take precedence over the config file.
+#[derive(Debug, Parser)]
+#[command(name = "id-generator", version, about)]
+pub struct Cli {
+    /// TOML or YAML config file, overriding CONFIG_FILE.
+    #[arg(short, long, global = true)]
+    pub config: Option<PathBuf>,
+    /// Defaults to `serve` with no flags, as used by the Docker image.
+    #[command(subcommand)]
+    pub command: Option<Command>,
+}
+
+#[derive(Debug, Subcommand)]
+pub enum Command {
+    /// Runs the HTTP and gRPC servers.
+    Serve(ServeArgs),
+    /// Prints IDs without starting a server.
+    ///
+    /// Nothing coordinates with running instances, so use a worker ID that no
+    /// server is using.
+    Generate(GenerateArgs),
+    /// Explains IDs using the configured layout and epoch.
+    Decode(DecodeArgs),
+    /// Creates an API key, printing the token for the client and the entry
+    /// to append to the key file.
+    ApiKey(ApiKeyArgs),
+}
+
+#[derive(Debug, Default, Args)]
+pub struct ServeArgs {
+    /// HTTP listener, overriding LISTEN and server.listen: host:port,
+    /// [ipv6]:port or unix:/path. Repeat for several.
+    #[arg(short, long)]
+    pub listen: Vec<Listener>,
+    /// Listener for /health, /ready and /metrics, overriding ADMIN_LISTEN and
+    /// server.admin_listen. Repeat for several.
+    #[arg(long)]
+    pub admin_listen: Vec<Listener>,
+    /// HTTP port. Replaces the HTTP listeners with one on this port.
+    #[arg(short, long)]
+    pub port: Option<u16>,
+    /// HTTP bind address. Replaces the HTTP listeners with one on this
+    /// address.
+    #[arg(short, long)]
+    pub bind: Option<IpAddr>,
+    /// Worker ID, overriding WORKER_ID, worker.id and POD_NAME.
+    #[arg(short, long)]
+    pub worker: Option<u64>,
+    /// HTTP worker threads, overriding WORKERS.
+    #[arg(long)]
+    pub workers: Option<u32>,
+    /// gRPC port, overriding GRPC_PORT and the port of server.grpc_listen.
+    #[arg(long)]
+    pub grpc_port: Option<u16>,
+}
+
+#[derive(Debug, Args)]
+pub struct GenerateArgs {
+    /// Number of IDs to print.
+    #[arg(short = 'n', long, default_value_t = 1)]
+    pub count: u64,
+    /// Worker ID, overriding WORKER_ID, worker.id and POD_NAME.
+    #[arg(short, long)]
+    pub worker: Option<u64>,
+    /// Output encoding: decimal, padded_decimal, hex, base32 or base62.
+    #[arg(short, long, default_value = "decimal")]
+    pub format: Encoding,
+}
+
+#[derive(Debug, Args)]
+pub struct DecodeArgs {
+    /// IDs as decimal numbers or in any supported encoding.
+    #[arg(required = true)]
+    pub ids: Vec<String>,
+    /// Read every ID in this encoding instead of guessing from its width.
+    #[arg(short, long)]
+    pub format: Option<Encoding>,
+    /// Print one JSON object per ID, as returned by `/decode/{id}`.
+    #[arg(long)]
+    pub json: bool,
+}
+
+#[derive(Debug, Args)]
+pub struct ApiKeyArgs {
+    /// Name of the key in the key file, errors and metrics: letters, digits,
+    /// '_', '.' and '-'.
+    #[arg(long, value_parser = parse_key_id)]
+    pub id: String,
+}
+
+fn parse_key_id(id: &str) -> Result<String, String> {
+    auth::check_key_id(id).map(|()| id.to_string())
+}
+
+/// Writes `args.count` IDs to `out`, one per line.
+pub fn generate(args: &GenerateArgs, generator: &SnowflakeGenerator, out: impl Write) -> Result<(), String> {
+    let mut out = BufWriter::new(out);
+    let mut line = String::with_capacity(21);
+    let mut remaining = args.count;
+
+    while remaining > 0 {
+        let range = generator.next_range(remaining).map_err(|e| e.to_string())?;
+        for id in range.ids() {
+            line.clear();
+            args.format.write(id, &mut line);
+            line.push('\n');
+            write_output(&mut out, line.as_bytes())?;
+        }
+        remaining -= range.count;
+    }
+
+    match out.flush() {
+        Err(e) if e.kind() != io::ErrorKind::BrokenPipe => Err(e.to_string()),
+        _ => Ok(()),
+    }
+}
+
+/// Writes an explanation of each ID to `out`. Every ID is attempted; the
+/// error lists the ones that couldn't be decoded.
+pub fn decode(args: &DecodeArgs, layout: Layout, epoch_ms: u64, mut out: impl Write) -> Result<(), String> {
+    // Decoding doesn't depend on the worker ID, so any valid one will do
+    let options = GeneratorOptions {
+        layout,
+        epoch_ms,
+        ..Default::default()
+    };
+    let generator = SnowflakeGenerator::with_options(0, options).map_err(|e| e.to_string())?;
+    let mut failures = Vec::new();
+
+    for raw in &args.ids {
+        let parsed = match args.format {
+            Some(encoding) => encoding.decode(raw),
+            None => Encoding::decode_any(raw),
+        };
+        let decoded = parsed
+            .ok_or_else(|| format!("ID must be a valid number or encoded ID, got: '{}'", raw))
+            .and_then(|id| generator.decode(id).map(|decoded| (id, decoded)).map_err(|e| e.to_string()));
+
+        let (id, decoded) = match decoded {
+            Ok(decoded) => decoded,
+            Err(e) => {
+                failures.push(format!("{}: {}", raw, e));
+                continue;
+            }
+        };
+
+        let line = if args.json {
+            let response = DecodeResponse {
+                id: id.to_string(),
+                timestamp: decoded.timestamp,
+                unix_ms: decoded.unix_ms,
+                time: decoded.rfc3339(),
+                worker_id: decoded.worker_id,
+                sequence: decoded.sequence,
+            };
+            serde_json::to_string(&response).map_err(|e| e.to_string())?
+        } else {
+            format!(
+                "{}: time={} worker_id={} sequence={} timestamp={} unix_ms={}",
+                raw,
+                decoded.rfc3339(),
+                decoded.worker_id,
+                decoded.sequence,
+                decoded.timestamp,
+                decoded.unix_ms
+            )
+        };
+        write_output(&mut out, format!("{}\n", line).as_bytes())?;
+    }
+
+    if failures.is_empty() {
+        Ok(())
+    } else {
+        Err(failures.join("\n"))
+    }
+}
+
+/// Writes a new token as a comment, followed by its TOML key file entry.
+pub fn api_key(args: &ApiKeyArgs, mut out: impl Write) -> Result<(), String> {
+    let token = auth::generate_token()?;
+    let entry = format!(
+        "# Token for {name}, shown only once:\n# {token}\n[[keys]]\nid = {id}\nsecret_sha256 = \"{hash}\"\n",
+        name = args.id,
+        id = toml::Value::from(args.id.as_str()),
+        token = token,
+        hash = auth::hash_token(&token)
+    );
+    write_output(&mut out, entry.as_bytes())
+}
+
+/// Writes to stdout-like output, treating a closed pipe (`| head`) as success.
+fn write_output(out: &mut impl Write, bytes: &[u8]) -> Result<(), String> {
+    match out.write_all(bytes) {
+        Err(e) if e.kind() != io::ErrorKind::BrokenPipe => Err(e.to_string()),
+        _ => Ok(()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use id_generator::UNIX_EPOCH_OFFSET;
+
+    fn parse(args: &[&str]) -> Cli {
+        Cli::try_parse_from(std::iter::once("id-generator").chain(args.iter().copied())).unwrap()
+    }
+
+    #[test]
+    fn test_no_subcommand_serves() {
+        assert!(parse(&[]).command.is_none());
+    }
+
+    #[test]
+    fn test_serve_flags() {
+        let Some(Command::Serve(args)) = parse(&["serve", "-p", "9090", "--bind", "::1", "--worker", "7", "--workers", "4"]).command else {
+            panic!("Expected serve");
+        };
+        assert_eq!(args.port, Some(9090));
+        assert_eq!(args.bind, Some("::1".parse().unwrap()));
+        assert_eq!(args.worker, Some(7));
+        assert_eq!(args.workers, Some(4));
+        assert_eq!(args.grpc_port, None);
+
+        let Some(Command::Serve(args)) = parse(&["serve", "-l", "[::]:8080", "--listen", "unix:/run/id.sock", "--admin-listen", "0.0.0.0:9090"]).command else {
+            panic!("Expected serve");
+        };
+        assert_eq!(args.listen.len(), 2);
+        assert_eq!(args.admin_listen, ["0.0.0.0:9090".parse().unwrap()]);
+    }
+
+    #[test]
+    fn test_rejects_invalid_flags() {
+        let cli = |args: &[&str]| Cli::try_parse_from(std::iter::once("id-generator").chain(args.iter().copied()));
+        assert!(cli(&["serve", "--port", "70000"]).is_err());
+        assert!(cli(&["serve", "--listen", "8080"]).is_err());
+        assert!(cli(&["generate", "--format", "octal"]).is_err());
+        assert!(cli(&["decode"]).is_err(), "At least one ID is required");
+        assert!(cli(&["api-key"]).is_err(), "The key needs an ID");
+    }
+
+    #[test]
+    fn test_generate() {
+        let Some(Command::Generate(args)) = parse(&["generate", "-n", "5000", "--worker", "3", "--format", "hex"]).command else {
+            panic!("Expected generate");
+        };
+        let generator = SnowflakeGenerator::new(3).unwrap();
+        let mut out = Vec::new();
+        generate(&args, &generator, &mut out).unwrap();
+
+        let lines: Vec<&str> = std::str::from_utf8(&out).unwrap().lines().collect();
+        assert_eq!(lines.len(), 5000);
+        assert!(lines.windows(2).all(|pair| pair[0] < pair[1]));
+
+        let id = Encoding::Hex.decode(lines[0]).unwrap();
+        assert_eq!(generator.decode(id).unwrap().worker_id, 3);
+    }
+
+    #[test]
+    fn test_decode() {
+        let generator = SnowflakeGenerator::new(5).unwrap();
+        let id = generator.next_id().unwrap();
+        let encoded = Encoding::Base62.encode(id);
+
+        let Some(Command::Decode(args)) = parse(&["decode", &id.to_string(), &encoded]).command else {
+            panic!("Expected decode");
+        };
+        let mut out = Vec::new();
+        decode(&args, Layout::DEFAULT, UNIX_EPOCH_OFFSET, &mut out).unwrap();
+
+        let out = String::from_utf8(out).unwrap();
+        let lines: Vec<&str> = out.lines().collect();
+        assert_eq!(lines.len(), 2);
+        assert!(lines[0].starts_with(&format!("{}: time=", id)));
+        assert!(lines.iter().all(|line| line.contains("worker_id=5 ")));
+    }
+
+    #[test]
+    fn test_decode_json_and_failures() {
+        let id = SnowflakeGenerator::new(5).unwrap().next_id().unwrap();
+        let Some(Command::Decode(args)) = parse(&["decode", "--json", "nope", &id.to_string()]).command else {
+            panic!("Expected decode");
+        };
+        let mut out = Vec::new();
+        let err = decode(&args, Layout::DEFAULT, UNIX_EPOCH_OFFSET, &mut out).unwrap_err();
+        assert!(err.starts_with("nope: "));
+
+        // Valid IDs are still decoded
+        let response: DecodeResponse = serde_json::from_slice(&out).unwrap();
+        assert_eq!(response.id, id.to_string());
+        assert_eq!(response.worker_id, 5);
+    }
+
+    #[test]
+    fn test_api_key() {
+        let Some(Command::ApiKey(args)) = parse(&["api-key", "--id", "billing"]).command else {
+            panic!("Expected api-key");
+        };
+        let mut out = Vec::new();
+        api_key(&args, &mut out).unwrap();
+        let out = String::from_utf8(out).unwrap();
+
+        // The output is a valid key file whose key accepts the printed token
+        let token = out.lines().nth(1).unwrap().trim_start_matches("# ");
+        let path = std::env::temp_dir().join(format!("id-generator-cli-{}-keys.toml", std::process::id()));
+        std::fs::write(&path, &out).unwrap();
+        let keys = auth::ApiKeys::load(&path).unwrap();
+        std::fs::remove_file(path).unwrap();
+        assert_eq!(keys.authenticate(token).unwrap().id, "billing");
+
+        // IDs go into the file unescaped in the comment, so odd ones are refused
+        let args = ["id-generator", "api-key", "--id", "billing\n[[keys]]"];
+        assert!(Cli::try_parse_from(args).unwrap_err().to_string().contains("may only contain"));
+    }
+}
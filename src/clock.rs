@@ -0,0 +1,276 @@
+use std::sync::atomic::{AtomicI64, AtomicU64, Ordering};
+use std::sync::Arc;
+use std::time::Instant;
+
+use crate::metrics::{CLOCK_CORRECTION_MS, CLOCK_WALL_GAP_MS};
+use crate::time;
+
+/// Source of the current time for a generator.
+///
+/// The generator only ever asks for Unix milliseconds, so implementations are
+/// free to derive them however they like, e.g. from a fake clock in tests.
+pub trait Clock: Send + Sync {
+    /// Milliseconds since the Unix epoch.
+    fn unix_millis(&self) -> u64;
+}
+
+/// The operating system's wall clock.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct SystemClock;
+
+impl Clock for SystemClock {
+    fn unix_millis(&self) -> u64 {
+        time::unix_millis()
+    }
+}
+
+/// A clock that only moves when told to.
+///
+/// Share it through an `Arc` to keep a handle after passing it to a generator:
+///
+/// ```
+/// use std::sync::Arc;
+/// use id_generator::{GeneratorOptions, SnowflakeGenerator, UNIX_EPOCH_OFFSET};
+/// use id_generator::clock::ManualClock;
+///
+/// let clock = Arc::new(ManualClock::new(UNIX_EPOCH_OFFSET + 1000));
+/// let generator = SnowflakeGenerator::with_clock(1, GeneratorOptions::default(), clock.clone())?;
+///
+/// let id = generator.next_id()?;
+/// clock.advance(1);
+/// assert!(generator.next_id()? > id);
+/// # Ok::<(), id_generator::SnowflakeError>(())
+/// ```
+#[derive(Debug, Default)]
+pub struct ManualClock {
+    now: AtomicU64,
+}
+
+impl ManualClock {
+    pub fn new(unix_ms: u64) -> Self {
+        Self {
+            now: AtomicU64::new(unix_ms),
+        }
+    }
+
+    /// Sets the time, forwards or backwards.
+    pub fn set(&self, unix_ms: u64) {
+        self.now.store(unix_ms, Ordering::Release);
+    }
+
+    pub fn advance(&self, ms: u64) {
+        self.now.fetch_add(ms, Ordering::AcqRel);
+    }
+
+    /// Moves the clock backwards, as an NTP step or leap second would.
+    pub fn rewind(&self, ms: u64) {
+        // fetch_update only fails if the closure returns None
+        let _ = self
+            .now
+            .fetch_update(Ordering::AcqRel, Ordering::Acquire, |now| Some(now.saturating_sub(ms)));
+    }
+}
+
+impl Clock for ManualClock {
+    fn unix_millis(&self) -> u64 {
+        self.now.load(Ordering::Acquire)
+    }
+}
+
+/// Default rate at which [`MonotonicClock`] converges on the wall clock, in
+/// parts per million. The same as the maximum NTP slew rate.
+pub const DEFAULT_SLEW_PPM: u64 = 500;
+
+/// A clock anchored to the wall clock once and then advanced by monotonic
+/// elapsed time, so wall-clock steps in either direction don't move it.
+///
+/// Differences from the wall clock are corrected gradually: for every
+/// millisecond that passes, the clock runs at most `slew_ppm` parts per million
+/// faster or slower than real time until it agrees with the wall clock again.
+/// As long as `slew_ppm` is below 1,000,000 the clock never goes backwards.
+pub struct MonotonicClock {
+    wall: Arc<dyn Clock>,
+    anchor: Instant,
+    anchor_unix_us: u64,
+    slew_ppm: u64,
+    // Kept in atomics rather than behind a lock, since the generator reads the
+    // clock on every reservation attempt
+    /// Monotonic time up to which the correction has been applied, in
+    /// microseconds since `anchor`.
+    last_elapsed_us: AtomicU64,
+    /// Correction applied so far, in microseconds.
+    offset_us: AtomicI64,
+    last_unix_ms: AtomicU64,
+}
+
+impl MonotonicClock {
+    /// Anchors to the system clock.
+    pub fn new(slew_ppm: u64) -> Self {
+        Self::with_wall_clock(Arc::new(SystemClock), slew_ppm)
+    }
+
+    /// Anchors to `wall`, which is also what the clock slews towards.
+    pub fn with_wall_clock(wall: Arc<dyn Clock>, slew_ppm: u64) -> Self {
+        let anchor_unix_ms = wall.unix_millis();
+        Self {
+            wall,
+            anchor: Instant::now(),
+            anchor_unix_us: anchor_unix_ms * 1000,
+            slew_ppm: slew_ppm.min(999_999),
+            last_elapsed_us: AtomicU64::new(0),
+            offset_us: AtomicI64::new(0),
+            last_unix_ms: AtomicU64::new(anchor_unix_ms),
+        }
+    }
+
+    /// Advances the clock to `elapsed_us` after the anchor, slewing towards
+    /// `wall_ms`. Returns the anchored time in Unix milliseconds.
+    fn advance_to(&self, elapsed_us: u64, wall_ms: u64) -> u64 {
+        // Each stretch of elapsed time is claimed by exactly one reader, which
+        // applies the correction for it, so concurrent readers can't slew
+        // faster than `slew_ppm` between them. Stretches too short to allow a
+        // whole microsecond of correction are left for a later reading.
+        let previous_us = self.last_elapsed_us.load(Ordering::Acquire);
+        let max_step_us = (elapsed_us.saturating_sub(previous_us).saturating_mul(self.slew_ppm) / 1_000_000) as i64;
+        let mut offset_us = self.offset_us.load(Ordering::Acquire);
+        let claimed = max_step_us > 0
+            && self
+                .last_elapsed_us
+                .compare_exchange(previous_us, elapsed_us, Ordering::AcqRel, Ordering::Acquire)
+                .is_ok();
+        if claimed {
+            let anchored_us = (self.anchor_unix_us + elapsed_us) as i64 + offset_us;
+            let gap_us = (wall_ms * 1000) as i64 - anchored_us;
+            let step_us = gap_us.clamp(-max_step_us, max_step_us);
+            offset_us = self.offset_us.fetch_add(step_us, Ordering::AcqRel) + step_us;
+        }
+
+        // Readers can race on the elapsed time; never step backwards
+        let anchored_us = (self.anchor_unix_us + elapsed_us) as i64 + offset_us;
+        let unix_ms = anchored_us.max(0) as u64 / 1000;
+        let unix_ms = self.last_unix_ms.fetch_max(unix_ms, Ordering::AcqRel).max(unix_ms);
+
+        CLOCK_WALL_GAP_MS.set(unix_ms as f64 - wall_ms as f64);
+        CLOCK_CORRECTION_MS.set(offset_us as f64 / 1000.0);
+        unix_ms
+    }
+}
+
+impl Clock for MonotonicClock {
+    fn unix_millis(&self) -> u64 {
+        let elapsed_us = self.anchor.elapsed().as_micros() as u64;
+        self.advance_to(elapsed_us, self.wall.unix_millis())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_manual_clock() {
+        let clock = ManualClock::new(1000);
+        assert_eq!(clock.unix_millis(), 1000);
+
+        clock.advance(5);
+        assert_eq!(clock.unix_millis(), 1005);
+
+        clock.rewind(10);
+        assert_eq!(clock.unix_millis(), 995);
+
+        clock.rewind(5000);
+        assert_eq!(clock.unix_millis(), 0);
+
+        clock.set(42);
+        assert_eq!(clock.unix_millis(), 42);
+    }
+
+    #[test]
+    fn test_system_clock_is_close_to_now() {
+        let now = time::unix_millis();
+        let clock_now = SystemClock.unix_millis();
+        assert!(clock_now >= now && clock_now - now < 1000);
+    }
+
+    fn anchored_at(unix_ms: u64, slew_ppm: u64) -> (Arc<ManualClock>, MonotonicClock) {
+        let wall = Arc::new(ManualClock::new(unix_ms));
+        let clock = MonotonicClock::with_wall_clock(wall.clone(), slew_ppm);
+        (wall, clock)
+    }
+
+    #[test]
+    fn test_monotonic_clock_follows_elapsed_time() {
+        let (wall, clock) = anchored_at(1_000_000, DEFAULT_SLEW_PPM);
+
+        wall.advance(250);
+        assert_eq!(clock.advance_to(250_000, wall.unix_millis()), 1_000_250);
+    }
+
+    #[test]
+    fn test_monotonic_clock_ignores_backward_step() {
+        let (wall, clock) = anchored_at(1_000_000, DEFAULT_SLEW_PPM);
+
+        // The wall clock steps back a second 100 ms after startup
+        wall.set(1_000_100 - 1000);
+        let now = clock.advance_to(100_000, wall.unix_millis());
+        assert_eq!(now, 1_000_099, "Slewed by at most 500 ppm of 100 ms");
+
+        // Keeps moving forward, slightly slower than real time
+        let later = clock.advance_to(1_100_000, wall.unix_millis() + 1000);
+        assert!(later > now);
+        assert_eq!(later, 1_001_100 - 1);
+    }
+
+    #[test]
+    fn test_monotonic_clock_converges_on_wall_clock() {
+        let (_, clock) = anchored_at(1_000_000, 100_000);
+
+        // 10% slew closes a 100 ms gap within a second
+        let mut now = 0;
+        for step in 1..=10 {
+            let elapsed_ms = step * 100;
+            let previous = now;
+            now = clock.advance_to(elapsed_ms * 1000, 1_000_000 + elapsed_ms - 100);
+            assert!(now >= previous);
+        }
+        assert_eq!(now, 1_000_000 + 1000 - 100);
+    }
+
+    #[test]
+    fn test_monotonic_clock_never_goes_backwards() {
+        let (_, clock) = anchored_at(1_000_000, 999_999);
+
+        let first = clock.advance_to(1000, 0);
+        let second = clock.advance_to(500, 0);
+        assert!(second >= first);
+    }
+
+    #[test]
+    fn test_monotonic_clock_concurrent_readers() {
+        let (wall, clock) = anchored_at(1_000_000, DEFAULT_SLEW_PPM);
+        let clock = Arc::new(clock);
+        // The wall clock is a second behind, so every reading slews backwards
+        wall.set(999_000);
+
+        let readers: Vec<_> = (0..4)
+            .map(|reader| {
+                let clock = clock.clone();
+                std::thread::spawn(move || {
+                    let mut previous = 0;
+                    for step in 0..10_000u64 {
+                        let now = clock.advance_to(step * 100 + reader, 999_000);
+                        assert!(now >= previous);
+                        previous = now;
+                    }
+                })
+            })
+            .collect();
+        for reader in readers {
+            reader.join().unwrap();
+        }
+
+        // One second of elapsed time allows at most 500 us of correction
+        let correction_us = clock.offset_us.load(Ordering::Acquire);
+        assert!((-500..0).contains(&correction_us), "{}", correction_us);
+    }
+}
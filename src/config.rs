@@ -0,0 +1,615 @@
+use std::fmt;
+use std::net::SocketAddr;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+
+use id_generator::clock::DEFAULT_SLEW_PPM;
+use id_generator::high_water_mark::DEFAULT_WINDOW_MS;
+use id_generator::time::parse_rfc3339;
+use id_generator::{Layout, CLOCK_DRIFT_TIMEOUT_MS};
+use serde::de::{self, SeqAccess, Visitor};
+use serde::de::DeserializeOwned;
+use serde::{Deserialize, Deserializer};
+
+use crate::readiness::DEFAULT_MAX_EXHAUSTIONS_PER_SEC;
+use crate::{DEFAULT_GRPC_PORT, DEFAULT_HTTP_PORT, DEFAULT_MAX_BORROW_MS, MAX_IDS_PER_REQUEST};
+
+/// Settings read from a TOML or YAML file. Every field is optional; the
+/// environment variables documented in the README override the file.
+#[derive(Debug, Default, Deserialize, PartialEq)]
+#[serde(default, deny_unknown_fields)]
+pub struct Config {
+    pub server: ServerConfig,
+    pub worker: WorkerConfig,
+    pub generator: GeneratorConfig,
+    pub limits: LimitsConfig,
+    pub clock: ClockConfig,
+    pub high_water_mark: Option<HighWaterMarkConfig>,
+    pub tls: Option<TlsConfig>,
+    pub auth: AuthConfig,
+    pub rate_limit: RateLimitConfig,
+    pub readiness: ReadinessConfig,
+    pub metrics: MetricsConfig,
+}
+
+/// An address to serve HTTP on: `host:port`, `[ipv6]:port` or
+/// `unix:/path/to/socket`.
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize)]
+#[serde(try_from = "String")]
+pub enum Listener {
+    Tcp(SocketAddr),
+    Unix(PathBuf),
+}
+
+impl FromStr for Listener {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        if let Some(path) = s.strip_prefix("unix:") {
+            if path.is_empty() {
+                return Err("unix: must be followed by a socket path".to_string());
+            }
+            return Ok(Listener::Unix(PathBuf::from(path)));
+        }
+        s.parse()
+            .map(Listener::Tcp)
+            .map_err(|_| format!("expected host:port, [ipv6]:port or unix:/path, got: '{}'", s))
+    }
+}
+
+impl TryFrom<String> for Listener {
+    type Error = String;
+
+    fn try_from(s: String) -> Result<Self, Self::Error> {
+        s.parse()
+    }
+}
+
+impl fmt::Display for Listener {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Listener::Tcp(addr) => write!(f, "{}", addr),
+            Listener::Unix(path) => write!(f, "unix:{}", path.display()),
+        }
+    }
+}
+
+/// Accepts either a single listener or a list of them. Written out rather than
+/// as an untagged enum so a bad address keeps its error message.
+fn one_or_many<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Vec<Listener>, D::Error> {
+    struct OneOrMany;
+
+    impl<'de> Visitor<'de> for OneOrMany {
+        type Value = Vec<Listener>;
+
+        fn expecting(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+            f.write_str("an address or a list of addresses")
+        }
+
+        fn visit_str<E: de::Error>(self, s: &str) -> Result<Self::Value, E> {
+            s.parse().map(|listener| vec![listener]).map_err(E::custom)
+        }
+
+        fn visit_seq<A: SeqAccess<'de>>(self, mut seq: A) -> Result<Self::Value, A::Error> {
+            let mut listeners = Vec::new();
+            while let Some(listener) = seq.next_element()? {
+                listeners.push(listener);
+            }
+            Ok(listeners)
+        }
+    }
+
+    deserializer.deserialize_any(OneOrMany)
+}
+
+#[derive(Debug, Deserialize, PartialEq)]
+#[serde(default, deny_unknown_fields)]
+pub struct ServerConfig {
+    /// HTTP listeners for the ID endpoints.
+    #[serde(deserialize_with = "one_or_many")]
+    pub listen: Vec<Listener>,
+    /// Listeners for `/health`, `/ready` and `/metrics`. When empty they are
+    /// served on `listen` alongside the ID endpoints.
+    #[serde(deserialize_with = "one_or_many")]
+    pub admin_listen: Vec<Listener>,
+    /// gRPC listen address.
+    pub grpc_listen: SocketAddr,
+    /// HTTP worker threads.
+    pub workers: u32,
+}
+
+impl Default for ServerConfig {
+    fn default() -> Self {
+        Self {
+            listen: vec![Listener::Tcp(SocketAddr::from(([0, 0, 0, 0], DEFAULT_HTTP_PORT)))],
+            admin_listen: Vec::new(),
+            grpc_listen: SocketAddr::from(([0, 0, 0, 0], DEFAULT_GRPC_PORT)),
+            workers: 1,
+        }
+    }
+}
+
+#[derive(Debug, Deserialize, PartialEq)]
+#[serde(default, deny_unknown_fields)]
+pub struct WorkerConfig {
+    /// Fixed worker ID.
+    pub id: Option<u64>,
+    /// Derive the worker ID from a `POD_NAME` ending in `-<n>` when no ID is
+    /// set.
+    pub from_pod_name: bool,
+}
+
+impl Default for WorkerConfig {
+    fn default() -> Self {
+        Self {
+            id: None,
+            from_pod_name: true,
+        }
+    }
+}
+
+#[derive(Debug, Deserialize, PartialEq)]
+#[serde(default, deny_unknown_fields)]
+pub struct GeneratorConfig {
+    pub timestamp_bits: u8,
+    pub worker_id_bits: u8,
+    pub sequence_bits: u8,
+    /// Custom epoch in Unix milliseconds.
+    pub epoch_ms: Option<u64>,
+    /// Custom epoch as a UTC date or date-time.
+    pub epoch: Option<String>,
+}
+
+impl Default for GeneratorConfig {
+    fn default() -> Self {
+        Self {
+            timestamp_bits: Layout::DEFAULT.timestamp_bits(),
+            worker_id_bits: Layout::DEFAULT.worker_id_bits(),
+            sequence_bits: Layout::DEFAULT.sequence_bits(),
+            epoch_ms: None,
+            epoch: None,
+        }
+    }
+}
+
+impl GeneratorConfig {
+    pub fn layout(&self) -> Result<Layout, String> {
+        Layout::new(self.timestamp_bits, self.worker_id_bits, self.sequence_bits).map_err(|e| e.to_string())
+    }
+
+    /// The epoch in Unix milliseconds, `None` if neither field is set.
+    pub fn epoch_ms(&self) -> Result<Option<u64>, String> {
+        match (self.epoch_ms, &self.epoch) {
+            (Some(_), Some(_)) => Err("only one of epoch_ms and epoch may be set".to_string()),
+            (Some(epoch_ms), None) => Ok(Some(epoch_ms)),
+            (None, Some(epoch)) => parse_rfc3339(epoch).map(Some).ok_or_else(|| {
+                format!(
+                    "epoch must be a UTC date (YYYY-MM-DD) or date-time (YYYY-MM-DDTHH:MM:SS[.mmm]Z), got: '{}'",
+                    epoch
+                )
+            }),
+            (None, None) => Ok(None),
+        }
+    }
+}
+
+#[derive(Debug, Deserialize, PartialEq)]
+#[serde(default, deny_unknown_fields)]
+pub struct LimitsConfig {
+    /// Largest count accepted by `/ids/{count}` and `StreamIds`.
+    pub max_ids_per_request: u64,
+}
+
+impl Default for LimitsConfig {
+    fn default() -> Self {
+        Self {
+            max_ids_per_request: MAX_IDS_PER_REQUEST,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, Default, Deserialize, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum ClockMode {
+    #[default]
+    System,
+    Monotonic,
+}
+
+#[derive(Debug, Clone, Copy, Default, Deserialize, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum DriftPolicyName {
+    #[default]
+    Wait,
+    FailFast,
+    Borrow,
+}
+
+#[derive(Debug, Deserialize, PartialEq)]
+#[serde(default, deny_unknown_fields)]
+pub struct ClockConfig {
+    pub mode: ClockMode,
+    pub slew_ppm: u64,
+    pub drift_policy: DriftPolicyName,
+    pub drift_timeout_ms: u64,
+    pub max_borrow_ms: u64,
+}
+
+impl Default for ClockConfig {
+    fn default() -> Self {
+        Self {
+            mode: ClockMode::System,
+            slew_ppm: DEFAULT_SLEW_PPM,
+            drift_policy: DriftPolicyName::Wait,
+            drift_timeout_ms: CLOCK_DRIFT_TIMEOUT_MS,
+            max_borrow_ms: DEFAULT_MAX_BORROW_MS,
+        }
+    }
+}
+
+#[derive(Debug, Deserialize, PartialEq)]
+#[serde(deny_unknown_fields)]
+pub struct HighWaterMarkConfig {
+    pub file: PathBuf,
+    #[serde(default = "default_window_ms")]
+    pub window_ms: u64,
+}
+
+fn default_window_ms() -> u64 {
+    DEFAULT_WINDOW_MS
+}
+
+/// Whether clients must present a certificate when `tls.client_ca_file` is set.
+#[derive(Debug, Clone, Copy, Default, Deserialize, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum ClientAuth {
+    /// Clients without a certificate are accepted, but ones that present an
+    /// invalid certificate are not.
+    Optional,
+    #[default]
+    Required,
+}
+
+#[derive(Debug, Clone, Deserialize, PartialEq)]
+#[serde(deny_unknown_fields)]
+pub struct TlsConfig {
+    /// PEM certificate chain, leaf first.
+    pub cert_file: PathBuf,
+    /// PEM private key for the leaf certificate.
+    pub key_file: PathBuf,
+    /// PEM CA bundle to verify client certificates against. Enables mutual TLS.
+    #[serde(default)]
+    pub client_ca_file: Option<PathBuf>,
+    #[serde(default)]
+    pub client_auth: ClientAuth,
+    /// How often to check the files for changes; 0 disables reloading.
+    #[serde(default = "default_reload_interval_secs")]
+    pub reload_interval_secs: u64,
+}
+
+pub const DEFAULT_TLS_RELOAD_INTERVAL_SECS: u64 = 10;
+
+fn default_reload_interval_secs() -> u64 {
+    DEFAULT_TLS_RELOAD_INTERVAL_SECS
+}
+
+#[derive(Debug, Default, Deserialize, PartialEq)]
+#[serde(default, deny_unknown_fields)]
+pub struct AuthConfig {
+    /// TOML or YAML file of API keys. Without one, the API is open.
+    pub key_file: Option<PathBuf>,
+}
+
+#[derive(Debug, Default, Deserialize, PartialEq)]
+#[serde(default, deny_unknown_fields)]
+pub struct RateLimitConfig {
+    /// IDs per second for each client without a limit on its API key. Unset
+    /// leaves them unlimited.
+    pub ids_per_sec: Option<f64>,
+    /// Most IDs a client can take at once; one second's worth by default.
+    pub burst: Option<u64>,
+    /// Key clients by the first `X-Forwarded-For` address rather than the
+    /// connection's peer address.
+    pub trust_forwarded_for: bool,
+}
+
+#[derive(Debug, Deserialize, PartialEq)]
+#[serde(default, deny_unknown_fields)]
+pub struct ReadinessConfig {
+    pub max_exhaustions_per_sec: f64,
+}
+
+impl Default for ReadinessConfig {
+    fn default() -> Self {
+        Self {
+            max_exhaustions_per_sec: DEFAULT_MAX_EXHAUSTIONS_PER_SEC,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Deserialize, PartialEq)]
+#[serde(default, deny_unknown_fields)]
+pub struct MetricsConfig {
+    /// Serve Prometheus metrics over HTTP.
+    pub enabled: bool,
+    pub path: String,
+}
+
+impl Default for MetricsConfig {
+    fn default() -> Self {
+        Self {
+            enabled: true,
+            path: "/metrics".to_string(),
+        }
+    }
+}
+
+/// Parses TOML or YAML, chosen by file extension.
+pub fn parse_document<T: DeserializeOwned + Default>(contents: &str, extension: &str) -> Result<T, String> {
+    match extension {
+        "toml" => toml::from_str(contents).map_err(|e| e.to_string().trim_end().to_string()),
+        "yaml" | "yml" => {
+            // An empty YAML document is null rather than an empty mapping
+            if contents.trim().is_empty() {
+                return Ok(T::default());
+            }
+            serde_yaml::from_str(contents).map_err(|e| e.to_string())
+        }
+        _ => Err(format!("expected a .toml, .yaml or .yml extension, got: '{}'", extension)),
+    }
+}
+
+/// Paths already taken by the API, which the metrics endpoint can't use.
+const RESERVED_PATHS: [&str; 5] = ["/id", "/ids", "/decode", "/health", "/ready"];
+
+impl Config {
+    /// Reads and validates a config file. `.toml` files are read as TOML and
+    /// `.yaml` or `.yml` files as YAML.
+    pub fn load(path: &Path) -> Result<Self, String> {
+        let error = |message: String| format!("Config file {}: {}", path.display(), message);
+
+        let contents = std::fs::read_to_string(path).map_err(|e| error(e.to_string()))?;
+        let extension = path.extension().and_then(|extension| extension.to_str()).unwrap_or("");
+        let config = Self::parse(&contents, extension).map_err(error)?;
+        config.validate().map_err(error)?;
+        Ok(config)
+    }
+
+    fn parse(contents: &str, extension: &str) -> Result<Self, String> {
+        parse_document(contents, extension)
+    }
+
+    /// Checks values that parse but can't work, naming the offending key.
+    pub fn validate(&self) -> Result<(), String> {
+        if self.server.workers == 0 {
+            return Err("server.workers must be at least 1".to_string());
+        }
+        if self.server.listen.is_empty() {
+            return Err("server.listen must have at least one address".to_string());
+        }
+        if let Some(shared) = self.server.admin_listen.iter().find(|admin| self.server.listen.contains(admin)) {
+            return Err(format!("server.admin_listen {} is also in server.listen", shared));
+        }
+
+        let layout = self.generator.layout().map_err(|e| format!("generator: {}", e))?;
+        self.generator.epoch_ms().map_err(|e| format!("generator: {}", e))?;
+
+        if let Some(id) = self.worker.id {
+            if id > layout.max_worker_id() {
+                return Err(format!(
+                    "worker.id must be between 0 and {} for {} worker ID bits, got: {}",
+                    layout.max_worker_id(),
+                    layout.worker_id_bits(),
+                    id
+                ));
+            }
+        }
+
+        // Counts are sent as MessagePack array32 lengths
+        let max_ids = self.limits.max_ids_per_request;
+        if max_ids == 0 || max_ids > u64::from(u32::MAX) {
+            return Err(format!(
+                "limits.max_ids_per_request must be between 1 and {}, got: {}",
+                u32::MAX,
+                max_ids
+            ));
+        }
+
+        if self.clock.slew_ppm >= 1_000_000 {
+            return Err(format!("clock.slew_ppm must be below 1000000, got: {}", self.clock.slew_ppm));
+        }
+
+        if let Some(high_water_mark) = &self.high_water_mark {
+            if high_water_mark.file.as_os_str().is_empty() {
+                return Err("high_water_mark.file must not be empty".to_string());
+            }
+        }
+
+        if let Some(tls) = &self.tls {
+            if tls.cert_file.as_os_str().is_empty() || tls.key_file.as_os_str().is_empty() {
+                return Err("tls.cert_file and tls.key_file must not be empty".to_string());
+            }
+            if tls.client_ca_file.as_ref().is_some_and(|file| file.as_os_str().is_empty()) {
+                return Err("tls.client_ca_file must not be empty".to_string());
+            }
+        }
+
+        if self.auth.key_file.as_ref().is_some_and(|file| file.as_os_str().is_empty()) {
+            return Err("auth.key_file must not be empty".to_string());
+        }
+
+        if let Some(rate) = self.rate_limit.ids_per_sec {
+            if !rate.is_finite() || rate <= 0.0 {
+                return Err(format!("rate_limit.ids_per_sec must be a positive number, got: {}", rate));
+            }
+        }
+        match self.rate_limit.burst {
+            Some(0) => return Err("rate_limit.burst must be at least 1".to_string()),
+            Some(_) if self.rate_limit.ids_per_sec.is_none() => {
+                return Err("rate_limit.burst needs rate_limit.ids_per_sec".to_string())
+            }
+            _ => {}
+        }
+
+        let rate = self.readiness.max_exhaustions_per_sec;
+        if !rate.is_finite() || rate < 0.0 {
+            return Err(format!(
+                "readiness.max_exhaustions_per_sec must be a non-negative number, got: {}",
+                rate
+            ));
+        }
+
+        let path = &self.metrics.path;
+        if !path.starts_with('/') {
+            return Err(format!("metrics.path must start with '/', got: '{}'", path));
+        }
+        if RESERVED_PATHS.iter().any(|reserved| path == reserved || path.starts_with(&format!("{}/", reserved))) {
+            return Err(format!("metrics.path '{}' is already used by the API", path));
+        }
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn temp_file(name: &str, contents: &str) -> PathBuf {
+        let path = std::env::temp_dir().join(format!("id-generator-config-{}-{}", std::process::id(), name));
+        std::fs::write(&path, contents).unwrap();
+        path
+    }
+
+    #[test]
+    fn test_load_toml() {
+        let path = temp_file(
+            "full.toml",
+            r#"
+[server]
+listen = ["[::]:9090", "unix:/run/id-generator.sock"]
+admin_listen = "127.0.0.1:9091"
+workers = 4
+
+[worker]
+id = 7
+
+[generator]
+worker_id_bits = 8
+sequence_bits = 14
+epoch = "2024-01-01"
+
+[limits]
+max_ids_per_request = 1000
+
+[clock]
+mode = "monotonic"
+drift_policy = "borrow"
+max_borrow_ms = 50
+
+[high_water_mark]
+file = "/var/lib/id-generator/high-water-mark"
+
+[metrics]
+path = "/internal/metrics"
+"#,
+        );
+        let config = Config::load(&path).unwrap();
+        std::fs::remove_file(path).unwrap();
+
+        assert_eq!(
+            config.server.listen,
+            [
+                Listener::Tcp("[::]:9090".parse().unwrap()),
+                Listener::Unix("/run/id-generator.sock".into()),
+            ]
+        );
+        assert_eq!(config.server.admin_listen, [Listener::Tcp("127.0.0.1:9091".parse().unwrap())]);
+        assert_eq!(config.server.grpc_listen, ServerConfig::default().grpc_listen);
+        assert_eq!(config.server.workers, 4);
+        assert_eq!(config.worker.id, Some(7));
+        assert_eq!(config.generator.layout().unwrap(), Layout::new(41, 8, 14).unwrap());
+        assert_eq!(config.generator.epoch_ms().unwrap(), Some(1_704_067_200_000));
+        assert_eq!(config.limits.max_ids_per_request, 1000);
+        assert_eq!(config.clock.mode, ClockMode::Monotonic);
+        assert_eq!(config.clock.drift_policy, DriftPolicyName::Borrow);
+        assert_eq!(config.clock.max_borrow_ms, 50);
+        assert_eq!(config.high_water_mark.unwrap().window_ms, DEFAULT_WINDOW_MS);
+        assert_eq!(config.metrics.path, "/internal/metrics");
+    }
+
+    #[test]
+    fn test_load_yaml() {
+        let path = temp_file(
+            "full.yaml",
+            "worker:\n  from_pod_name: false\nclock:\n  drift_policy: fail_fast\nmetrics:\n  enabled: false\n",
+        );
+        let config = Config::load(&path).unwrap();
+        std::fs::remove_file(path).unwrap();
+
+        assert!(!config.worker.from_pod_name);
+        assert_eq!(config.clock.drift_policy, DriftPolicyName::FailFast);
+        assert!(!config.metrics.enabled);
+
+        let config = Config::parse("tls:\n  cert_file: /tls/tls.crt\n  key_file: /tls/tls.key\n  client_ca_file: /tls/ca.crt\n", "yaml").unwrap();
+        let tls = config.tls.unwrap();
+        assert_eq!(tls.client_ca_file, Some(PathBuf::from("/tls/ca.crt")));
+        assert_eq!(tls.client_auth, ClientAuth::Required);
+        assert_eq!(tls.reload_interval_secs, DEFAULT_TLS_RELOAD_INTERVAL_SECS);
+
+        assert_eq!(Config::parse("", "yml").unwrap(), Config::default());
+    }
+
+    #[test]
+    fn test_parse_errors_name_the_problem() {
+        let err = Config::parse("[server]\nworkerz = 2\n", "toml").unwrap_err();
+        assert!(err.contains("unknown field `workerz`"), "{}", err);
+        assert!(err.contains("line 2"), "{}", err);
+
+        let err = Config::parse("clock:\n  mode: atomic\n", "yaml").unwrap_err();
+        assert!(err.contains("clock.mode"), "{}", err);
+
+        let err = Config::parse("", "json").unwrap_err();
+        assert!(err.contains(".toml, .yaml or .yml"));
+    }
+
+    #[test]
+    fn test_parse_listener() {
+        assert_eq!("0.0.0.0:8080".parse(), Ok(Listener::Tcp(SocketAddr::from(([0, 0, 0, 0], 8080)))));
+        assert_eq!("[::1]:8080".parse::<Listener>().unwrap().to_string(), "[::1]:8080");
+        assert_eq!("unix:/tmp/id.sock".parse(), Ok(Listener::Unix("/tmp/id.sock".into())));
+        assert!("unix:".parse::<Listener>().is_err());
+        assert!("localhost".parse::<Listener>().unwrap_err().contains("host:port"));
+
+        let err = Config::parse("[server]\nlisten = \"8080\"", "toml").unwrap_err();
+        assert!(err.contains("expected host:port"), "{}", err);
+    }
+
+    #[test]
+    fn test_validate() {
+        let invalid = |toml: &str| Config::parse(toml, "toml").unwrap().validate().unwrap_err();
+
+        assert!(Config::default().validate().is_ok());
+        assert!(invalid("[server]\nworkers = 0").contains("server.workers"));
+        assert!(invalid("[server]\nlisten = []").contains("at least one address"));
+        assert!(invalid("[server]\nlisten = \"unix:/a\"\nadmin_listen = [\"unix:/a\"]").contains("also in server.listen"));
+        assert!(invalid("[generator]\nsequence_bits = 20").contains("sum to 63 or 64"));
+        assert!(invalid("[generator]\nepoch = \"yesterday\"").contains("generator: epoch must be"));
+        assert!(invalid("[generator]\nepoch = \"2024-01-01\"\nepoch_ms = 1").contains("only one of"));
+        assert!(invalid("[worker]\nid = 1024").contains("worker.id must be between 0 and 1023"));
+        assert!(invalid("[limits]\nmax_ids_per_request = 0").contains("limits.max_ids_per_request"));
+        assert!(invalid("[clock]\nslew_ppm = 1000000").contains("clock.slew_ppm"));
+        assert!(invalid("[readiness]\nmax_exhaustions_per_sec = -1.0").contains("readiness.max_exhaustions_per_sec"));
+        assert!(invalid("[tls]\ncert_file = \"\"\nkey_file = \"/tls.key\"").contains("tls.cert_file"));
+        assert!(invalid("[rate_limit]\nids_per_sec = -5.0").contains("rate_limit.ids_per_sec"));
+        assert!(invalid("[rate_limit]\nburst = 100").contains("needs rate_limit.ids_per_sec"));
+        assert!(invalid("[metrics]\npath = \"metrics\"").contains("must start with '/'"));
+        assert!(invalid("[metrics]\npath = \"/ids/metrics\"").contains("already used"));
+    }
+
+    #[test]
+    fn test_load_reports_path() {
+        let err = Config::load(Path::new("/nonexistent/id-generator.toml")).unwrap_err();
+        assert!(err.starts_with("Config file /nonexistent/id-generator.toml: "), "{}", err);
+    }
+}
@@ -0,0 +1,270 @@
+use std::fmt::Write;
+
+const CROCKFORD_ALPHABET: &[u8; 32] = b"0123456789ABCDEFGHJKMNPQRSTVWXYZ";
+// Digits, then upper case, then lower case, so the alphabet is in ASCII order
+const BASE62_ALPHABET: &[u8; 62] = b"0123456789ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz";
+
+/// String representations for IDs.
+///
+/// Every encoding except `Decimal` is fixed-width and uses an alphabet in
+/// ASCII order, so encoded IDs sort lexicographically in the same order as the
+/// numbers themselves.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Encoding {
+    /// Plain decimal, e.g. `123456789012345678`. Not fixed-width.
+    Decimal,
+    /// Decimal zero-padded to 20 digits.
+    PaddedDecimal,
+    /// Lower case hexadecimal, 16 digits.
+    Hex,
+    /// Crockford base32, 13 characters.
+    Base32,
+    /// Base62 (`0-9A-Za-z`), 11 characters.
+    Base62,
+}
+
+impl Encoding {
+    pub const ALL: [Encoding; 5] = [
+        Encoding::Decimal,
+        Encoding::PaddedDecimal,
+        Encoding::Hex,
+        Encoding::Base32,
+        Encoding::Base62,
+    ];
+
+    /// Name used to select the encoding, e.g. in a `format` query parameter.
+    pub fn name(&self) -> &'static str {
+        match self {
+            Encoding::Decimal => "decimal",
+            Encoding::PaddedDecimal => "padded_decimal",
+            Encoding::Hex => "hex",
+            Encoding::Base32 => "base32",
+            Encoding::Base62 => "base62",
+        }
+    }
+
+    /// Width of every encoded ID, or `None` if the width varies.
+    pub fn width(&self) -> Option<usize> {
+        match self {
+            Encoding::Decimal => None,
+            Encoding::PaddedDecimal => Some(20),
+            Encoding::Hex => Some(16),
+            Encoding::Base32 => Some(13),
+            Encoding::Base62 => Some(11),
+        }
+    }
+
+    pub fn encode(&self, id: u64) -> String {
+        let mut out = String::with_capacity(20);
+        self.write(id, &mut out);
+        out
+    }
+
+    /// Appends the encoded ID to `out`.
+    pub fn write(&self, id: u64, out: &mut String) {
+        // Writing to a String cannot fail
+        match self {
+            Encoding::Decimal => {
+                let _ = write!(out, "{}", id);
+            }
+            Encoding::PaddedDecimal => {
+                let _ = write!(out, "{:020}", id);
+            }
+            Encoding::Hex => {
+                let _ = write!(out, "{:016x}", id);
+            }
+            Encoding::Base32 => write_fixed(id, CROCKFORD_ALPHABET, 13, out),
+            Encoding::Base62 => write_fixed(id, BASE62_ALPHABET, 11, out),
+        }
+    }
+
+    /// Parses an ID in this encoding. Shorter, unpadded input is accepted; values
+    /// that don't fit in a `u64` are rejected.
+    pub fn decode(&self, s: &str) -> Option<u64> {
+        match self {
+            Encoding::Decimal | Encoding::PaddedDecimal => {
+                if s.is_empty() || s.len() > 20 || !s.bytes().all(|b| b.is_ascii_digit()) {
+                    return None;
+                }
+                s.parse().ok()
+            }
+            Encoding::Hex => {
+                if s.is_empty() || s.len() > 16 || !s.bytes().all(|b| b.is_ascii_hexdigit()) {
+                    return None;
+                }
+                u64::from_str_radix(s, 16).ok()
+            }
+            Encoding::Base32 => decode_digits(s, 32, crockford_value),
+            Encoding::Base62 => decode_digits(s, 62, base62_value),
+        }
+    }
+
+    /// Parses an ID in any supported encoding.
+    ///
+    /// All-digit input is always read as decimal. Anything else is matched by
+    /// its width: 16 characters as hex, 13 as base32 and 11 as base62. Use
+    /// [`decode`](Self::decode) when the encoding is known, since for example a
+    /// hex ID made up only of digits is otherwise read as decimal.
+    pub fn decode_any(s: &str) -> Option<u64> {
+        if !s.is_empty() && s.bytes().all(|b| b.is_ascii_digit()) {
+            return Encoding::Decimal.decode(s);
+        }
+
+        Encoding::ALL
+            .iter()
+            .find(|encoding| encoding.width() == Some(s.len()))
+            .and_then(|encoding| encoding.decode(s))
+    }
+}
+
+impl std::str::FromStr for Encoding {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Encoding::ALL
+            .iter()
+            .copied()
+            .find(|encoding| encoding.name() == s)
+            .ok_or_else(|| {
+                let names: Vec<&str> = Encoding::ALL.iter().map(Encoding::name).collect();
+                format!("Unknown format '{}', expected one of: {}", s, names.join(", "))
+            })
+    }
+}
+
+fn write_fixed(mut id: u64, alphabet: &[u8], width: usize, out: &mut String) {
+    let base = alphabet.len() as u64;
+    let mut buf = vec![alphabet[0]; width];
+    for slot in buf.iter_mut().rev() {
+        *slot = alphabet[(id % base) as usize];
+        id /= base;
+    }
+    // Safe: both alphabets are ASCII
+    out.push_str(std::str::from_utf8(&buf).expect("ASCII alphabet"));
+}
+
+fn decode_digits(s: &str, base: u64, value: fn(u8) -> Option<u64>) -> Option<u64> {
+    if s.is_empty() {
+        return None;
+    }
+    s.bytes().try_fold(0u64, |acc, b| {
+        acc.checked_mul(base)?.checked_add(value(b)?)
+    })
+}
+
+/// Decodes a Crockford base32 symbol, case-insensitively and treating the
+/// commonly confused `I`/`L` as `1` and `O` as `0`.
+fn crockford_value(b: u8) -> Option<u64> {
+    let b = match b.to_ascii_uppercase() {
+        b'I' | b'L' => b'1',
+        b'O' => b'0',
+        b => b,
+    };
+    CROCKFORD_ALPHABET.iter().position(|&c| c == b).map(|v| v as u64)
+}
+
+fn base62_value(b: u8) -> Option<u64> {
+    BASE62_ALPHABET.iter().position(|&c| c == b).map(|v| v as u64)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SAMPLES: [u64; 7] = [0, 1, 61, 62, 123456789012345678, i64::MAX as u64, u64::MAX];
+
+    #[test]
+    fn test_encode_known_values() {
+        assert_eq!(Encoding::Decimal.encode(255), "255");
+        assert_eq!(Encoding::PaddedDecimal.encode(255), "00000000000000000255");
+        assert_eq!(Encoding::Hex.encode(255), "00000000000000ff");
+        assert_eq!(Encoding::Base32.encode(255), "000000000007Z");
+        assert_eq!(Encoding::Base62.encode(255), "00000000047");
+        assert_eq!(Encoding::Base62.encode(u64::MAX), "LygHa16AHYF");
+    }
+
+    #[test]
+    fn test_fixed_width() {
+        for encoding in Encoding::ALL {
+            if let Some(width) = encoding.width() {
+                for id in SAMPLES {
+                    assert_eq!(encoding.encode(id).len(), width, "{:?} {}", encoding, id);
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn test_roundtrip() {
+        for encoding in Encoding::ALL {
+            for id in SAMPLES {
+                assert_eq!(encoding.decode(&encoding.encode(id)), Some(id), "{:?} {}", encoding, id);
+            }
+        }
+    }
+
+    #[test]
+    fn test_fixed_width_preserves_sort_order() {
+        for encoding in Encoding::ALL.iter().filter(|e| e.width().is_some()) {
+            for pair in SAMPLES.windows(2) {
+                assert!(encoding.encode(pair[0]) < encoding.encode(pair[1]), "{:?} {:?}", encoding, pair);
+            }
+        }
+    }
+
+    #[test]
+    fn test_decode_rejects_overflow_and_garbage() {
+        assert_eq!(Encoding::Decimal.decode("18446744073709551616"), None);
+        assert_eq!(Encoding::Hex.decode("10000000000000000"), None);
+        assert_eq!(Encoding::Base32.decode("G000000000000"), None);
+        assert_eq!(Encoding::Base62.decode("LygHa16AHYG"), None);
+        assert_eq!(Encoding::Base62.decode("abc-"), None);
+        assert_eq!(Encoding::Base32.decode("U"), None);
+        assert_eq!(Encoding::Decimal.decode(""), None);
+        assert_eq!(Encoding::Decimal.decode("+1"), None);
+    }
+
+    #[test]
+    fn test_hex_rejects_sign() {
+        assert_eq!(Encoding::Hex.decode("0123456789abcdeF"), Some(0x0123456789abcdef));
+        assert_eq!(Encoding::Hex.decode("+0123456789abcde"), None);
+        assert_eq!(Encoding::decode_any("+0123456789abcde"), None);
+    }
+
+    #[test]
+    fn test_base32_is_lenient() {
+        assert_eq!(Encoding::Base32.decode("000000000007z"), Some(255));
+        assert_eq!(Encoding::Base32.decode("OOOOOOOOOOOOl"), Some(1));
+    }
+
+    #[test]
+    fn test_decode_any() {
+        let id = 123456789012345678;
+        for encoding in Encoding::ALL {
+            let encoded = encoding.encode(id);
+            assert_eq!(Encoding::decode_any(&encoded), Some(id), "{:?} {}", encoding, encoded);
+        }
+        assert_eq!(Encoding::decode_any("not-an-id"), None);
+        assert_eq!(Encoding::decode_any("00000000000000000255"), Some(255));
+    }
+
+    #[test]
+    fn test_decode_any_reads_digits_as_decimal() {
+        // A hex ID that happens to contain no letters needs its encoding named
+        let id = 0x0123456789012345;
+        assert_eq!(Encoding::Hex.encode(id), "0123456789012345");
+        assert_eq!(Encoding::decode_any("0123456789012345"), Some(123456789012345));
+        assert_eq!(Encoding::Hex.decode("0123456789012345"), Some(id));
+
+        assert_eq!(Encoding::decode_any("0000000000255"), Some(255));
+        assert_eq!(Encoding::decode_any("00000000255"), Some(255));
+    }
+
+    #[test]
+    fn test_from_str() {
+        for encoding in Encoding::ALL {
+            assert_eq!(encoding.name().parse::<Encoding>(), Ok(encoding));
+        }
+        assert!("base64".parse::<Encoding>().unwrap_err().contains("expected one of"));
+    }
+}
@@ -0,0 +1,587 @@
+use std::net::SocketAddr;
+use std::pin::Pin;
+use std::sync::Arc;
+use std::time::Duration;
+
+use actix_web::web;
+use futures_util::{future, stream, Stream, StreamExt};
+use id_generator::metrics::{BATCH_SIZE, GRPC_REQUESTS};
+use id_generator::SnowflakeError;
+use tokio::net::{TcpListener, TcpStream};
+use tokio_rustls::server::TlsStream;
+use tokio_rustls::TlsAcceptor;
+use tonic::service::Interceptor;
+use tonic::{Request, Response, Status};
+
+use crate::auth::{self, ApiKey, ApiKeys};
+use crate::rate_limit::{self, Throttle};
+use crate::tls::ClientIdentity;
+use crate::AppState;
+
+pub mod proto {
+    tonic::include_proto!("id_generator.v1");
+}
+
+use proto::id_generator_server::{IdGenerator, IdGeneratorServer};
+use proto::{
+    DecodeRequest, DecodeResponse, HealthRequest, HealthResponse, NextIdRequest, NextIdResponse,
+    NextIdsRequest, NextIdsResponse, StreamIdsRequest, StreamIdsResponse,
+};
+
+/// Largest count for the unary `NextIds`, keeping responses around 1 MB and
+/// well below the 4 MB message limit most clients default to.
+const MAX_IDS_PER_UNARY_REQUEST: u64 = 100_000;
+
+/// How long a client has to complete the TLS handshake.
+const TLS_HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// TLS handshakes in progress at once before new connections wait.
+const MAX_PENDING_TLS_HANDSHAKES: usize = 64;
+
+/// gRPC front end sharing the HTTP server's `AppState`.
+pub struct GrpcService {
+    data: web::Data<AppState>,
+}
+
+impl GrpcService {
+    pub fn new(data: web::Data<AppState>) -> Self {
+        Self { data }
+    }
+
+    /// Checks that the caller may use `endpoint`, the HTTP endpoint the method
+    /// mirrors, when API keys are required. Returns the caller's key.
+    fn authorize<T>(&self, request: &Request<T>, endpoint: &str) -> Result<Option<Arc<ApiKey>>, Denied> {
+        if self.data.api_keys.is_none() {
+            return Ok(None);
+        }
+        let key = request
+            .extensions()
+            .get::<Arc<ApiKey>>()
+            .cloned()
+            .ok_or(Denied::Unauthenticated("An API key is required as 'authorization: Bearer <key>' metadata"))?;
+
+        let namespace = request.metadata().get("namespace").and_then(|value| value.to_str().ok());
+        let client = request
+            .peer_certs()
+            .and_then(|certs| certs.first().and_then(ClientIdentity::from_certificate));
+        key.authorize(endpoint, namespace, client.as_ref())
+            .map_err(Denied::PermissionDenied)?;
+        Ok(Some(key))
+    }
+}
+
+/// Why API key checks refused a call.
+#[derive(Debug)]
+enum Denied {
+    /// No key, or an unknown one.
+    Unauthenticated(&'static str),
+    /// A valid key that may not make the call.
+    PermissionDenied(String),
+}
+
+fn status_from_denied(denied: Denied) -> Status {
+    match denied {
+        Denied::Unauthenticated(message) => Status::unauthenticated(message),
+        Denied::PermissionDenied(message) => Status::permission_denied(message),
+    }
+}
+
+/// Looks up the API key named by `authorization: Bearer <token>` metadata and
+/// stores it in the request extensions. Calls without the metadata are let
+/// through so `Health` stays open; the other methods refuse them.
+#[derive(Clone)]
+pub struct Authenticator {
+    keys: Option<Arc<ApiKeys>>,
+}
+
+impl Interceptor for Authenticator {
+    fn call(&mut self, mut request: Request<()>) -> Result<Request<()>, Status> {
+        authenticate(self.keys.as_deref(), &mut request).map_err(status_from_denied)?;
+        Ok(request)
+    }
+}
+
+fn authenticate<T>(keys: Option<&ApiKeys>, request: &mut Request<T>) -> Result<(), Denied> {
+    let (Some(keys), Some(value)) = (keys, request.metadata().get("authorization")) else {
+        return Ok(());
+    };
+    let key = value
+        .to_str()
+        .ok()
+        .and_then(auth::parse_bearer)
+        .and_then(|token| keys.authenticate(token))
+        .cloned()
+        .ok_or(Denied::Unauthenticated("Invalid API key"))?;
+    request.extensions_mut().insert(key);
+    Ok(())
+}
+
+/// Maps generator errors onto gRPC status codes.
+fn status_from_error(e: SnowflakeError) -> Status {
+    match e {
+        SnowflakeError::ClockDriftTimeout | SnowflakeError::ClockBehind { .. } => Status::unavailable(e.to_string()),
+        SnowflakeError::InvalidId(_) | SnowflakeError::FutureTimestamp(_) => Status::invalid_argument(e.to_string()),
+        _ => Status::internal(e.to_string()),
+    }
+}
+
+/// Maps rate limit rejections onto gRPC status codes, passing the wait on in
+/// `retry-after` metadata as HTTP does in its header.
+fn status_from_throttle(throttle: Throttle) -> Status {
+    match throttle {
+        Throttle::ExceedsBurst(burst) => Status::invalid_argument(format!(
+            "Count must be less than or equal to {}, the rate limit burst",
+            burst
+        )),
+        Throttle::RetryAfter(wait) => {
+            let secs = rate_limit::retry_after_secs(wait);
+            let mut status = Status::resource_exhausted(format!("Rate limit exceeded, retry in {} s", secs));
+            status.metadata_mut().insert("retry-after", secs.into());
+            status
+        }
+    }
+}
+
+fn check_count(count: u64, max: u64) -> Result<(), String> {
+    if count == 0 {
+        return Err("Count must be at least 1".to_string());
+    }
+    if count > max {
+        return Err(format!("Count must be less than or equal to {}", max));
+    }
+    Ok(())
+}
+
+/// Counts a finished call by method and status code.
+fn count_request<T>(method: &str, result: &Result<T, Status>) {
+    let code = match result {
+        Ok(_) => tonic::Code::Ok,
+        Err(status) => status.code(),
+    };
+    GRPC_REQUESTS.with_label_values(&[method, &format!("{:?}", code)]).inc();
+}
+
+type IdStream = Pin<Box<dyn Stream<Item = Result<StreamIdsResponse, Status>> + Send>>;
+
+#[tonic::async_trait]
+impl IdGenerator for GrpcService {
+    async fn next_id(&self, request: Request<NextIdRequest>) -> Result<Response<NextIdResponse>, Status> {
+        let result = async {
+            let key = self.authorize(&request, "/id").map_err(status_from_denied)?;
+            rate_limit::check_grpc(&self.data, key.as_deref(), request.remote_addr(), 1).map_err(status_from_throttle)?;
+            self.data.generator.next_id_async().await.map_err(|e| {
+                rate_limit::refund_grpc(&self.data, key.as_deref(), request.remote_addr(), 1);
+                status_from_error(e)
+            })
+        }
+        .await;
+        count_request("NextId", &result);
+        Ok(Response::new(NextIdResponse { id: result? }))
+    }
+
+    async fn next_ids(&self, request: Request<NextIdsRequest>) -> Result<Response<NextIdsResponse>, Status> {
+        let result = async {
+            let key = self.authorize(&request, "/ids").map_err(status_from_denied)?;
+            let max = ApiKey::max_ids_per_request(key.as_deref(), self.data.max_ids_per_request);
+            let count = request.get_ref().count;
+            check_count(count, MAX_IDS_PER_UNARY_REQUEST.min(max)).map_err(Status::invalid_argument)?;
+            rate_limit::check_grpc(&self.data, key.as_deref(), request.remote_addr(), count)
+                .map_err(status_from_throttle)?;
+            BATCH_SIZE.observe(count as f64);
+
+            let ids = self.data.generator.next_ids_async(count).await.map_err(|e| {
+                rate_limit::refund_grpc(&self.data, key.as_deref(), request.remote_addr(), count);
+                status_from_error(e)
+            })?;
+            Ok(Response::new(NextIdsResponse { ids }))
+        }
+        .await;
+        count_request("NextIds", &result);
+        result
+    }
+
+    type StreamIdsStream = IdStream;
+
+    async fn stream_ids(&self, request: Request<StreamIdsRequest>) -> Result<Response<IdStream>, Status> {
+        let count = request.get_ref().count;
+        // Counted when the stream starts; errors part way through only reach
+        // the client
+        let checked = match self.authorize(&request, "/ids") {
+            Ok(key) => {
+                let max = ApiKey::max_ids_per_request(key.as_deref(), self.data.max_ids_per_request);
+                match check_count(count, max) {
+                    Ok(()) => rate_limit::check_grpc(&self.data, key.as_deref(), request.remote_addr(), count)
+                        .map_err(status_from_throttle),
+                    Err(e) => Err(Status::invalid_argument(e)),
+                }
+            }
+            Err(denied) => Err(status_from_denied(denied)),
+        };
+        count_request("StreamIds", &checked);
+        checked?;
+        BATCH_SIZE.observe(count as f64);
+
+        // As with the HTTP endpoint, ranges are reserved one at a time as the
+        // client consumes them
+        let ids = stream::unfold((self.data.clone(), count), |(data, remaining)| async move {
+            if remaining == 0 {
+                return None;
+            }
+            match data.generator.next_range_async(remaining).await {
+                Ok(range) => {
+                    let message = StreamIdsResponse { ids: range.ids().collect() };
+                    Some((Ok(message), (data, remaining - range.count)))
+                }
+                Err(e) => Some((Err(status_from_error(e)), (data, 0))),
+            }
+        });
+
+        Ok(Response::new(Box::pin(ids)))
+    }
+
+    async fn decode(&self, request: Request<DecodeRequest>) -> Result<Response<DecodeResponse>, Status> {
+        let result = match self.authorize(&request, "/decode") {
+            Ok(_) => self.data.generator.decode(request.get_ref().id).map_err(status_from_error),
+            Err(denied) => Err(status_from_denied(denied)),
+        };
+        count_request("Decode", &result);
+
+        let decoded = result?;
+        Ok(Response::new(DecodeResponse {
+            timestamp: decoded.timestamp,
+            unix_ms: decoded.unix_ms,
+            time: decoded.rfc3339(),
+            worker_id: decoded.worker_id,
+            sequence: decoded.sequence,
+        }))
+    }
+
+    async fn health(&self, _request: Request<HealthRequest>) -> Result<Response<HealthResponse>, Status> {
+        count_request("Health", &Ok::<_, Status>(()));
+        Ok(Response::new(HealthResponse {
+            status: "healthy".to_string(),
+            worker_id: self.data.generator.worker_id(),
+        }))
+    }
+}
+
+/// Serves the gRPC API on `addr` until the future is dropped. With `tls`,
+/// connections use the same certificates and client verification as the
+/// HTTP listeners.
+pub async fn serve(addr: SocketAddr, data: web::Data<AppState>, tls: Option<rustls::ServerConfig>) -> std::io::Result<()> {
+    let authenticator = Authenticator {
+        keys: data.api_keys.clone(),
+    };
+    let service = IdGeneratorServer::with_interceptor(GrpcService::new(data), authenticator);
+    let router = tonic::transport::Server::builder().add_service(service);
+    let result = match tls {
+        Some(tls) => {
+            let listener = TcpListener::bind(addr).await?;
+            router.serve_with_incoming(tls_incoming(listener, tls)).await
+        }
+        None => router.serve(addr).await,
+    };
+    result.map_err(std::io::Error::other)
+}
+
+/// Accepts TLS connections, running several handshakes at once so a slow
+/// client can't hold up the rest. Failed handshakes are dropped.
+fn tls_incoming(
+    listener: TcpListener,
+    mut config: rustls::ServerConfig,
+) -> impl Stream<Item = std::io::Result<TlsStream<TcpStream>>> {
+    // gRPC clients insist on negotiating HTTP/2
+    config.alpn_protocols = vec![b"h2".to_vec()];
+    let acceptor = TlsAcceptor::from(Arc::new(config));
+
+    stream::unfold(listener, |listener| async move {
+        let accepted = listener.accept().await;
+        Some((accepted, listener))
+    })
+    .filter_map(|accepted| future::ready(accepted.ok()))
+    .map(move |(tcp, _)| tokio::time::timeout(TLS_HANDSHAKE_TIMEOUT, acceptor.accept(tcp)))
+    .buffer_unordered(MAX_PENDING_TLS_HANDSHAKES)
+    .filter_map(|handshake| future::ready(handshake.ok().and_then(Result::ok).map(Ok)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::rate_limit::{Limit, RateLimiter};
+    use crate::MAX_IDS_PER_REQUEST;
+    use futures_util::StreamExt;
+    use id_generator::metrics::THROTTLED_REQUESTS;
+    use id_generator::SnowflakeGenerator;
+    use tonic::Code;
+
+    fn service() -> GrpcService {
+        GrpcService::new(web::Data::new(AppState::with_generator(SnowflakeGenerator::new(3).unwrap())))
+    }
+
+    #[tokio::test]
+    async fn test_next_id_and_decode() {
+        let service = service();
+        let id = service.next_id(Request::new(NextIdRequest {})).await.unwrap().into_inner().id;
+
+        let decoded = service.decode(Request::new(DecodeRequest { id })).await.unwrap().into_inner();
+        assert_eq!(decoded.worker_id, 3);
+        assert!(decoded.time.ends_with('Z'));
+    }
+
+    #[tokio::test]
+    async fn test_next_ids() {
+        let ids = service()
+            .next_ids(Request::new(NextIdsRequest { count: 5000 }))
+            .await
+            .unwrap()
+            .into_inner()
+            .ids;
+
+        assert_eq!(ids.len(), 5000);
+        assert!(ids.windows(2).all(|pair| pair[0] < pair[1]));
+    }
+
+    #[tokio::test]
+    async fn test_invalid_count() {
+        let service = service();
+        for count in [0, MAX_IDS_PER_UNARY_REQUEST + 1] {
+            let status = service.next_ids(Request::new(NextIdsRequest { count })).await.unwrap_err();
+            assert_eq!(status.code(), Code::InvalidArgument);
+        }
+
+        let status = service
+            .stream_ids(Request::new(StreamIdsRequest { count: MAX_IDS_PER_REQUEST + 1 }))
+            .await
+            .err()
+            .unwrap();
+        assert_eq!(status.code(), Code::InvalidArgument);
+    }
+
+    #[tokio::test]
+    async fn test_stream_ids() {
+        let stream = service()
+            .stream_ids(Request::new(StreamIdsRequest { count: 10_000 }))
+            .await
+            .unwrap()
+            .into_inner();
+
+        let messages: Vec<_> = stream.collect().await;
+        assert!(messages.len() >= 3, "10,000 IDs need at least three 4096-ID ranges");
+
+        let ids: Vec<u64> = messages.into_iter().flat_map(|m| m.unwrap().ids).collect();
+        assert_eq!(ids.len(), 10_000);
+        assert!(ids.windows(2).all(|pair| pair[0] < pair[1]));
+    }
+
+    #[tokio::test]
+    async fn test_decode_rejects_invalid_id() {
+        let status = service().decode(Request::new(DecodeRequest { id: 1 << 63 })).await.unwrap_err();
+        assert_eq!(status.code(), Code::InvalidArgument);
+    }
+
+    #[tokio::test]
+    async fn test_requests_counted_by_method_and_code() {
+        let counter = |code: &str| GRPC_REQUESTS.with_label_values(&["NextIds", code]).get();
+        let (ok, invalid) = (counter("Ok"), counter("InvalidArgument"));
+
+        let service = service();
+        service.next_ids(Request::new(NextIdsRequest { count: 2 })).await.unwrap();
+        service.next_ids(Request::new(NextIdsRequest { count: 0 })).await.unwrap_err();
+
+        assert!(counter("Ok") > ok);
+        assert!(counter("InvalidArgument") > invalid);
+    }
+
+    #[tokio::test]
+    async fn test_rate_limited() {
+        let mut state = AppState::with_generator(SnowflakeGenerator::new(3).unwrap());
+        state.rate_limiter = RateLimiter::new(Some(Limit::new(1.0, Some(100))), false);
+        let service = GrpcService::new(web::Data::new(state));
+
+        service.next_ids(Request::new(NextIdsRequest { count: 100 })).await.unwrap();
+        let status = service.next_id(Request::new(NextIdRequest {})).await.unwrap_err();
+        assert_eq!(status.code(), Code::ResourceExhausted);
+        assert_eq!(status.metadata().get("retry-after").unwrap(), "1");
+
+        let status = service.next_ids(Request::new(NextIdsRequest { count: 101 })).await.unwrap_err();
+        assert_eq!(status.code(), Code::InvalidArgument);
+    }
+
+    #[tokio::test]
+    async fn test_rate_limit_refunded_on_errors() {
+        let clock = Arc::new(id_generator::clock::ManualClock::new(crate::UNIX_EPOCH_OFFSET + 1_000_000));
+        let mut state = AppState::new(3, id_generator::GeneratorOptions::default(), clock.clone()).unwrap();
+        state.rate_limiter = RateLimiter::new(Some(Limit::new(0.001, Some(3))), false);
+        let service = GrpcService::new(web::Data::new(state));
+        service.next_id(Request::new(NextIdRequest {})).await.unwrap();
+
+        // Failed calls hand their IDs back
+        clock.rewind(10);
+        let status = service.next_id(Request::new(NextIdRequest {})).await.unwrap_err();
+        assert_eq!(status.code(), Code::Unavailable);
+        let status = service.next_ids(Request::new(NextIdsRequest { count: 2 })).await.unwrap_err();
+        assert_eq!(status.code(), Code::Unavailable);
+
+        clock.advance(20);
+        service.next_ids(Request::new(NextIdsRequest { count: 2 })).await.unwrap();
+        let status = service.next_id(Request::new(NextIdRequest {})).await.unwrap_err();
+        assert_eq!(status.code(), Code::ResourceExhausted);
+    }
+
+    /// A service requiring API keys, with one key that may only call `/ids`
+    /// for up to 10 IDs.
+    fn service_with_keys() -> GrpcService {
+        let key = ApiKey {
+            id: "bulk".to_string(),
+            secret_sha256: auth::hash_token("bulk-token"),
+            max_ids_per_request: Some(10),
+            endpoints: Some(vec!["/ids".to_string()]),
+            namespaces: None,
+            ids_per_sec: None,
+            burst: None,
+            client_subject: None,
+        };
+        let mut state = AppState::with_generator(SnowflakeGenerator::new(3).unwrap());
+        state.api_keys = Some(Arc::new(ApiKeys::new(vec![key]).unwrap()));
+        GrpcService::new(web::Data::new(state))
+    }
+
+    /// Builds a request as the interceptor would pass it on.
+    fn authenticated<T>(service: &GrpcService, message: T, token: Option<&str>) -> Result<Request<T>, Denied> {
+        let mut request = Request::new(message);
+        if let Some(token) = token {
+            request
+                .metadata_mut()
+                .insert("authorization", format!("Bearer {}", token).parse().unwrap());
+        }
+        authenticate(service.data.api_keys.as_deref(), &mut request)?;
+        Ok(request)
+    }
+
+    #[tokio::test]
+    async fn test_api_keys_required() {
+        let service = service_with_keys();
+
+        let request = authenticated(&service, NextIdRequest {}, None).unwrap();
+        let status = service.next_id(request).await.unwrap_err();
+        assert_eq!(status.code(), Code::Unauthenticated);
+
+        let denied = authenticated(&service, NextIdRequest {}, Some("guess")).unwrap_err();
+        assert_eq!(status_from_denied(denied).code(), Code::Unauthenticated);
+
+        let request = authenticated(&service, StreamIdsRequest { count: 5 }, None).unwrap();
+        let status = service.stream_ids(request).await.err().unwrap();
+        assert_eq!(status.code(), Code::Unauthenticated);
+
+        // Health stays open for probes
+        let request = authenticated(&service, HealthRequest {}, None).unwrap();
+        assert!(service.health(request).await.is_ok());
+    }
+
+    #[tokio::test]
+    async fn test_api_key_limits_apply() {
+        let service = service_with_keys();
+
+        let request = authenticated(&service, NextIdRequest {}, Some("bulk-token")).unwrap();
+        let status = service.next_id(request).await.unwrap_err();
+        assert_eq!(status.code(), Code::PermissionDenied);
+        assert!(status.message().contains("may not call /id"));
+
+        let request = authenticated(&service, DecodeRequest { id: 1 }, Some("bulk-token")).unwrap();
+        assert_eq!(service.decode(request).await.unwrap_err().code(), Code::PermissionDenied);
+
+        let request = authenticated(&service, NextIdsRequest { count: 10 }, Some("bulk-token")).unwrap();
+        assert_eq!(service.next_ids(request).await.unwrap().into_inner().ids.len(), 10);
+
+        let request = authenticated(&service, NextIdsRequest { count: 11 }, Some("bulk-token")).unwrap();
+        assert_eq!(service.next_ids(request).await.unwrap_err().code(), Code::InvalidArgument);
+
+        let request = authenticated(&service, StreamIdsRequest { count: 11 }, Some("bulk-token")).unwrap();
+        assert_eq!(service.stream_ids(request).await.err().unwrap().code(), Code::InvalidArgument);
+    }
+
+    #[tokio::test]
+    async fn test_api_key_rate_limit_applies() {
+        let key = ApiKey {
+            id: "grpc-limited".to_string(),
+            secret_sha256: auth::hash_token("limited-token"),
+            max_ids_per_request: None,
+            endpoints: None,
+            namespaces: None,
+            ids_per_sec: Some(1.0),
+            burst: Some(5),
+            client_subject: None,
+        };
+        let mut state = AppState::with_generator(SnowflakeGenerator::new(3).unwrap());
+        state.api_keys = Some(Arc::new(ApiKeys::new(vec![key]).unwrap()));
+        let service = GrpcService::new(web::Data::new(state));
+        let throttled = || THROTTLED_REQUESTS.with_label_values(&["key:grpc-limited"]).get();
+        let before = throttled();
+
+        // No default limit, so only the key's own one can refuse these
+        let request = authenticated(&service, NextIdsRequest { count: 5 }, Some("limited-token")).unwrap();
+        service.next_ids(request).await.unwrap();
+        let request = authenticated(&service, NextIdRequest {}, Some("limited-token")).unwrap();
+        let status = service.next_id(request).await.unwrap_err();
+        assert_eq!(status.code(), Code::ResourceExhausted);
+        assert_eq!(throttled(), before + 1);
+
+        // Counts beyond the burst are refused and counted too
+        let request = authenticated(&service, StreamIdsRequest { count: 6 }, Some("limited-token")).unwrap();
+        assert_eq!(service.stream_ids(request).await.err().unwrap().code(), Code::InvalidArgument);
+        assert_eq!(throttled(), before + 2);
+    }
+
+    #[tokio::test]
+    async fn test_tls_incoming_negotiates_h2() {
+        let key = rcgen::KeyPair::generate().unwrap();
+        let cert = rcgen::CertificateParams::new(vec!["localhost".to_string()])
+            .unwrap()
+            .self_signed(&key)
+            .unwrap();
+        let provider = Arc::new(rustls::crypto::ring::default_provider());
+        let server_config = rustls::ServerConfig::builder_with_provider(provider.clone())
+            .with_safe_default_protocol_versions()
+            .unwrap()
+            .with_no_client_auth()
+            .with_single_cert(
+                vec![cert.der().clone()],
+                rustls::pki_types::PrivateKeyDer::try_from(key.serialize_der()).unwrap(),
+            )
+            .unwrap();
+
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let mut incoming = Box::pin(tls_incoming(listener, server_config));
+
+        let mut roots = rustls::RootCertStore::empty();
+        roots.add(cert.der().clone()).unwrap();
+        let mut client_config = rustls::ClientConfig::builder_with_provider(provider)
+            .with_safe_default_protocol_versions()
+            .unwrap()
+            .with_root_certificates(roots)
+            .with_no_client_auth();
+        client_config.alpn_protocols = vec![b"h2".to_vec()];
+        let connector = tokio_rustls::TlsConnector::from(Arc::new(client_config));
+
+        // A client that hangs up mid-handshake is skipped
+        drop(TcpStream::connect(addr).await.unwrap());
+
+        let client = async {
+            let tcp = TcpStream::connect(addr).await.unwrap();
+            let name = rustls::pki_types::ServerName::try_from("localhost").unwrap();
+            connector.connect(name, tcp).await.unwrap()
+        };
+        let (server, _client) = tokio::join!(incoming.next(), client);
+        let server = server.unwrap().unwrap();
+        assert_eq!(server.get_ref().1.alpn_protocol(), Some(&b"h2"[..]));
+    }
+
+    #[test]
+    fn test_status_mapping() {
+        assert_eq!(status_from_error(SnowflakeError::ClockDriftTimeout).code(), Code::Unavailable);
+        assert_eq!(status_from_error(SnowflakeError::ClockBehind { behind_ms: 5 }).code(), Code::Unavailable);
+        assert_eq!(status_from_error(SnowflakeError::InvalidId(1)).code(), Code::InvalidArgument);
+        assert_eq!(status_from_error(SnowflakeError::EpochInFuture(1)).code(), Code::Internal);
+    }
+}
@@ -0,0 +1,188 @@
+use std::fs::{self, File};
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Mutex;
+
+use crate::SnowflakeError;
+
+/// Default size of the window reserved ahead of the clock with each write.
+pub const DEFAULT_WINDOW_MS: u64 = 1000;
+
+/// Where and how far ahead to persist the high-water mark.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct HighWaterMarkOptions {
+    /// File holding the mark as Unix milliseconds.
+    pub path: PathBuf,
+    /// How far past the current timestamp each write reserves. Larger windows
+    /// mean fewer writes but a longer wait after an unclean restart.
+    pub window_ms: u64,
+}
+
+impl HighWaterMarkOptions {
+    pub fn new(path: impl Into<PathBuf>) -> Self {
+        Self {
+            path: path.into(),
+            window_ms: DEFAULT_WINDOW_MS,
+        }
+    }
+}
+
+/// A persisted upper bound on the timestamps this worker may have issued.
+///
+/// Before an ID with a timestamp past the current mark is issued, the mark is
+/// moved to that timestamp plus the window and written to disk, so the file
+/// always covers every issued ID even if the process dies without flushing.
+pub(crate) struct HighWaterMark {
+    path: PathBuf,
+    window_ms: u64,
+    /// Generator timestamp up to which IDs may be issued without writing.
+    reserved_until: AtomicU64,
+    write_lock: Mutex<()>,
+}
+
+impl HighWaterMark {
+    /// Reads the existing mark, if any, and checks that the file is writable.
+    ///
+    /// Returns the mark in Unix milliseconds alongside the handle.
+    pub(crate) fn open(options: HighWaterMarkOptions, epoch_ms: u64) -> Result<(Self, Option<u64>), SnowflakeError> {
+        let HighWaterMarkOptions { path, window_ms } = options;
+
+        let persisted = match fs::read_to_string(&path) {
+            Ok(contents) => Some(contents.trim().parse::<u64>().map_err(|_| {
+                error(&path, format!("expected Unix milliseconds, got: '{}'", contents.trim()))
+            })?),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => None,
+            Err(e) => return Err(error(&path, e)),
+        };
+
+        // Write the mark back so an unwritable path fails at startup rather
+        // than on the first ID
+        let mark = persisted.unwrap_or(epoch_ms);
+        write_atomic(&path, mark).map_err(|e| error(&path, e))?;
+
+        let hwm = Self {
+            path,
+            window_ms,
+            reserved_until: AtomicU64::new(mark.saturating_sub(epoch_ms)),
+            write_lock: Mutex::new(()),
+        };
+        Ok((hwm, persisted))
+    }
+
+    /// Makes sure the persisted mark covers `timestamp`, writing a new one if
+    /// it doesn't.
+    pub(crate) fn reserve(&self, timestamp: u64, epoch_ms: u64) -> Result<(), SnowflakeError> {
+        if timestamp <= self.reserved_until.load(Ordering::Acquire) {
+            return Ok(());
+        }
+
+        let _guard = self.write_lock.lock().unwrap_or_else(|e| e.into_inner());
+        // Another caller may have moved the mark while we waited for the lock
+        if timestamp <= self.reserved_until.load(Ordering::Acquire) {
+            return Ok(());
+        }
+
+        let until = timestamp + self.window_ms;
+        write_atomic(&self.path, epoch_ms + until).map_err(|e| error(&self.path, e))?;
+        self.reserved_until.store(until, Ordering::Release);
+        Ok(())
+    }
+
+    /// Writes `last_timestamp` as the mark, giving back the unused part of the
+    /// reserved window.
+    pub(crate) fn flush(&self, last_timestamp: u64, epoch_ms: u64) -> Result<(), SnowflakeError> {
+        let _guard = self.write_lock.lock().unwrap_or_else(|e| e.into_inner());
+        write_atomic(&self.path, epoch_ms + last_timestamp).map_err(|e| error(&self.path, e))?;
+        self.reserved_until.store(last_timestamp, Ordering::Release);
+        Ok(())
+    }
+}
+
+fn error(path: &Path, message: impl ToString) -> SnowflakeError {
+    SnowflakeError::HighWaterMark {
+        path: path.to_path_buf(),
+        message: message.to_string(),
+    }
+}
+
+/// Replaces the file via a synced temporary file and a rename, so a crash
+/// mid-write leaves either the old mark or the new one.
+fn write_atomic(path: &Path, unix_ms: u64) -> std::io::Result<()> {
+    let mut tmp_name = path.as_os_str().to_owned();
+    tmp_name.push(".tmp");
+    let tmp_path = PathBuf::from(tmp_name);
+
+    let mut file = File::create(&tmp_path)?;
+    writeln!(file, "{}", unix_ms)?;
+    file.sync_all()?;
+    fs::rename(&tmp_path, path)?;
+
+    // Persist the rename itself; not every platform supports syncing a directory
+    if let Some(dir) = path.parent().filter(|dir| !dir.as_os_str().is_empty()) {
+        if let Ok(dir) = File::open(dir) {
+            let _ = dir.sync_all();
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn temp_path(name: &str) -> PathBuf {
+        let path = std::env::temp_dir().join(format!("id-generator-hwm-{}-{}", std::process::id(), name));
+        let _ = fs::remove_file(&path);
+        path
+    }
+
+    #[test]
+    fn test_open_missing_file_writes_epoch() {
+        let path = temp_path("missing");
+        let (hwm, persisted) = HighWaterMark::open(HighWaterMarkOptions::new(&path), 1000).unwrap();
+
+        assert_eq!(persisted, None);
+        assert_eq!(hwm.reserved_until.load(Ordering::Acquire), 0);
+        assert_eq!(fs::read_to_string(&path).unwrap().trim(), "1000");
+        fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn test_reserve_writes_window_ahead() {
+        let path = temp_path("reserve");
+        let options = HighWaterMarkOptions { path: path.clone(), window_ms: 500 };
+        let (hwm, _) = HighWaterMark::open(options, 1000).unwrap();
+
+        hwm.reserve(10, 1000).unwrap();
+        assert_eq!(fs::read_to_string(&path).unwrap().trim(), "1510");
+
+        // Covered by the reserved window, so nothing is written
+        hwm.reserve(400, 1000).unwrap();
+        assert_eq!(fs::read_to_string(&path).unwrap().trim(), "1510");
+
+        hwm.flush(400, 1000).unwrap();
+        assert_eq!(fs::read_to_string(&path).unwrap().trim(), "1400");
+
+        let (_, persisted) = HighWaterMark::open(HighWaterMarkOptions::new(&path), 1000).unwrap();
+        assert_eq!(persisted, Some(1400));
+        fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn test_open_rejects_corrupt_file() {
+        let path = temp_path("corrupt");
+        fs::write(&path, "not a number").unwrap();
+
+        let result = HighWaterMark::open(HighWaterMarkOptions::new(&path), 0);
+        assert!(matches!(result, Err(SnowflakeError::HighWaterMark { .. })));
+        fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn test_open_rejects_unwritable_path() {
+        let path = temp_path("no-such-dir").join("mark");
+        let result = HighWaterMark::open(HighWaterMarkOptions::new(&path), 0);
+        assert!(matches!(result, Err(SnowflakeError::HighWaterMark { .. })));
+    }
+}
@@ -0,0 +1,171 @@
+use crate::{DecodedSnowflake, SnowflakeError};
+
+/// Bit widths of the three snowflake fields.
+///
+/// Fields are packed most significant first: timestamp, worker ID, sequence.
+/// The widths must sum to 63 (top bit always clear, so IDs fit in an `i64`) or
+/// 64 (all bits used).
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Layout {
+    timestamp_bits: u8,
+    worker_id_bits: u8,
+    sequence_bits: u8,
+}
+
+impl Layout {
+    /// The classic 41/10/12 Twitter layout.
+    pub const DEFAULT: Layout = Layout {
+        timestamp_bits: 41,
+        worker_id_bits: 10,
+        sequence_bits: 12,
+    };
+
+    pub fn new(timestamp_bits: u8, worker_id_bits: u8, sequence_bits: u8) -> Result<Self, SnowflakeError> {
+        let total = u32::from(timestamp_bits) + u32::from(worker_id_bits) + u32::from(sequence_bits);
+
+        if timestamp_bits == 0 || worker_id_bits == 0 || sequence_bits == 0 || !(63..=64).contains(&total) {
+            return Err(SnowflakeError::InvalidLayout {
+                timestamp_bits,
+                worker_id_bits,
+                sequence_bits,
+            });
+        }
+
+        Ok(Self {
+            timestamp_bits,
+            worker_id_bits,
+            sequence_bits,
+        })
+    }
+
+    pub fn timestamp_bits(&self) -> u8 {
+        self.timestamp_bits
+    }
+
+    pub fn worker_id_bits(&self) -> u8 {
+        self.worker_id_bits
+    }
+
+    pub fn sequence_bits(&self) -> u8 {
+        self.sequence_bits
+    }
+
+    pub fn timestamp_mask(&self) -> u64 {
+        mask(self.timestamp_bits)
+    }
+
+    pub fn worker_id_mask(&self) -> u64 {
+        mask(self.worker_id_bits)
+    }
+
+    pub fn sequence_mask(&self) -> u64 {
+        mask(self.sequence_bits)
+    }
+
+    pub fn max_worker_id(&self) -> u64 {
+        self.worker_id_mask()
+    }
+
+    fn worker_id_shift(&self) -> u32 {
+        u32::from(self.sequence_bits)
+    }
+
+    fn timestamp_shift(&self) -> u32 {
+        u32::from(self.worker_id_bits) + u32::from(self.sequence_bits)
+    }
+
+    /// Packs the three snowflake fields into a single 64-bit ID, truncating
+    /// each value to its field width.
+    pub fn format(&self, worker_id: u64, sequence: u64, timestamp: u64) -> u64 {
+        ((timestamp & self.timestamp_mask()) << self.timestamp_shift())
+            | ((worker_id & self.worker_id_mask()) << self.worker_id_shift())
+            | (sequence & self.sequence_mask())
+    }
+
+    /// Splits an ID into its fields without checking it against the clock.
+    /// `epoch_ms` is only used to compute the absolute time.
+    ///
+    /// Fails if the ID has bits set above the timestamp field.
+    pub fn split(&self, id: u64, epoch_ms: u64) -> Result<DecodedSnowflake, SnowflakeError> {
+        let timestamp = id >> self.timestamp_shift();
+        if timestamp > self.timestamp_mask() {
+            return Err(SnowflakeError::InvalidId(id));
+        }
+
+        Ok(DecodedSnowflake {
+            timestamp,
+            unix_ms: timestamp.saturating_add(epoch_ms),
+            worker_id: (id >> self.worker_id_shift()) & self.worker_id_mask(),
+            sequence: id & self.sequence_mask(),
+        })
+    }
+}
+
+impl Default for Layout {
+    fn default() -> Self {
+        Self::DEFAULT
+    }
+}
+
+fn mask(bits: u8) -> u64 {
+    if bits >= 64 {
+        u64::MAX
+    } else {
+        (1 << bits) - 1
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_default_layout_masks() {
+        let layout = Layout::default();
+        assert_eq!(layout.timestamp_mask(), 0x1FFFFFFFFFF);
+        assert_eq!(layout.worker_id_mask(), 0x3FF);
+        assert_eq!(layout.sequence_mask(), 0xFFF);
+        assert_eq!(layout.max_worker_id(), 1023);
+    }
+
+    #[test]
+    fn test_new_accepts_63_and_64_bits() {
+        assert!(Layout::new(41, 8, 14).is_ok());
+        assert!(Layout::new(42, 10, 12).is_ok());
+    }
+
+    #[test]
+    fn test_new_rejects_invalid_widths() {
+        assert!(Layout::new(40, 10, 12).is_err());
+        assert!(Layout::new(43, 10, 12).is_err());
+        assert!(Layout::new(53, 0, 10).is_err());
+        assert!(Layout::new(0, 31, 32).is_err());
+    }
+
+    #[test]
+    fn test_custom_layout_roundtrip() {
+        let layout = Layout::new(41, 8, 14).unwrap();
+        let id = layout.format(255, 16383, 12345);
+
+        let decoded = layout.split(id, 0).unwrap();
+
+        assert_eq!(decoded.timestamp, 12345);
+        assert_eq!(decoded.worker_id, 255);
+        assert_eq!(decoded.sequence, 16383);
+    }
+
+    #[test]
+    fn test_64_bit_layout_uses_top_bit() {
+        let layout = Layout::new(42, 10, 12).unwrap();
+        let id = layout.format(0, 0, layout.timestamp_mask());
+
+        assert_eq!(id >> 63, 1);
+        assert_eq!(layout.split(id, 0).unwrap().timestamp, layout.timestamp_mask());
+    }
+
+    #[test]
+    fn test_split_rejects_bits_above_layout() {
+        let layout = Layout::default();
+        assert!(matches!(layout.split(1 << 63, 0), Err(SnowflakeError::InvalidId(_))));
+    }
+}
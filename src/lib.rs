@@ -0,0 +1,1242 @@
+use std::path::PathBuf;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+
+pub mod clock;
+pub mod encoding;
+pub mod high_water_mark;
+pub mod layout;
+pub mod metrics;
+pub mod time;
+
+pub use clock::{Clock, SystemClock};
+pub use high_water_mark::HighWaterMarkOptions;
+pub use layout::Layout;
+
+use high_water_mark::HighWaterMark;
+
+use metrics::{
+    BORROWED_LEAD_MS, CLOCK_BACKWARD, CLOCK_BACKWARD_MS, CLOCK_DRIFT_TIMEOUTS, CURRENT_SEQUENCE,
+    GENERATION_DURATION, IDS_GENERATED, LOCK_WAIT, SEQUENCE_EXHAUSTED, SLEEP_DURATION,
+};
+
+// Constants
+pub const UNIX_EPOCH_OFFSET: u64 = 1705065354064;
+
+/// Default for [`GeneratorOptions::drift_timeout_ms`].
+pub const CLOCK_DRIFT_TIMEOUT_MS: u64 = 100;
+
+// How long to wait before re-reading the clock while waiting for it to advance
+const POLL_INTERVAL: Duration = Duration::from_micros(100);
+
+// Custom error type for snowflake generation
+#[derive(Debug)]
+pub enum SnowflakeError {
+    ClockDriftTimeout,
+    ClockBehind { behind_ms: u64 },
+    InvalidWorkerId { worker_id: u64, max: u64 },
+    InvalidLayout { timestamp_bits: u8, worker_id_bits: u8, sequence_bits: u8 },
+    EpochInFuture(u64),
+    TimestampOverflow { epoch_ms: u64, timestamp_bits: u8 },
+    InvalidId(u64),
+    FutureTimestamp(u64),
+    HighWaterMark { path: PathBuf, message: String },
+}
+
+impl std::fmt::Display for SnowflakeError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            SnowflakeError::ClockDriftTimeout => write!(f, "Clock drift timeout exceeded"),
+            SnowflakeError::ClockBehind { behind_ms } => {
+                write!(f, "Clock is {} ms behind the last issued timestamp", behind_ms)
+            }
+            SnowflakeError::InvalidWorkerId { worker_id, max } => write!(
+                f,
+                "Worker ID must be between 0 and {}, got: {}",
+                max, worker_id
+            ),
+            SnowflakeError::InvalidLayout { timestamp_bits, worker_id_bits, sequence_bits } => write!(
+                f,
+                "Bit layout {}/{}/{} is invalid: each field needs at least 1 bit and the widths must sum to 63 or 64",
+                timestamp_bits, worker_id_bits, sequence_bits
+            ),
+            SnowflakeError::EpochInFuture(epoch_ms) => write!(
+                f,
+                "Epoch {} ({}) is in the future",
+                epoch_ms,
+                time::format_rfc3339(*epoch_ms)
+            ),
+            SnowflakeError::TimestampOverflow { epoch_ms, timestamp_bits } => write!(
+                f,
+                "Current time no longer fits in the {}-bit timestamp field for epoch {} ({})",
+                timestamp_bits,
+                epoch_ms,
+                time::format_rfc3339(*epoch_ms)
+            ),
+            SnowflakeError::InvalidId(id) => write!(f, "ID {} does not fit the snowflake layout", id),
+            SnowflakeError::FutureTimestamp(id) => write!(f, "ID {} has a timestamp in the future", id),
+            SnowflakeError::HighWaterMark { path, message } => {
+                write!(f, "High-water mark file {}: {}", path.display(), message)
+            }
+        }
+    }
+}
+
+impl std::error::Error for SnowflakeError {}
+
+/// Milliseconds elapsed since the given epoch (in Unix milliseconds).
+pub fn get_timestamp(epoch_ms: u64) -> u64 {
+    time::unix_millis().saturating_sub(epoch_ms)
+}
+
+/// The fields of a snowflake ID, as returned by [`SnowflakeGenerator::decode`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct DecodedSnowflake {
+    /// Milliseconds since the custom epoch.
+    pub timestamp: u64,
+    /// Milliseconds since the Unix epoch.
+    pub unix_ms: u64,
+    pub worker_id: u64,
+    pub sequence: u64,
+}
+
+impl DecodedSnowflake {
+    /// The timestamp as an RFC 3339 UTC string with millisecond precision.
+    pub fn rfc3339(&self) -> String {
+        time::format_rfc3339(self.unix_ms)
+    }
+}
+
+/// What to do when the clock is behind the last issued timestamp.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum DriftPolicy {
+    /// Wait for the clock to catch up, failing with
+    /// [`SnowflakeError::ClockDriftTimeout`] after the drift timeout.
+    #[default]
+    Wait,
+    /// Fail with [`SnowflakeError::ClockBehind`] straight away.
+    FailFast,
+    /// Keep issuing IDs from the last issued timestamp, moving on into future
+    /// milliseconds as sequences run out, as long as that stays at most
+    /// `max_lead_ms` ahead of the clock. Beyond that, wait as with `Wait`.
+    ///
+    /// This also lets a busy generator run ahead of the clock instead of
+    /// waiting for the next millisecond when a sequence is exhausted.
+    Borrow { max_lead_ms: u64 },
+}
+
+impl DriftPolicy {
+    /// How far ahead of the clock IDs may be issued.
+    pub fn max_lead_ms(&self) -> u64 {
+        match self {
+            DriftPolicy::Borrow { max_lead_ms } => *max_lead_ms,
+            DriftPolicy::Wait | DriftPolicy::FailFast => 0,
+        }
+    }
+}
+
+/// Generator settings. `layout` and `epoch_ms` must be the same for every
+/// generator issuing IDs in the same keyspace.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct GeneratorOptions {
+    pub layout: Layout,
+    /// Custom epoch in Unix milliseconds.
+    pub epoch_ms: u64,
+    /// Persist the last issued timestamp so a restarted generator doesn't
+    /// reissue IDs after the clock has moved backwards. Off by default.
+    pub high_water_mark: Option<HighWaterMarkOptions>,
+    pub drift_policy: DriftPolicy,
+    /// How long to wait for the clock before giving up with
+    /// [`SnowflakeError::ClockDriftTimeout`].
+    pub drift_timeout_ms: u64,
+}
+
+impl Default for GeneratorOptions {
+    fn default() -> Self {
+        Self {
+            layout: Layout::DEFAULT,
+            epoch_ms: UNIX_EPOCH_OFFSET,
+            high_water_mark: None,
+            drift_policy: DriftPolicy::Wait,
+            drift_timeout_ms: CLOCK_DRIFT_TIMEOUT_MS,
+        }
+    }
+}
+
+/// Thread-safe, lock-free snowflake generator for a single worker.
+///
+/// Owns the last issued timestamp and sequence, so the same instance must be
+/// shared by everything generating IDs for this worker ID.
+pub struct SnowflakeGenerator {
+    layout: Layout,
+    epoch_ms: u64,
+    worker_id: u64,
+    /// Last issued timestamp and sequence, packed as
+    /// `timestamp << sequence_bits | sequence`.
+    state: AtomicU64,
+    high_water_mark: Option<HighWaterMark>,
+    clock: Arc<dyn Clock>,
+    drift_policy: DriftPolicy,
+    drift_timeout_ms: u64,
+    /// Times a caller found the sequence exhausted, as counted by
+    /// `SEQUENCE_EXHAUSTED` but for this generator only.
+    exhaustions: AtomicU64,
+    /// Set while the clock is further behind the last issued timestamp than
+    /// the drift policy allows, so each backward step is counted once.
+    clock_behind: AtomicBool,
+    backward_events: AtomicU64,
+}
+
+/// A run of consecutive IDs issued within one millisecond.
+///
+/// The IDs share timestamp and worker ID and differ only in their sequence
+/// number, so the run is fully described by its first sequence and length.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct IdRange {
+    layout: Layout,
+    /// Milliseconds since the custom epoch.
+    pub timestamp: u64,
+    pub worker_id: u64,
+    pub sequence_start: u64,
+    pub count: u64,
+}
+
+impl IdRange {
+    pub fn sequence_end(&self) -> u64 {
+        self.sequence_start + self.count - 1
+    }
+
+    pub fn first_id(&self) -> u64 {
+        self.layout.format(self.worker_id, self.sequence_start, self.timestamp)
+    }
+
+    pub fn last_id(&self) -> u64 {
+        self.layout.format(self.worker_id, self.sequence_end(), self.timestamp)
+    }
+
+    /// The IDs in the range, in increasing order.
+    pub fn ids(&self) -> impl Iterator<Item = u64> {
+        let range = *self;
+        (range.sequence_start..=range.sequence_end())
+            .map(move |sequence| range.layout.format(range.worker_id, sequence, range.timestamp))
+    }
+}
+
+/// Tracks how long a single reservation has been waiting for the clock.
+#[derive(Default)]
+struct WaitState {
+    started: Option<Instant>,
+    exhausted: bool,
+}
+
+impl WaitState {
+    /// Records how long a reservation took, and how much of that was spent
+    /// waiting for the clock.
+    fn observe(&self, reservation_started: Instant) {
+        GENERATION_DURATION.observe(reservation_started.elapsed().as_secs_f64());
+        if let Some(waiting_since) = self.started {
+            SLEEP_DURATION.observe(waiting_since.elapsed().as_secs_f64());
+        }
+    }
+}
+
+impl SnowflakeGenerator {
+    /// Creates a generator using the default layout and epoch.
+    pub fn new(worker_id: u64) -> Result<Self, SnowflakeError> {
+        Self::with_options(worker_id, GeneratorOptions::default())
+    }
+
+    /// Creates a generator, checking that the worker ID fits the layout and
+    /// that the current time can be represented relative to the epoch.
+    ///
+    /// With a high-water mark configured, the persisted mark is loaded and no
+    /// IDs are issued until the clock has passed it.
+    pub fn with_options(worker_id: u64, options: GeneratorOptions) -> Result<Self, SnowflakeError> {
+        Self::with_clock(worker_id, options, Arc::new(SystemClock))
+    }
+
+    /// Like [`with_options`](Self::with_options), but reading the time from
+    /// `clock` instead of the system clock.
+    pub fn with_clock(worker_id: u64, options: GeneratorOptions, clock: Arc<dyn Clock>) -> Result<Self, SnowflakeError> {
+        let GeneratorOptions {
+            layout,
+            epoch_ms,
+            high_water_mark,
+            drift_policy,
+            drift_timeout_ms,
+        } = options;
+
+        if worker_id > layout.max_worker_id() {
+            return Err(SnowflakeError::InvalidWorkerId {
+                worker_id,
+                max: layout.max_worker_id(),
+            });
+        }
+
+        let now = clock.unix_millis();
+        if epoch_ms > now {
+            return Err(SnowflakeError::EpochInFuture(epoch_ms));
+        }
+        if now - epoch_ms > layout.timestamp_mask() {
+            return Err(SnowflakeError::TimestampOverflow {
+                epoch_ms,
+                timestamp_bits: layout.timestamp_bits(),
+            });
+        }
+
+        let (high_water_mark, persisted) = match high_water_mark {
+            Some(options) => {
+                let (hwm, persisted) = HighWaterMark::open(options, epoch_ms)?;
+                (Some(hwm), persisted)
+            }
+            None => (None, None),
+        };
+
+        // Start as if the whole millisecond at the mark had been used, so the
+        // first ID comes from a later millisecond
+        let state = match persisted {
+            Some(mark) => {
+                let timestamp = mark.saturating_sub(epoch_ms).min(layout.timestamp_mask());
+                (timestamp << layout.sequence_bits()) | layout.sequence_mask()
+            }
+            None => 0,
+        };
+
+        Ok(Self {
+            layout,
+            epoch_ms,
+            worker_id,
+            state: AtomicU64::new(state),
+            high_water_mark,
+            clock,
+            drift_policy,
+            drift_timeout_ms,
+            exhaustions: AtomicU64::new(0),
+            clock_behind: AtomicBool::new(false),
+            backward_events: AtomicU64::new(0),
+        })
+    }
+
+    pub fn worker_id(&self) -> u64 {
+        self.worker_id
+    }
+
+    pub fn layout(&self) -> &Layout {
+        &self.layout
+    }
+
+    pub fn epoch_ms(&self) -> u64 {
+        self.epoch_ms
+    }
+
+    /// How many times callers have had to wait for the next millisecond
+    /// because the sequence ran out.
+    pub fn sequence_exhaustions(&self) -> u64 {
+        self.exhaustions.load(Ordering::Relaxed)
+    }
+
+    /// How many times the clock has been found further behind the last issued
+    /// timestamp than the drift policy allows.
+    pub fn clock_backward_events(&self) -> u64 {
+        self.backward_events.load(Ordering::Relaxed)
+    }
+
+    pub fn drift_policy(&self) -> DriftPolicy {
+        self.drift_policy
+    }
+
+    /// How far the last issued timestamp is ahead of the clock, in
+    /// milliseconds. Only non-zero after a backward clock step, or when
+    /// borrowing from the future.
+    pub fn lead_ms(&self) -> u64 {
+        self.last_timestamp().saturating_sub(self.current_timestamp())
+    }
+
+    pub fn clock(&self) -> &Arc<dyn Clock> {
+        &self.clock
+    }
+
+    /// Milliseconds since the epoch according to this generator's clock.
+    pub fn current_timestamp(&self) -> u64 {
+        self.clock.unix_millis().saturating_sub(self.epoch_ms)
+    }
+
+    /// Timestamp of the last issued ID, in milliseconds since the epoch. After
+    /// loading a high-water mark this is the mark until the first new ID.
+    pub fn last_timestamp(&self) -> u64 {
+        self.state.load(Ordering::Acquire) >> self.layout.sequence_bits()
+    }
+
+    /// Writes the timestamp of the last issued ID to the high-water mark file,
+    /// giving back the rest of the reserved window so a restart can resume
+    /// issuing IDs sooner. Call once generation has stopped, e.g. on shutdown.
+    /// Does nothing without a high-water mark.
+    pub fn flush_high_water_mark(&self) -> Result<(), SnowflakeError> {
+        match &self.high_water_mark {
+            Some(hwm) => hwm.flush(self.last_timestamp(), self.epoch_ms),
+            None => Ok(()),
+        }
+    }
+
+    /// Generates a single ID, sleeping the current thread if the sequence is
+    /// exhausted or the clock moved backwards.
+    pub fn next_id(&self) -> Result<u64, SnowflakeError> {
+        Ok(self.next_range(1)?.first_id())
+    }
+
+    /// Generates `count` strictly increasing IDs, sleeping the current thread
+    /// whenever it has to wait for the clock.
+    ///
+    /// Sequence numbers are reserved a millisecond's worth at a time, so
+    /// concurrent callers interleave with a large batch instead of waiting for
+    /// it to finish. IDs within a batch are therefore not necessarily
+    /// contiguous.
+    pub fn next_ids(&self, count: u64) -> Result<Vec<u64>, SnowflakeError> {
+        let mut results = Vec::with_capacity(count as usize);
+
+        while (results.len() as u64) < count {
+            results.extend(self.next_range(count - results.len() as u64)?.ids());
+        }
+
+        Ok(results)
+    }
+
+    /// Async version of [`next_id`](Self::next_id) that waits on the tokio
+    /// timer instead of blocking the thread, so other tasks keep running.
+    pub async fn next_id_async(&self) -> Result<u64, SnowflakeError> {
+        Ok(self.next_range_async(1).await?.first_id())
+    }
+
+    /// Async version of [`next_ids`](Self::next_ids). Also yields to the
+    /// runtime between milliseconds so a large batch doesn't monopolise the
+    /// worker thread.
+    pub async fn next_ids_async(&self, count: u64) -> Result<Vec<u64>, SnowflakeError> {
+        let mut results = Vec::with_capacity(count as usize);
+
+        while (results.len() as u64) < count {
+            results.extend(self.next_range_async(count - results.len() as u64).await?.ids());
+            tokio::task::yield_now().await;
+        }
+
+        Ok(results)
+    }
+
+    /// Reserves up to `max` consecutive IDs within the current millisecond,
+    /// sleeping the current thread if it has to wait for the clock.
+    ///
+    /// Always reserves at least one ID. The returned range may be shorter than
+    /// `max` if the millisecond runs out of sequence numbers.
+    pub fn next_range(&self, max: u64) -> Result<IdRange, SnowflakeError> {
+        let started = Instant::now();
+        let mut wait = WaitState::default();
+        let result = loop {
+            match self.try_reserve(max, &mut wait) {
+                Ok(Some(range)) => break Ok(range),
+                Ok(None) => std::thread::sleep(POLL_INTERVAL),
+                Err(e) => break Err(e),
+            }
+        };
+        wait.observe(started);
+        result
+    }
+
+    /// Async version of [`next_range`](Self::next_range).
+    pub async fn next_range_async(&self, max: u64) -> Result<IdRange, SnowflakeError> {
+        let started = Instant::now();
+        let mut wait = WaitState::default();
+        let result = loop {
+            match self.try_reserve(max, &mut wait) {
+                Ok(Some(range)) => break Ok(range),
+                Ok(None) => tokio::time::sleep(POLL_INTERVAL).await,
+                Err(e) => break Err(e),
+            }
+        };
+        wait.observe(started);
+        result
+    }
+
+    /// Claims up to `max` consecutive sequence numbers within a single
+    /// millisecond with one compare-and-swap on the packed state.
+    ///
+    /// Returns `Ok(None)` when the caller has to wait for the clock to move
+    /// forward, and an error once it has waited longer than the drift
+    /// timeout, or straight away if the drift policy is `FailFast`.
+    fn try_reserve(&self, max: u64, wait: &mut WaitState) -> Result<Option<IdRange>, SnowflakeError> {
+        let timeout = Duration::from_millis(self.drift_timeout_ms);
+        let sequence_bits = u32::from(self.layout.sequence_bits());
+        let sequence_mask = self.layout.sequence_mask();
+        let max_lead = self.drift_policy.max_lead_ms();
+        // Set when the compare-and-swap first loses to another caller
+        let mut contended: Option<Instant> = None;
+
+        loop {
+            let state = self.state.load(Ordering::Acquire);
+            let last_timestamp = state >> sequence_bits;
+            let last_sequence = state & sequence_mask;
+            let current_timestamp = self.current_timestamp();
+
+            // Continue from the last issued millisecond unless the clock has
+            // moved past it. That millisecond can be ahead of the clock after a
+            // backward step, or once it runs out of sequence numbers.
+            let (timestamp, sequence) = if current_timestamp > last_timestamp {
+                (current_timestamp, 0)
+            } else if last_sequence < sequence_mask {
+                (last_timestamp, last_sequence + 1)
+            } else {
+                (last_timestamp + 1, 0)
+            };
+
+            let lead = timestamp - current_timestamp;
+            if lead > max_lead {
+                // Only count the first time this caller runs into exhaustion
+                if current_timestamp == last_timestamp && !wait.exhausted {
+                    wait.exhausted = true;
+                    self.exhaustions.fetch_add(1, Ordering::Relaxed);
+                    SEQUENCE_EXHAUSTED.inc();
+                }
+                // The state is loaded before the clock is read, so a reading
+                // this far behind means the clock itself went backwards
+                if current_timestamp + max_lead < last_timestamp && !self.clock_behind.swap(true, Ordering::Relaxed) {
+                    self.backward_events.fetch_add(1, Ordering::Relaxed);
+                    CLOCK_BACKWARD.inc();
+                    CLOCK_BACKWARD_MS.observe((last_timestamp - current_timestamp) as f64);
+                }
+                // Handle leap seconds / clock drift backwards
+                if current_timestamp < last_timestamp && self.drift_policy == DriftPolicy::FailFast {
+                    return Err(SnowflakeError::ClockBehind {
+                        behind_ms: last_timestamp - current_timestamp,
+                    });
+                }
+                if wait.started.get_or_insert_with(Instant::now).elapsed() > timeout {
+                    CLOCK_DRIFT_TIMEOUTS.inc();
+                    return Err(SnowflakeError::ClockDriftTimeout);
+                }
+                return Ok(None);
+            }
+
+            // Refuse to wrap around rather than issue IDs that sort before older ones
+            if timestamp > self.layout.timestamp_mask() {
+                return Err(SnowflakeError::TimestampOverflow {
+                    epoch_ms: self.epoch_ms,
+                    timestamp_bits: self.layout.timestamp_bits(),
+                });
+            }
+
+            if let Some(hwm) = &self.high_water_mark {
+                hwm.reserve(timestamp, self.epoch_ms)?;
+            }
+
+            let count = max.clamp(1, sequence_mask - sequence + 1);
+            let last_reserved = sequence + count - 1;
+            let new_state = (timestamp << sequence_bits) | last_reserved;
+
+            if self
+                .state
+                .compare_exchange_weak(state, new_state, Ordering::AcqRel, Ordering::Acquire)
+                .is_ok()
+            {
+                CURRENT_SEQUENCE.set(last_reserved as f64);
+                BORROWED_LEAD_MS.set(lead as f64);
+                IDS_GENERATED.inc_by(count);
+                LOCK_WAIT.observe(contended.map_or(0.0, |since| since.elapsed().as_secs_f64()));
+                if self.clock_behind.load(Ordering::Relaxed) {
+                    self.clock_behind.store(false, Ordering::Relaxed);
+                }
+
+                return Ok(Some(IdRange {
+                    layout: self.layout,
+                    timestamp,
+                    worker_id: self.worker_id,
+                    sequence_start: sequence,
+                    count,
+                }));
+            }
+            contended.get_or_insert_with(Instant::now);
+        }
+    }
+
+    /// Splits an ID issued under this generator's layout and epoch into its
+    /// fields.
+    ///
+    /// Rejects IDs with bits set outside the layout and IDs whose timestamp lies
+    /// further in the future than the drift policy allows borrowing, since
+    /// neither can have been issued by a generator.
+    pub fn decode(&self, id: u64) -> Result<DecodedSnowflake, SnowflakeError> {
+        let decoded = self.layout.split(id, self.epoch_ms)?;
+
+        if decoded.timestamp > self.current_timestamp().saturating_add(self.drift_policy.max_lead_ms()) {
+            return Err(SnowflakeError::FutureTimestamp(id));
+        }
+
+        Ok(decoded)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const TIMESTAMP_MASK: u64 = 0x1FFFFFFFFFF;
+    const WORKER_ID_MASK: u64 = 0x3FF;
+    const SEQUENCE_MASK: u64 = 0xFFF;
+    const MAX_WORKER_ID: u64 = 1023;
+
+    fn format_snowflake(worker_id: u64, sequence: u64, timestamp: u64) -> u64 {
+        Layout::DEFAULT.format(worker_id, sequence, timestamp)
+    }
+
+    #[test]
+    fn test_format_snowflake_basic() {
+        let id = format_snowflake(0, 0, 0);
+        assert_eq!(id, 0);
+    }
+
+    #[test]
+    fn test_format_snowflake_with_values() {
+        // Test with specific values
+        let worker_id = 1;
+        let sequence = 1;
+        let timestamp = 1;
+
+        let id = format_snowflake(worker_id, sequence, timestamp);
+
+        // Verify the bits are in correct positions
+        // timestamp (41 bits) << 22 | worker_id (10 bits) << 12 | sequence (12 bits)
+        let expected = (1_u64 << 22) | (1_u64 << 12) | 1_u64;
+        assert_eq!(id, expected);
+    }
+
+    #[test]
+    fn test_format_snowflake_max_values() {
+        let worker_id = MAX_WORKER_ID;
+        let sequence = SEQUENCE_MASK;
+        let timestamp = TIMESTAMP_MASK;
+
+        let id = format_snowflake(worker_id, sequence, timestamp);
+
+        // Extract and verify each component
+        let extracted_sequence = id & SEQUENCE_MASK;
+        let extracted_worker = (id >> 12) & WORKER_ID_MASK;
+        let extracted_timestamp = (id >> 22) & TIMESTAMP_MASK;
+
+        assert_eq!(extracted_sequence, SEQUENCE_MASK);
+        assert_eq!(extracted_worker, MAX_WORKER_ID);
+        assert_eq!(extracted_timestamp, TIMESTAMP_MASK);
+    }
+
+    #[test]
+    fn test_format_snowflake_masks_overflow() {
+        // Values exceeding mask should be truncated
+        let worker_id = 2048; // > 1023
+        let sequence = 8192;  // > 4095
+
+        let id = format_snowflake(worker_id, sequence, 0);
+
+        let extracted_worker = (id >> 12) & WORKER_ID_MASK;
+        let extracted_sequence = id & SEQUENCE_MASK;
+
+        // Should be masked to valid ranges
+        assert_eq!(extracted_worker, worker_id & WORKER_ID_MASK);
+        assert_eq!(extracted_sequence, sequence & SEQUENCE_MASK);
+    }
+
+    #[test]
+    fn test_get_timestamp_returns_positive() {
+        let ts = get_timestamp(UNIX_EPOCH_OFFSET);
+        // After UNIX_EPOCH_OFFSET (Jan 2024), timestamp should be positive
+        assert!(ts > 0, "Timestamp should be positive after epoch offset");
+    }
+
+    #[test]
+    fn test_get_timestamp_increases() {
+        let ts1 = get_timestamp(UNIX_EPOCH_OFFSET);
+        std::thread::sleep(Duration::from_millis(2));
+        let ts2 = get_timestamp(UNIX_EPOCH_OFFSET);
+        assert!(ts2 >= ts1, "Timestamp should not decrease");
+    }
+
+    #[test]
+    fn test_generator_rejects_invalid_worker_id() {
+        assert!(SnowflakeGenerator::new(MAX_WORKER_ID).is_ok());
+        assert!(matches!(
+            SnowflakeGenerator::new(MAX_WORKER_ID + 1),
+            Err(SnowflakeError::InvalidWorkerId { worker_id: 1024, max: 1023 })
+        ));
+    }
+
+    #[test]
+    fn test_generator_custom_layout() {
+        let options = GeneratorOptions {
+            layout: Layout::new(41, 8, 14).unwrap(),
+            ..Default::default()
+        };
+        assert!(SnowflakeGenerator::with_options(256, options.clone()).is_err());
+
+        let generator = SnowflakeGenerator::with_options(255, options.clone()).unwrap();
+        let ids = generator.next_ids(100).unwrap();
+
+        for i in 1..ids.len() {
+            assert!(ids[i] > ids[i-1], "IDs should be monotonically increasing");
+        }
+        for id in ids {
+            assert_eq!(options.layout.split(id, options.epoch_ms).unwrap().worker_id, 255);
+        }
+    }
+
+    #[test]
+    fn test_next_id_single() {
+        let generator = SnowflakeGenerator::new(1).unwrap();
+
+        let result = generator.next_id();
+
+        assert!(result.is_ok());
+        assert_eq!((result.unwrap() >> 12) & WORKER_ID_MASK, 1);
+    }
+
+    #[test]
+    fn test_next_ids_multiple() {
+        let generator = SnowflakeGenerator::new(1).unwrap();
+
+        let result = generator.next_ids(100);
+
+        assert!(result.is_ok());
+        let ids = result.unwrap();
+        assert_eq!(ids.len(), 100);
+    }
+
+    #[test]
+    fn test_next_ids_uniqueness() {
+        let generator = SnowflakeGenerator::new(1).unwrap();
+
+        let result = generator.next_ids(1000);
+
+        assert!(result.is_ok());
+        let ids = result.unwrap();
+
+        // Check all IDs are unique
+        let mut unique_ids: std::collections::HashSet<u64> = std::collections::HashSet::new();
+        for id in &ids {
+            assert!(unique_ids.insert(*id), "Duplicate ID found: {}", id);
+        }
+    }
+
+    #[test]
+    fn test_next_ids_ordering() {
+        let generator = SnowflakeGenerator::new(1).unwrap();
+
+        let result = generator.next_ids(100);
+
+        assert!(result.is_ok());
+        let ids = result.unwrap();
+
+        // IDs should be monotonically increasing
+        for i in 1..ids.len() {
+            assert!(ids[i] > ids[i-1], "IDs should be monotonically increasing");
+        }
+    }
+
+    #[test]
+    fn test_next_ids_ordering_across_calls() {
+        let generator = SnowflakeGenerator::new(1).unwrap();
+
+        let first = generator.next_id().unwrap();
+        let second = generator.next_id().unwrap();
+
+        assert!(second > first, "IDs should increase across calls");
+    }
+
+    #[test]
+    fn test_next_ids_worker_id_embedded() {
+        let worker_id = 42;
+        let generator = SnowflakeGenerator::new(worker_id).unwrap();
+
+        let result = generator.next_ids(10);
+
+        assert!(result.is_ok());
+        for id in result.unwrap() {
+            let extracted_worker = (id >> 12) & WORKER_ID_MASK;
+            assert_eq!(extracted_worker, worker_id, "Worker ID should be embedded in snowflake");
+        }
+    }
+
+    #[test]
+    fn test_snowflake_error_display() {
+        assert_eq!(
+            SnowflakeError::ClockDriftTimeout.to_string(),
+            "Clock drift timeout exceeded"
+        );
+        assert_eq!(
+            SnowflakeError::ClockBehind { behind_ms: 12 }.to_string(),
+            "Clock is 12 ms behind the last issued timestamp"
+        );
+        assert_eq!(
+            SnowflakeError::InvalidWorkerId { worker_id: 1024, max: 1023 }.to_string(),
+            "Worker ID must be between 0 and 1023, got: 1024"
+        );
+        assert_eq!(
+            SnowflakeError::InvalidLayout { timestamp_bits: 41, worker_id_bits: 10, sequence_bits: 10 }.to_string(),
+            "Bit layout 41/10/10 is invalid: each field needs at least 1 bit and the widths must sum to 63 or 64"
+        );
+        assert_eq!(
+            SnowflakeError::InvalidId(1).to_string(),
+            "ID 1 does not fit the snowflake layout"
+        );
+        assert_eq!(
+            SnowflakeError::FutureTimestamp(1).to_string(),
+            "ID 1 has a timestamp in the future"
+        );
+        assert_eq!(
+            SnowflakeError::EpochInFuture(UNIX_EPOCH_OFFSET).to_string(),
+            "Epoch 1705065354064 (2024-01-12T13:15:54.064Z) is in the future"
+        );
+        assert_eq!(
+            SnowflakeError::TimestampOverflow { epoch_ms: 0, timestamp_bits: 41 }.to_string(),
+            "Current time no longer fits in the 41-bit timestamp field for epoch 0 (1970-01-01T00:00:00.000Z)"
+        );
+    }
+
+    fn high_water_mark_path(name: &str) -> PathBuf {
+        let path = std::env::temp_dir().join(format!("id-generator-lib-{}-{}", std::process::id(), name));
+        let _ = std::fs::remove_file(&path);
+        path
+    }
+
+    fn with_high_water_mark(path: &std::path::Path) -> Result<SnowflakeGenerator, SnowflakeError> {
+        let options = GeneratorOptions {
+            high_water_mark: Some(HighWaterMarkOptions::new(path)),
+            ..Default::default()
+        };
+        SnowflakeGenerator::with_options(1, options)
+    }
+
+    #[test]
+    fn test_high_water_mark_covers_issued_ids() {
+        let path = high_water_mark_path("covers");
+        let generator = with_high_water_mark(&path).unwrap();
+        let id = generator.next_id().unwrap();
+
+        let mark: u64 = std::fs::read_to_string(&path).unwrap().trim().parse().unwrap();
+        let decoded = generator.decode(id).unwrap();
+        assert!(mark >= decoded.unix_ms + high_water_mark::DEFAULT_WINDOW_MS);
+
+        generator.flush_high_water_mark().unwrap();
+        let mark: u64 = std::fs::read_to_string(&path).unwrap().trim().parse().unwrap();
+        assert_eq!(mark, decoded.unix_ms);
+        std::fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn test_high_water_mark_refuses_ids_until_clock_passes() {
+        let path = high_water_mark_path("refuses");
+
+        // A mark well ahead of the clock, as after a backward clock step
+        let far_mark = time::unix_millis() + 10_000;
+        std::fs::write(&path, far_mark.to_string()).unwrap();
+        let generator = with_high_water_mark(&path).unwrap();
+        assert!(matches!(generator.next_id(), Err(SnowflakeError::ClockDriftTimeout)));
+
+        // A mark just ahead of the clock is waited out
+        let near_mark = time::unix_millis() + 20;
+        std::fs::write(&path, near_mark.to_string()).unwrap();
+        let generator = with_high_water_mark(&path).unwrap();
+        let id = generator.next_id().unwrap();
+        assert!(generator.decode(id).unwrap().unix_ms > near_mark);
+        std::fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn test_decode_snowflake_roundtrip() {
+        let generator = SnowflakeGenerator::new(1).unwrap();
+        let id = format_snowflake(42, 7, 1000);
+
+        let decoded = generator.decode(id).unwrap();
+
+        assert_eq!(decoded.timestamp, 1000);
+        assert_eq!(decoded.worker_id, 42);
+        assert_eq!(decoded.sequence, 7);
+        assert_eq!(decoded.unix_ms, UNIX_EPOCH_OFFSET + 1000);
+        assert_eq!(decoded.rfc3339(), "2024-01-12T13:15:55.064Z");
+    }
+
+    #[test]
+    fn test_decode_snowflake_generated_id() {
+        let generator = SnowflakeGenerator::new(5).unwrap();
+        let id = generator.next_id().unwrap();
+
+        let decoded = generator.decode(id).unwrap();
+
+        assert_eq!(decoded.worker_id, 5);
+        assert_eq!(format_snowflake(decoded.worker_id, decoded.sequence, decoded.timestamp), id);
+    }
+
+    #[test]
+    fn test_decode_snowflake_rejects_top_bit() {
+        let generator = SnowflakeGenerator::new(1).unwrap();
+        let result = generator.decode(1 << 63);
+        assert!(matches!(result, Err(SnowflakeError::InvalidId(_))));
+    }
+
+    #[test]
+    fn test_decode_snowflake_rejects_future_timestamp() {
+        let generator = SnowflakeGenerator::new(1).unwrap();
+        let id = format_snowflake(1, 0, TIMESTAMP_MASK);
+        let result = generator.decode(id);
+        assert!(matches!(result, Err(SnowflakeError::FutureTimestamp(_))));
+    }
+
+    #[test]
+    fn test_custom_epoch() {
+        let twitter_epoch = 1288834974657;
+        let options = GeneratorOptions { epoch_ms: twitter_epoch, ..Default::default() };
+        let generator = SnowflakeGenerator::with_options(1, options).unwrap();
+
+        let id = generator.next_id().unwrap();
+        let decoded = generator.decode(id).unwrap();
+
+        assert_eq!(decoded.unix_ms, decoded.timestamp + twitter_epoch);
+        assert!(decoded.timestamp > get_timestamp(UNIX_EPOCH_OFFSET), "Older epoch should give larger timestamps");
+    }
+
+    #[test]
+    fn test_epoch_in_future_rejected() {
+        let options = GeneratorOptions { epoch_ms: time::unix_millis() + 60_000, ..Default::default() };
+        assert!(matches!(
+            SnowflakeGenerator::with_options(1, options),
+            Err(SnowflakeError::EpochInFuture(_))
+        ));
+    }
+
+    #[test]
+    fn test_epoch_overflowing_timestamp_rejected() {
+        // 40 bits of milliseconds is roughly 35 years, so a 1970 epoch has already overflowed
+        let options = GeneratorOptions { epoch_ms: 0, layout: Layout::new(40, 11, 12).unwrap(), ..Default::default() };
+        assert!(matches!(
+            SnowflakeGenerator::with_options(1, options),
+            Err(SnowflakeError::TimestampOverflow { epoch_ms: 0, timestamp_bits: 40 })
+        ));
+
+        // ...but fits in 42 bits
+        let options = GeneratorOptions { epoch_ms: 0, layout: Layout::new(42, 10, 12).unwrap(), ..Default::default() };
+        assert!(SnowflakeGenerator::with_options(1, options).is_ok());
+    }
+
+    #[test]
+    fn test_sequence_overflow_handling() {
+        let generator = SnowflakeGenerator::new(1).unwrap();
+        // Start at max sequence for the current millisecond
+        let now = get_timestamp(UNIX_EPOCH_OFFSET);
+        generator.state.store((now << 12) | SEQUENCE_MASK, Ordering::Relaxed);
+
+        // This should trigger sequence overflow handling
+        let result = generator.next_ids(2);
+
+        assert!(result.is_ok());
+        let ids = result.unwrap();
+        assert_eq!(ids.len(), 2);
+
+        // Both should be valid and unique
+        assert_ne!(ids[0], ids[1]);
+        assert!(ids[0] > format_snowflake(1, SEQUENCE_MASK, now), "Should move past the exhausted millisecond");
+    }
+
+    fn manual_generator(options: GeneratorOptions) -> (Arc<clock::ManualClock>, SnowflakeGenerator) {
+        let clock = Arc::new(clock::ManualClock::new(options.epoch_ms + 1_000_000));
+        let generator = SnowflakeGenerator::with_clock(1, options, clock.clone()).unwrap();
+        (clock, generator)
+    }
+
+    #[test]
+    fn test_manual_clock_exhaustion_times_out() {
+        let (clock, generator) = manual_generator(GeneratorOptions::default());
+
+        // The whole millisecond is available, but a frozen clock never frees more
+        let ids = generator.next_ids(SEQUENCE_MASK + 1).unwrap();
+        assert_eq!(ids.len() as u64, SEQUENCE_MASK + 1);
+        assert!(matches!(generator.next_id(), Err(SnowflakeError::ClockDriftTimeout)));
+
+        clock.advance(1);
+        let id = generator.next_id().unwrap();
+        assert_eq!(generator.decode(id).unwrap().sequence, 0);
+    }
+
+    #[test]
+    fn test_reservations_are_timed() {
+        let (_, generator) = manual_generator(GeneratorOptions::default());
+        let (generated, slept) = (GENERATION_DURATION.get_sample_count(), SLEEP_DURATION.get_sample_count());
+
+        generator.next_id().unwrap();
+        assert!(GENERATION_DURATION.get_sample_count() > generated);
+
+        // Waiting for a frozen clock is timed even when it gives up
+        generator.next_ids(SEQUENCE_MASK).unwrap();
+        generator.next_id().unwrap_err();
+        assert!(SLEEP_DURATION.get_sample_count() > slept);
+    }
+
+    #[test]
+    fn test_manual_clock_backward_step() {
+        let (clock, generator) = manual_generator(GeneratorOptions::default());
+        let before = generator.next_id().unwrap();
+
+        let timeouts = CLOCK_DRIFT_TIMEOUTS.get();
+        clock.rewind(5);
+        assert!(matches!(generator.next_id(), Err(SnowflakeError::ClockDriftTimeout)));
+        assert!(CLOCK_DRIFT_TIMEOUTS.get() > timeouts);
+        assert_eq!(generator.clock_backward_events(), 1);
+
+        // Catching up to the last timestamp resumes the sequence
+        clock.advance(5);
+        let after = generator.next_id().unwrap();
+        assert_eq!(after, before + 1);
+    }
+
+    #[test]
+    fn test_backward_step_counted_once() {
+        let (clock, generator) = manual_generator(GeneratorOptions {
+            drift_policy: DriftPolicy::FailFast,
+            ..Default::default()
+        });
+        let (events, timeouts) = (CLOCK_BACKWARD.get(), CLOCK_DRIFT_TIMEOUTS.get());
+        generator.next_id().unwrap();
+
+        clock.rewind(20);
+        generator.next_id().unwrap_err();
+        generator.next_id().unwrap_err();
+        assert_eq!(generator.clock_backward_events(), 1, "Still the same step");
+        assert!(CLOCK_BACKWARD.get() > events);
+        assert_eq!(CLOCK_DRIFT_TIMEOUTS.get(), timeouts, "Failing fast never times out");
+
+        // Once IDs are issued again, the next step is a new event
+        clock.advance(21);
+        generator.next_id().unwrap();
+        clock.rewind(5);
+        generator.next_id().unwrap_err();
+        assert_eq!(generator.clock_backward_events(), 2);
+    }
+
+    #[test]
+    fn test_borrowed_lead_is_not_a_backward_step() {
+        let (clock, generator) = manual_generator(GeneratorOptions {
+            drift_policy: DriftPolicy::Borrow { max_lead_ms: 5 },
+            ..Default::default()
+        });
+        generator.next_id().unwrap();
+
+        clock.rewind(3);
+        generator.next_id().unwrap();
+        assert_eq!(generator.clock_backward_events(), 0);
+
+        clock.rewind(10);
+        generator.next_id().unwrap_err();
+        assert_eq!(generator.clock_backward_events(), 1);
+    }
+
+    #[test]
+    fn test_manual_clock_waits_out_short_backward_step() {
+        let (clock, generator) = manual_generator(GeneratorOptions::default());
+        let before = generator.next_id().unwrap();
+        clock.rewind(1);
+
+        let ticker = {
+            let clock = clock.clone();
+            std::thread::spawn(move || {
+                std::thread::sleep(Duration::from_millis(20));
+                clock.advance(2);
+            })
+        };
+        let after = generator.next_id().unwrap();
+        ticker.join().unwrap();
+
+        assert!(after > before);
+        assert_eq!(generator.decode(after).unwrap().timestamp, generator.decode(before).unwrap().timestamp + 1);
+    }
+
+    #[test]
+    fn test_drift_policy_fail_fast() {
+        let options = GeneratorOptions { drift_policy: DriftPolicy::FailFast, ..Default::default() };
+        let (clock, generator) = manual_generator(options);
+        generator.next_id().unwrap();
+
+        clock.rewind(5);
+        let started = Instant::now();
+        assert!(matches!(generator.next_id(), Err(SnowflakeError::ClockBehind { behind_ms: 5 })));
+        assert!(started.elapsed() < Duration::from_millis(CLOCK_DRIFT_TIMEOUT_MS));
+    }
+
+    #[test]
+    fn test_drift_policy_borrow() {
+        let options = GeneratorOptions {
+            drift_policy: DriftPolicy::Borrow { max_lead_ms: 2 },
+            ..Default::default()
+        };
+        let (clock, generator) = manual_generator(options);
+        let before = generator.next_id().unwrap();
+        let last_timestamp = generator.last_timestamp();
+
+        // Carries on from the last timestamp after a backward step
+        clock.rewind(1);
+        let after = generator.next_id().unwrap();
+        assert_eq!(after, before + 1);
+        assert_eq!(generator.lead_ms(), 1);
+        assert!(generator.decode(after).is_ok(), "Borrowed IDs are within the decodable lead");
+
+        // Moves into the next millisecond when the sequence runs out, up to the cap
+        let ids = generator.next_ids(SEQUENCE_MASK + 1).unwrap();
+        assert!(ids.windows(2).all(|pair| pair[0] < pair[1]));
+        assert_eq!(generator.last_timestamp(), last_timestamp + 1);
+        assert_eq!(generator.lead_ms(), 2);
+
+        // Beyond the cap it waits, then times out
+        generator.next_ids(SEQUENCE_MASK + 1).unwrap_err();
+        assert!(matches!(generator.next_id(), Err(SnowflakeError::ClockDriftTimeout)));
+    }
+
+    #[test]
+    fn test_drift_timeout_is_configurable() {
+        let options = GeneratorOptions { drift_timeout_ms: 5, ..Default::default() };
+        let (clock, generator) = manual_generator(options);
+        generator.next_id().unwrap();
+
+        clock.rewind(5);
+        let started = Instant::now();
+        assert!(matches!(generator.next_id(), Err(SnowflakeError::ClockDriftTimeout)));
+        assert!(started.elapsed() < Duration::from_millis(CLOCK_DRIFT_TIMEOUT_MS));
+    }
+
+    #[test]
+    fn test_manual_clock_timestamp_overflow() {
+        let layout = Layout::new(40, 11, 12).unwrap();
+        let options = GeneratorOptions { epoch_ms: 0, layout, ..Default::default() };
+        let clock = Arc::new(clock::ManualClock::new(layout.timestamp_mask()));
+        let generator = SnowflakeGenerator::with_clock(1, options.clone(), clock.clone()).unwrap();
+        assert!(generator.next_id().is_ok());
+
+        clock.advance(1);
+        assert!(matches!(generator.next_id(), Err(SnowflakeError::TimestampOverflow { .. })));
+        assert!(matches!(
+            SnowflakeGenerator::with_clock(1, options, clock),
+            Err(SnowflakeError::TimestampOverflow { .. })
+        ));
+    }
+
+    #[test]
+    fn test_manual_clock_decode_future_timestamp() {
+        let (clock, generator) = manual_generator(GeneratorOptions::default());
+        let id = generator.next_id().unwrap();
+
+        clock.rewind(1);
+        assert!(matches!(generator.decode(id), Err(SnowflakeError::FutureTimestamp(_))));
+    }
+
+    #[test]
+    fn test_next_ids_spans_milliseconds() {
+        let generator = SnowflakeGenerator::new(1).unwrap();
+
+        let ids = generator.next_ids(10_000).unwrap();
+
+        assert_eq!(ids.len(), 10_000);
+        for i in 1..ids.len() {
+            assert!(ids[i] > ids[i-1], "IDs should be monotonically increasing");
+        }
+    }
+
+    #[test]
+    fn test_next_range() {
+        let generator = SnowflakeGenerator::new(7).unwrap();
+
+        let first = generator.next_range(10).unwrap();
+        let second = generator.next_range(10).unwrap();
+
+        assert!(first.count >= 1 && first.count <= 10);
+        assert_eq!(first.worker_id, 7);
+        assert_eq!(first.ids().count() as u64, first.count);
+        assert_eq!(first.ids().next(), Some(first.first_id()));
+        assert_eq!(first.ids().last(), Some(first.last_id()));
+        assert_eq!(first.last_id() - first.first_id(), first.count - 1);
+        assert!(second.first_id() > first.last_id());
+    }
+
+    #[test]
+    fn test_next_range_capped_by_sequence_space() {
+        let generator = SnowflakeGenerator::new(1).unwrap();
+
+        let range = generator.next_range(SEQUENCE_MASK + 100).unwrap();
+
+        assert!(range.count <= SEQUENCE_MASK + 1);
+        assert_eq!(range.sequence_end(), range.sequence_start + range.count - 1);
+        assert!(range.sequence_end() <= SEQUENCE_MASK);
+    }
+
+    #[tokio::test]
+    async fn test_next_ids_async() {
+        let generator = SnowflakeGenerator::new(1).unwrap();
+
+        let single = generator.next_id_async().await.unwrap();
+        let ids = generator.next_ids_async(10_000).await.unwrap();
+
+        assert_eq!(ids.len(), 10_000);
+        assert!(ids[0] > single);
+        for i in 1..ids.len() {
+            assert!(ids[i] > ids[i-1], "IDs should be monotonically increasing");
+        }
+    }
+
+    #[tokio::test]
+    async fn test_sequence_exhaustion_does_not_block_runtime() {
+        let generator = SnowflakeGenerator::new(1).unwrap();
+        let now = get_timestamp(UNIX_EPOCH_OFFSET);
+        generator.state.store((now << 12) | SEQUENCE_MASK, Ordering::Relaxed);
+
+        // On a current-thread runtime the ticker only runs if the generator yields
+        let ticks = std::sync::atomic::AtomicU64::new(0);
+        let ticker = async {
+            loop {
+                ticks.fetch_add(1, Ordering::Relaxed);
+                tokio::task::yield_now().await;
+            }
+        };
+
+        tokio::select! {
+            result = generator.next_id_async() => assert!(result.is_ok()),
+            _ = ticker => unreachable!(),
+        }
+        assert!(ticks.load(Ordering::Relaxed) > 0, "Other tasks should run while waiting");
+    }
+
+    #[test]
+    fn test_concurrent_generation_unique() {
+        let generator = std::sync::Arc::new(SnowflakeGenerator::new(1).unwrap());
+
+        let handles: Vec<_> = (0..8)
+            .map(|i| {
+                let generator = generator.clone();
+                std::thread::spawn(move || {
+                    // Mix single IDs with batches so reservations interleave
+                    let mut ids = Vec::new();
+                    for _ in 0..500 {
+                        if i % 2 == 0 {
+                            ids.push(generator.next_id().unwrap());
+                        } else {
+                            let batch = generator.next_ids(20).unwrap();
+                            assert!(batch.windows(2).all(|w| w[1] > w[0]));
+                            ids.extend(batch);
+                        }
+                    }
+                    ids
+                })
+            })
+            .collect();
+
+        let mut unique_ids = std::collections::HashSet::new();
+        for handle in handles {
+            let ids = handle.join().unwrap();
+            // Each thread must observe its own IDs increasing
+            assert!(ids.windows(2).all(|w| w[1] > w[0]));
+            for id in ids {
+                assert!(unique_ids.insert(id), "Duplicate ID found: {}", id);
+            }
+        }
+        assert_eq!(unique_ids.len(), 4 * 500 + 4 * 500 * 20);
+    }
+}
@@ -1,47 +1,52 @@
-use actix_web::{web, App, HttpServer, Result, HttpResponse, get, http::StatusCode};
-use serde::{Deserialize, Serialize};
-use std::time::{SystemTime, UNIX_EPOCH, Duration, Instant};
+use actix_web::{web, App, HttpMessage, HttpRequest, HttpServer, Result, HttpResponse, get, http::StatusCode};
+use actix_web::body::MessageBody;
+use actix_web::dev::{Server, ServiceRequest, ServiceResponse};
+use actix_web::http::header::Accept;
+use actix_web::middleware::{from_fn, Next};
+use actix_web::mime;
+use futures_util::{stream, Stream, StreamExt};
+use serde::{Deserialize, Deserializer, Serialize};
 use std::env::var;
-use std::sync::Mutex;
-use prometheus::{Gauge, IntCounter, Encoder, TextEncoder};
-use lazy_static::lazy_static;
-
-lazy_static! {
-    static ref IDS_GENERATED: IntCounter = IntCounter::new(
-        "id_generator_ids_generated_total", 
-        "Total IDs generated"
-    ).unwrap();
-    
-    static ref SEQUENCE_EXHAUSTED: IntCounter = IntCounter::new(
-        "id_generator_sequence_exhausted_total",
-        "Times sequence was exhausted within a millisecond"
-    ).unwrap();
-    
-    static ref WORKER_ID: Gauge = Gauge::new(
-        "id_generator_worker_id",
-        "Worker ID of this instance"
-    ).unwrap();
-
-    static ref CURRENT_SEQUENCE: Gauge = Gauge::new(
-        "id_generator_current_sequence",
-        "Current sequence number this ms"
-    ).unwrap();
-    
-    static ref MAX_SEQUENCE_PER_MS: Gauge = Gauge::new(
-        "id_generator_max_sequence_per_ms",
-        "Maximum sequence number per millisecond"
-    ).unwrap();
-}
+use std::fmt::Write;
+use std::net::SocketAddr;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use prometheus::{Encoder, TextEncoder};
+use id_generator::{
+    Clock, DriftPolicy, GeneratorOptions, HighWaterMarkOptions, IdRange, Layout, SnowflakeError, SnowflakeGenerator,
+    SystemClock, UNIX_EPOCH_OFFSET,
+};
+use id_generator::clock::MonotonicClock;
+use id_generator::encoding::Encoding;
+use id_generator::time::parse_rfc3339;
+use id_generator::metrics::{BATCH_SIZE, HTTP_CLIENT_REQUESTS, HTTP_REQUESTS, LAST_TIMESTAMP_OFFSET_MS, WORKER_ID, MAX_SEQUENCE_PER_MS};
+
+mod auth;
+mod cli;
+mod config;
+mod grpc;
+mod rate_limit;
+mod readiness;
+mod tls;
+
+use clap::Parser;
+use cli::{Cli, Command, ServeArgs};
+use auth::{ApiKey, ApiKeys};
+use config::{
+    AuthConfig, ClientAuth, RateLimitConfig, ClockConfig, ClockMode, Config, DriftPolicyName, GeneratorConfig, HighWaterMarkConfig, LimitsConfig,
+    Listener, MetricsConfig, ReadinessConfig, ServerConfig, TlsConfig, WorkerConfig, DEFAULT_TLS_RELOAD_INTERVAL_SECS,
+};
+use rate_limit::{Limit, RateLimiter};
+use readiness::{Readiness, ReadinessReason};
+use tls::{ClientIdentity, TlsCertificates};
 
 // Constants
-const UNIX_EPOCH_OFFSET: u64 = 1705065354064;
-const TIMESTAMP_MASK: u64 = 0x1FFFFFFFFFF;
-const WORKER_ID_MASK: u64 = 0x3FF;
-const SEQUENCE_MASK: u64 = 0xFFF;
-const MAX_WORKER_ID: u64 = 1023;
-
 const MAX_IDS_PER_REQUEST: u64 = 4_096_000;
-const CLOCK_DRIFT_TIMEOUT_MS: u64 = 100;
+const DEFAULT_HTTP_PORT: u16 = 8080;
+const DEFAULT_GRPC_PORT: u16 = 50051;
+const DEFAULT_MAX_BORROW_MS: u64 = 1000;
+/// Largest integer a JavaScript number represents exactly (2^53 - 1).
+const JS_MAX_SAFE_INTEGER: u64 = (1 << 53) - 1;
 
 // Error response structure for consistent JSON errors
 #[derive(Serialize, Deserialize)]
@@ -55,90 +60,30 @@ impl ErrorResponse {
     }
 }
 
-// Custom error type for snowflake generation
-#[derive(Debug)]
-enum SnowflakeError {
-    MutexPoisoned,
-    ClockDriftTimeout,
-}
-
-impl std::fmt::Display for SnowflakeError {
-    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-        match self {
-            SnowflakeError::MutexPoisoned => write!(f, "Internal state error"),
-            SnowflakeError::ClockDriftTimeout => write!(f, "Clock drift timeout exceeded"),
-        }
-    }
-}
-
 struct AppState {
-    worker_id: u64,
-    sequence: Mutex<u64>,
-    timestamp: Mutex<u64>,
-}
-
-fn get_timestamp() -> u64 {
-    let start = SystemTime::now();
-    let since_the_epoch = start.duration_since(UNIX_EPOCH).unwrap_or_default();
-    TryInto::<u64>::try_into(since_the_epoch.as_millis()).unwrap_or(0).saturating_sub(UNIX_EPOCH_OFFSET)
-}
-
-fn format_snowflake(worker_id: u64, sequence: u64, timestamp: u64) -> u64 {
-    ((timestamp & TIMESTAMP_MASK) << 22) | ((worker_id & WORKER_ID_MASK) << 12) | (sequence & SEQUENCE_MASK)
+    generator: SnowflakeGenerator,
+    readiness: Readiness,
+    /// Largest count accepted by `/ids/{count}` and `StreamIds`.
+    max_ids_per_request: u64,
+    /// Keys required by the HTTP API; `None` leaves it open.
+    api_keys: Option<Arc<ApiKeys>>,
+    rate_limiter: RateLimiter,
 }
 
-fn generate_snowflakes(
-    worker_id: u64,
-    sequence_mutex: &Mutex<u64>,
-    timestamp_mutex: &Mutex<u64>,
-    count: u64,
-) -> std::result::Result<Vec<String>, SnowflakeError> {
-    let mut sequence = sequence_mutex.lock().map_err(|_| SnowflakeError::MutexPoisoned)?;
-    let mut last_timestamp = timestamp_mutex.lock().map_err(|_| SnowflakeError::MutexPoisoned)?;
-    let mut results = Vec::with_capacity(count as usize);
-    let timeout = Duration::from_millis(CLOCK_DRIFT_TIMEOUT_MS);
-
-    for _ in 0..count {
-        let mut current_timestamp = get_timestamp();
-
-        // Handle leap seconds / clock drift backwards - wait until time catches up
-        if current_timestamp < *last_timestamp {
-            let wait_start = Instant::now();
-            while current_timestamp < *last_timestamp {
-                if wait_start.elapsed() > timeout {
-                    return Err(SnowflakeError::ClockDriftTimeout);
-                }
-                std::thread::sleep(Duration::from_micros(100));
-                current_timestamp = get_timestamp();
-            }
-        }
+impl AppState {
+    fn new(worker_id: u64, options: GeneratorOptions, clock: Arc<dyn Clock>) -> std::result::Result<Self, SnowflakeError> {
+        Ok(Self::with_generator(SnowflakeGenerator::with_clock(worker_id, options, clock)?))
+    }
 
-        if current_timestamp == *last_timestamp {
-            *sequence += 1;
-            if *sequence > SEQUENCE_MASK {
-                SEQUENCE_EXHAUSTED.inc();
-                let wait_start = Instant::now();
-                while current_timestamp == *last_timestamp {
-                    if wait_start.elapsed() > timeout {
-                        return Err(SnowflakeError::ClockDriftTimeout);
-                    }
-                    std::thread::sleep(Duration::from_micros(100));
-                    current_timestamp = get_timestamp();
-                }
-                *sequence = 0;
-            }
-        } else {
-            *sequence = 0;
+    fn with_generator(generator: SnowflakeGenerator) -> Self {
+        Self {
+            readiness: Readiness::new(&generator),
+            generator,
+            max_ids_per_request: MAX_IDS_PER_REQUEST,
+            api_keys: None,
+            rate_limiter: RateLimiter::default(),
         }
-        *last_timestamp = current_timestamp;
-        
-        CURRENT_SEQUENCE.set(*sequence as f64);
-        IDS_GENERATED.inc();
-        
-        results.push(format_snowflake(worker_id, *sequence, *last_timestamp).to_string());
     }
-
-    Ok(results)
 }
 
 #[derive(Serialize, Deserialize)]
@@ -147,8 +92,18 @@ struct Id {
 }
 
 #[derive(Serialize, Deserialize)]
-struct Bulk {
-    ids: Vec<String>,
+struct NumericId {
+    id: u64,
+}
+
+#[derive(Serialize, Deserialize)]
+struct DecodeResponse {
+    id: String,
+    timestamp: u64,
+    unix_ms: u64,
+    time: String,
+    worker_id: u64,
+    sequence: u64,
 }
 
 #[derive(Serialize, Deserialize)]
@@ -161,12 +116,122 @@ struct HealthResponse {
 async fn health(data: web::Data<AppState>) -> Result<HttpResponse> {
     Ok(HttpResponse::Ok().json(HealthResponse {
         status: "healthy".to_string(),
-        worker_id: data.worker_id,
+        worker_id: data.generator.worker_id(),
     }))
 }
 
-#[get("/metrics")]
-async fn metrics() -> Result<HttpResponse> {
+#[derive(Serialize, Deserialize)]
+struct ReadyResponse {
+    status: String,
+    worker_id: u64,
+    reasons: Vec<ReadinessReason>,
+}
+
+/// Unlike `/health`, fails with `503` while the generator can't issue IDs.
+#[get("/ready")]
+async fn ready(data: web::Data<AppState>) -> Result<HttpResponse> {
+    let reasons = data.readiness.check(&data.generator);
+    let (mut response, status) = if reasons.is_empty() {
+        (HttpResponse::Ok(), "ready")
+    } else {
+        (HttpResponse::ServiceUnavailable(), "not_ready")
+    };
+
+    Ok(response.json(ReadyResponse {
+        status: status.to_string(),
+        worker_id: data.generator.worker_id(),
+        reasons,
+    }))
+}
+
+fn api_routes(cfg: &mut web::ServiceConfig) {
+    cfg.app_data(query_config())
+        .service(snowflake)
+        .service(snowflakes)
+        .service(decode);
+}
+
+fn admin_routes(cfg: &mut web::ServiceConfig, metrics_config: &MetricsConfig) {
+    cfg.service(health).service(ready);
+    if metrics_config.enabled {
+        cfg.route(&metrics_config.path, web::get().to(metrics));
+    }
+}
+
+/// Builds an HTTP server with the routes added by `configure`, bound to every
+/// listener. With `tls`, TCP listeners serve HTTPS; Unix sockets stay plain.
+fn http_server(
+    data: web::Data<AppState>,
+    configure: impl Fn(&mut web::ServiceConfig) + Clone + Send + 'static,
+    listeners: &[Listener],
+    workers: usize,
+    tls: Option<&rustls::ServerConfig>,
+) -> std::io::Result<Server> {
+    let mut server = HttpServer::new(move || {
+        App::new()
+            .wrap(from_fn(auth::authenticate))
+            .wrap(from_fn(count_requests))
+            .app_data(data.clone())
+            .configure(configure.clone())
+    })
+    .on_connect(tls::on_connect);
+
+    for listener in listeners {
+        server = match (listener, tls) {
+            (Listener::Tcp(addr), Some(tls)) => server.bind_rustls_0_23(addr, tls.clone())?,
+            (Listener::Tcp(addr), None) => server.bind(addr)?,
+            (Listener::Unix(path), _) => {
+                remove_stale_socket(path)?;
+                server.bind_uds(path)?
+            }
+        };
+    }
+    Ok(server.workers(workers).run())
+}
+
+/// Removes a socket left behind by a previous run, which would otherwise make
+/// binding fail. Anything that isn't a socket is left for the bind to report.
+fn remove_stale_socket(path: &Path) -> std::io::Result<()> {
+    use std::os::unix::fs::FileTypeExt;
+
+    match std::fs::symlink_metadata(path) {
+        Ok(metadata) if metadata.file_type().is_socket() => std::fs::remove_file(path),
+        _ => Ok(()),
+    }
+}
+
+fn join_listeners(listeners: &[Listener]) -> String {
+    listeners.iter().map(Listener::to_string).collect::<Vec<_>>().join(",")
+}
+
+/// Counts every response by route pattern and status, so `/ids/{count}` is a
+/// single series however many counts are requested. Mutual TLS requests are
+/// also counted by client certificate subject.
+async fn count_requests(
+    req: ServiceRequest,
+    next: Next<impl MessageBody>,
+) -> Result<ServiceResponse<impl MessageBody>> {
+    if let Some(identity) = req.conn_data::<ClientIdentity>() {
+        HTTP_CLIENT_REQUESTS.with_label_values(&[&identity.subject]).inc();
+    }
+    let result = next.call(req).await;
+    let (endpoint, status) = match &result {
+        Ok(res) => (res.request().match_pattern(), res.status()),
+        Err(e) => (None, e.as_response_error().status_code()),
+    };
+    HTTP_REQUESTS
+        .with_label_values(&[endpoint.as_deref().unwrap_or("unmatched"), status.as_str()])
+        .inc();
+    result
+}
+
+async fn metrics(data: web::Data<AppState>) -> Result<HttpResponse> {
+    // Compared against the system clock even in monotonic mode, so a node
+    // whose wall clock is off shows up here
+    let generator = &data.generator;
+    let last_unix_ms = generator.epoch_ms() + generator.last_timestamp();
+    LAST_TIMESTAMP_OFFSET_MS.set(last_unix_ms as f64 - id_generator::time::unix_millis() as f64);
+
     let encoder = TextEncoder::new();
     let metric_families = prometheus::gather();
     let mut buffer = Vec::new();
@@ -184,287 +249,1139 @@ async fn metrics() -> Result<HttpResponse> {
     }
 }
 
-#[get("/id")]
-async fn snowflake(data: web::Data<AppState>) -> Result<HttpResponse> {
-    match generate_snowflakes(data.worker_id, &data.sequence, &data.timestamp, 1) {
-        Ok(ids) => {
-            // Safe: we always request 1 ID so the vector is never empty
-            let id = ids.into_iter().next().expect("requested 1 ID");
-            Ok(HttpResponse::Ok().json(Id { id }))
-        }
-        Err(e) => Ok(HttpResponse::build(StatusCode::INTERNAL_SERVER_ERROR)
-            .json(ErrorResponse::new(e.to_string()))),
-    }
+/// Deserializes an optional `format` query parameter by its `Encoding` name.
+fn deserialize_encoding<'de, D: Deserializer<'de>>(deserializer: D) -> std::result::Result<Option<Encoding>, D::Error> {
+    Option::<String>::deserialize(deserializer)?
+        .map(|s| s.parse().map_err(serde::de::Error::custom))
+        .transpose()
 }
 
-#[get("/ids/{count}")]
-async fn snowflakes(data: web::Data<AppState>, path: web::Path<u64>) -> Result<HttpResponse> {
-    let count = path.into_inner();
+#[derive(Deserialize)]
+struct FormatQuery {
+    #[serde(default, deserialize_with = "deserialize_encoding")]
+    format: Option<Encoding>,
+}
 
-    if count == 0 {
-        return Ok(HttpResponse::BadRequest()
-            .json(ErrorResponse::new("Count must be at least 1")));
-    }
+/// Value of the `numeric` query parameter.
+#[derive(Deserialize, Clone, Copy, Default, PartialEq, Eq)]
+enum NumericMode {
+    #[default]
+    #[serde(rename = "false")]
+    Off,
+    /// IDs as JSON numbers.
+    #[serde(rename = "true")]
+    On,
+    /// IDs as JSON numbers, refusing any that JavaScript can't represent exactly.
+    #[serde(rename = "js_safe")]
+    JsSafe,
+}
 
-    if count > MAX_IDS_PER_REQUEST {
-        return Ok(HttpResponse::BadRequest()
-            .json(ErrorResponse::new(format!(
-                "Count must be less than or equal to {}",
-                MAX_IDS_PER_REQUEST
-            ))));
-    }
+/// How individual IDs are written into JSON responses.
+#[derive(Clone, Copy)]
+enum IdOutput {
+    String(Encoding),
+    Number,
+    JsSafeNumber,
+}
 
-    match generate_snowflakes(data.worker_id, &data.sequence, &data.timestamp, count) {
-        Ok(ids) => Ok(HttpResponse::Ok().json(Bulk { ids })),
-        Err(e) => Ok(HttpResponse::build(StatusCode::INTERNAL_SERVER_ERROR)
-            .json(ErrorResponse::new(e.to_string()))),
+impl IdOutput {
+    fn from_query(format: Option<Encoding>, numeric: NumericMode) -> std::result::Result<Self, String> {
+        match (numeric, format) {
+            (NumericMode::Off, format) => Ok(IdOutput::String(format.unwrap_or(Encoding::Decimal))),
+            (_, Some(format)) if format != Encoding::Decimal => {
+                Err(format!("numeric cannot be combined with format={}", format.name()))
+            }
+            (NumericMode::On, _) => Ok(IdOutput::Number),
+            (NumericMode::JsSafe, _) => Ok(IdOutput::JsSafeNumber),
+        }
     }
-}
 
-fn parse_worker_id() -> std::result::Result<u64, String> {
-    // Priority 1: Explicit WORKER_ID environment variable
-    if let Ok(worker_id_str) = var("WORKER_ID") {
-        let worker_id: u64 = worker_id_str
-            .parse()
-            .map_err(|_| format!("WORKER_ID must be a valid number, got: '{}'", worker_id_str))?;
+    /// Checks that `id` can be written in this output.
+    fn check(&self, id: u64) -> std::result::Result<(), String> {
+        match self {
+            IdOutput::JsSafeNumber if id > JS_MAX_SAFE_INTEGER => Err(format!(
+                "ID {} exceeds 2^53 - 1 and cannot be represented exactly as a JavaScript number; request string IDs instead",
+                id
+            )),
+            _ => Ok(()),
+        }
+    }
 
-        if worker_id > MAX_WORKER_ID {
+    /// Checks that every ID of a `count`-ID request started now can be written
+    /// in this output, assuming it is generated as fast as the sequence space
+    /// allows.
+    fn check_request(&self, generator: &SnowflakeGenerator, count: u64) -> std::result::Result<(), String> {
+        if !matches!(self, IdOutput::JsSafeNumber) {
+            return Ok(());
+        }
+        let layout = generator.layout();
+        let start = generator.current_timestamp().max(generator.last_timestamp());
+        // The rest of the current millisecond may already be taken
+        let span_ms = count.div_ceil(layout.sequence_mask() + 1);
+        let last = (start + span_ms).min(layout.timestamp_mask());
+        let bound = layout.format(generator.worker_id(), layout.sequence_mask(), last);
+        if bound > JS_MAX_SAFE_INTEGER {
             return Err(format!(
-                "WORKER_ID must be between 0 and {}, got: {}",
-                MAX_WORKER_ID, worker_id
+                "IDs for this request could reach {}, which exceeds 2^53 - 1 and cannot be represented exactly as a JavaScript number; request string IDs instead",
+                bound
             ));
         }
-        return Ok(worker_id);
+        Ok(())
     }
 
-    // Priority 2: Derive from POD_NAME (e.g., "id-generator-0" -> 0)
-    if let Ok(pod_name) = var("POD_NAME") {
-        if let Some(last_part) = pod_name.rsplit('-').next() {
-            if let Ok(id) = last_part.parse::<u64>() {
-                if id > MAX_WORKER_ID {
-                    return Err(format!(
-                        "Derived worker ID from POD_NAME '{}' is {}, which exceeds max {}",
-                        pod_name, id, MAX_WORKER_ID
-                    ));
-                }
-                println!("Derived WORKER_ID={} from POD_NAME='{}'", id, pod_name);
-                return Ok(id);
+    /// Appends `id` as a JSON value.
+    fn write(&self, id: u64, out: &mut String) -> std::result::Result<(), String> {
+        self.check(id)?;
+        match self {
+            IdOutput::String(encoding) => {
+                out.push('"');
+                encoding.write(id, out);
+                out.push('"');
+            }
+            IdOutput::Number | IdOutput::JsSafeNumber => {
+                // Writing to a String cannot fail
+                let _ = write!(out, "{}", id);
             }
         }
+        Ok(())
     }
-
-    Err("WORKER_ID environment variable is required, or POD_NAME must end with a number".to_string())
 }
 
-fn parse_workers() -> u32 {
-    var("WORKERS")
-        .ok()
-        .and_then(|s| s.parse().ok())
-        .unwrap_or(1)
+#[derive(Deserialize)]
+struct IdQuery {
+    #[serde(default, deserialize_with = "deserialize_encoding")]
+    format: Option<Encoding>,
+    #[serde(default)]
+    numeric: NumericMode,
 }
 
-#[actix_web::main]
-async fn main() -> std::io::Result<()> {
-    let worker_id = match parse_worker_id() {
+#[get("/id")]
+async fn snowflake(req: HttpRequest, data: web::Data<AppState>, query: web::Query<IdQuery>) -> Result<HttpResponse> {
+    let output = match IdOutput::from_query(query.format, query.numeric) {
+        Ok(output) => output,
+        Err(e) => return Ok(HttpResponse::BadRequest().json(ErrorResponse::new(e))),
+    };
+
+    if let Err(e) = output.check_request(&data.generator, 1) {
+        return Ok(HttpResponse::UnprocessableEntity().json(ErrorResponse::new(e)));
+    }
+
+    if let Err(throttle) = rate_limit::check_http(&req, &data, 1) {
+        return Ok(rate_limit::throttle_response(throttle));
+    }
+
+    let id = match data.generator.next_id_async().await {
         Ok(id) => id,
         Err(e) => {
-            eprintln!("Configuration error: {}", e);
-            std::process::exit(1);
+            rate_limit::refund_http(&req, &data, 1);
+            return Ok(HttpResponse::build(StatusCode::INTERNAL_SERVER_ERROR)
+                .json(ErrorResponse::new(e.to_string())));
         }
     };
 
-    let workers = parse_workers();
-
-    println!("Starting id-generator with worker_id={}, workers={}", worker_id, workers);
+    // The clock may have moved past the bound since the check above
+    if let Err(e) = output.check(id) {
+        rate_limit::refund_http(&req, &data, 1);
+        return Ok(HttpResponse::UnprocessableEntity().json(ErrorResponse::new(e)));
+    }
 
-    // Initialize metrics
-    WORKER_ID.set(worker_id as f64);
-    MAX_SEQUENCE_PER_MS.set(SEQUENCE_MASK as f64);
+    match output {
+        IdOutput::String(encoding) => Ok(HttpResponse::Ok().json(Id { id: encoding.encode(id) })),
+        IdOutput::Number | IdOutput::JsSafeNumber => Ok(HttpResponse::Ok().json(NumericId { id })),
+    }
+}
 
-    let data = web::Data::new(AppState {
-        worker_id,
-        sequence: Mutex::new(0),
-        timestamp: Mutex::new(0),
-    });
+#[derive(Deserialize)]
+struct BulkQuery {
+    /// Return `{timestamp, worker_id, seq_start, seq_end}` segments instead of
+    /// individual IDs.
+    #[serde(default)]
+    compact: bool,
+    #[serde(default, deserialize_with = "deserialize_encoding")]
+    format: Option<Encoding>,
+    #[serde(default)]
+    numeric: NumericMode,
+}
 
-    HttpServer::new(move || {
-        App::new()
-            .app_data(data.clone())
-            .service(health)
-            .service(metrics)
-            .service(snowflake)
-            .service(snowflakes)
-    })
-    .bind(("0.0.0.0", 8080))?
-    .workers(workers as usize)
-    .run()
-    .await
+/// Response body formats for `/ids/{count}`, negotiated from `Accept`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum BulkFormat {
+    /// `{"ids": [...]}`
+    Json,
+    /// One JSON value per line.
+    Ndjson,
+    /// An `id` header row followed by one ID per row.
+    Csv,
+    /// Raw 8-byte unsigned integers, little-endian unless `endian=big`.
+    Binary { big_endian: bool },
+    /// A MessagePack array of uint64.
+    MessagePack,
+    /// A protobuf `message Ids { repeated fixed64 ids = 1; }` (packed).
+    Protobuf,
 }
 
-#[cfg(test)]
-mod tests {
-    use super::*;
+impl BulkFormat {
+    /// Picks the client's most preferred supported format, or `None` if none
+    /// of the accepted types are supported. No `Accept` header means JSON.
+    fn negotiate(accept: Option<&Accept>) -> Option<Self> {
+        match accept {
+            Some(accept) if !accept.is_empty() => accept.ranked().iter().find_map(Self::from_mime),
+            _ => Some(BulkFormat::Json),
+        }
+    }
 
-    #[test]
-    fn test_format_snowflake_basic() {
-        let id = format_snowflake(0, 0, 0);
-        assert_eq!(id, 0);
+    fn from_mime(mime: &mime::Mime) -> Option<Self> {
+        match (mime.type_().as_str(), mime.subtype().as_str()) {
+            ("*", "*") | ("application", "*") | ("application", "json") => Some(BulkFormat::Json),
+            ("application", "x-ndjson") | ("application", "ndjson") => Some(BulkFormat::Ndjson),
+            ("text", "*") | ("text", "csv") => Some(BulkFormat::Csv),
+            ("application", "octet-stream") => match mime.get_param("endian") {
+                None => Some(BulkFormat::Binary { big_endian: false }),
+                Some(endian) if endian == "little" => Some(BulkFormat::Binary { big_endian: false }),
+                Some(endian) if endian == "big" => Some(BulkFormat::Binary { big_endian: true }),
+                Some(_) => None,
+            },
+            ("application", "msgpack") | ("application", "x-msgpack") | ("application", "vnd.msgpack") => {
+                Some(BulkFormat::MessagePack)
+            }
+            ("application", "protobuf") | ("application", "x-protobuf") => Some(BulkFormat::Protobuf),
+            _ => None,
+        }
     }
 
-    #[test]
-    fn test_format_snowflake_with_values() {
-        // Test with specific values
-        let worker_id = 1;
-        let sequence = 1;
-        let timestamp = 1;
+    fn content_type(&self) -> &'static str {
+        match self {
+            BulkFormat::Json => "application/json",
+            BulkFormat::Ndjson => "application/x-ndjson",
+            BulkFormat::Csv => "text/csv; charset=utf-8",
+            BulkFormat::Binary { big_endian: false } => "application/octet-stream; endian=little",
+            BulkFormat::Binary { big_endian: true } => "application/octet-stream; endian=big",
+            BulkFormat::MessagePack => "application/msgpack",
+            BulkFormat::Protobuf => "application/x-protobuf",
+        }
+    }
 
-        let id = format_snowflake(worker_id, sequence, timestamp);
+    /// Bytes written before the first ID of a `count`-ID response.
+    fn prefix(&self, count: u64) -> Vec<u8> {
+        match self {
+            BulkFormat::Json => b"{\"ids\":[".to_vec(),
+            BulkFormat::Csv => b"id\n".to_vec(),
+            BulkFormat::Ndjson | BulkFormat::Binary { .. } => Vec::new(),
+            BulkFormat::MessagePack => {
+                // Counts are capped by max_ids_per_request, which is at most u32::MAX
+                if count <= 15 {
+                    vec![0x90 | count as u8]
+                } else if count <= u64::from(u16::MAX) {
+                    let mut prefix = vec![0xdc];
+                    prefix.extend_from_slice(&(count as u16).to_be_bytes());
+                    prefix
+                } else {
+                    let mut prefix = vec![0xdd];
+                    prefix.extend_from_slice(&(count as u32).to_be_bytes());
+                    prefix
+                }
+            }
+            BulkFormat::Protobuf => {
+                // Field 1, wire type 2 (length-delimited), followed by the
+                // varint byte length of the packed fixed64 values
+                let mut prefix = vec![0x0a];
+                let mut len = count * 8;
+                while len >= 0x80 {
+                    prefix.push((len as u8 & 0x7f) | 0x80);
+                    len >>= 7;
+                }
+                prefix.push(len as u8);
+                prefix
+            }
+        }
+    }
 
-        // Verify the bits are in correct positions
-        // timestamp (41 bits) << 22 | worker_id (10 bits) << 12 | sequence (12 bits)
-        let expected = (1_u64 << 22) | (1_u64 << 12) | 1_u64;
-        assert_eq!(id, expected);
+    fn suffix(&self) -> &'static [u8] {
+        match self {
+            BulkFormat::Json => b"]}",
+            _ => b"",
+        }
     }
 
-    #[test]
-    fn test_format_snowflake_max_values() {
-        let worker_id = MAX_WORKER_ID;
-        let sequence = SEQUENCE_MASK;
-        let timestamp = TIMESTAMP_MASK;
+    /// Ends a response early with `{"error": message}` once IDs have been
+    /// sent, for the formats that can carry one.
+    fn error_trailer(&self, message: &str) -> Option<Vec<u8>> {
+        let message = serde_json::to_string(message).ok()?;
+        match self {
+            BulkFormat::Json => Some(format!("],\"error\":{}}}", message).into_bytes()),
+            BulkFormat::Ndjson => Some(format!("{{\"error\":{}}}\n", message).into_bytes()),
+            _ => None,
+        }
+    }
 
-        let id = format_snowflake(worker_id, sequence, timestamp);
+    /// Encodes every ID in the range.
+    fn write_range(&self, output: IdOutput, range: &IdRange, is_first: bool) -> std::result::Result<Vec<u8>, String> {
+        match self {
+            BulkFormat::Json | BulkFormat::Ndjson | BulkFormat::Csv => {
+                let mut out = String::with_capacity(range.count as usize * 23);
+                for (i, id) in range.ids().enumerate() {
+                    match self {
+                        BulkFormat::Json if !is_first || i > 0 => out.push(','),
+                        _ => {}
+                    }
+                    match (self, output) {
+                        // CSV fields are written unquoted
+                        (BulkFormat::Csv, IdOutput::String(encoding)) => encoding.write(id, &mut out),
+                        _ => output.write(id, &mut out)?,
+                    }
+                    if *self != BulkFormat::Json {
+                        out.push('\n');
+                    }
+                }
+                Ok(out.into_bytes())
+            }
+            BulkFormat::Binary { big_endian } => {
+                let mut out = Vec::with_capacity(range.count as usize * 8);
+                for id in range.ids() {
+                    if *big_endian {
+                        out.extend_from_slice(&id.to_be_bytes());
+                    } else {
+                        out.extend_from_slice(&id.to_le_bytes());
+                    }
+                }
+                Ok(out)
+            }
+            BulkFormat::MessagePack => {
+                let mut out = Vec::with_capacity(range.count as usize * 9);
+                for id in range.ids() {
+                    out.push(0xcf);
+                    out.extend_from_slice(&id.to_be_bytes());
+                }
+                Ok(out)
+            }
+            BulkFormat::Protobuf => {
+                let mut out = Vec::with_capacity(range.count as usize * 8);
+                for id in range.ids() {
+                    out.extend_from_slice(&id.to_le_bytes());
+                }
+                Ok(out)
+            }
+        }
+    }
+}
 
-        // Extract and verify each component
-        let extracted_sequence = id & SEQUENCE_MASK;
-        let extracted_worker = (id >> 12) & WORKER_ID_MASK;
-        let extracted_timestamp = (id >> 22) & TIMESTAMP_MASK;
+#[get("/ids/{count}")]
+async fn snowflakes(
+    req: HttpRequest,
+    data: web::Data<AppState>,
+    path: web::Path<u64>,
+    query: web::Query<BulkQuery>,
+) -> Result<HttpResponse> {
+    let count = path.into_inner();
+    let max_ids_per_request = ApiKey::max_ids_per_request(
+        req.extensions().get::<Arc<ApiKey>>().map(Arc::as_ref),
+        data.max_ids_per_request,
+    );
 
-        assert_eq!(extracted_sequence, SEQUENCE_MASK);
-        assert_eq!(extracted_worker, MAX_WORKER_ID);
-        assert_eq!(extracted_timestamp, TIMESTAMP_MASK);
+    if count == 0 {
+        return Ok(HttpResponse::BadRequest()
+            .json(ErrorResponse::new("Count must be at least 1")));
     }
 
-    #[test]
-    fn test_format_snowflake_masks_overflow() {
-        // Values exceeding mask should be truncated
-        let worker_id = 2048; // > 1023
-        let sequence = 8192;  // > 4095
-
-        let id = format_snowflake(worker_id, sequence, 0);
+    if count > max_ids_per_request {
+        return Ok(HttpResponse::BadRequest()
+            .json(ErrorResponse::new(format!(
+                "Count must be less than or equal to {}",
+                max_ids_per_request
+            ))));
+    }
 
-        let extracted_worker = (id >> 12) & WORKER_ID_MASK;
-        let extracted_sequence = id & SEQUENCE_MASK;
+    let format = match BulkFormat::negotiate(req.get_header::<Accept>().as_ref()) {
+        Some(format) => format,
+        None => {
+            return Ok(HttpResponse::NotAcceptable().json(ErrorResponse::new(
+                "Supported types are application/json, application/x-ndjson, text/csv, \
+                 application/octet-stream (endian=little or endian=big), application/msgpack \
+                 and application/x-protobuf",
+            )));
+        }
+    };
 
-        // Should be masked to valid ranges
-        assert_eq!(extracted_worker, worker_id & WORKER_ID_MASK);
-        assert_eq!(extracted_sequence, sequence & SEQUENCE_MASK);
+    if query.compact && (query.format.is_some() || query.numeric != NumericMode::Off) {
+        return Ok(HttpResponse::BadRequest()
+            .json(ErrorResponse::new("format and numeric cannot be combined with compact")));
     }
 
-    #[test]
-    fn test_get_timestamp_returns_positive() {
-        let ts = get_timestamp();
-        // After UNIX_EPOCH_OFFSET (Jan 2024), timestamp should be positive
-        assert!(ts > 0, "Timestamp should be positive after epoch offset");
+    if query.compact && format != BulkFormat::Json {
+        return Ok(HttpResponse::BadRequest()
+            .json(ErrorResponse::new("compact is only available for JSON responses")));
     }
 
-    #[test]
-    fn test_get_timestamp_increases() {
-        let ts1 = get_timestamp();
-        std::thread::sleep(Duration::from_millis(2));
-        let ts2 = get_timestamp();
-        assert!(ts2 >= ts1, "Timestamp should not decrease");
+    let binary = matches!(format, BulkFormat::Binary { .. } | BulkFormat::MessagePack | BulkFormat::Protobuf);
+    if (binary && query.format.is_some()) || ((binary || format == BulkFormat::Csv) && query.numeric != NumericMode::Off) {
+        return Ok(HttpResponse::BadRequest().json(ErrorResponse::new(format!(
+            "format and numeric don't apply to {} responses",
+            format.content_type()
+        ))));
     }
 
-    #[test]
-    fn test_generate_snowflakes_single() {
-        let sequence = Mutex::new(0);
-        let timestamp = Mutex::new(0);
+    let output = match IdOutput::from_query(query.format, query.numeric) {
+        Ok(output) => output,
+        Err(e) => return Ok(HttpResponse::BadRequest().json(ErrorResponse::new(e))),
+    };
 
-        let result = generate_snowflakes(1, &sequence, &timestamp, 1);
+    if let Err(e) = output.check_request(&data.generator, count) {
+        return Ok(HttpResponse::UnprocessableEntity().json(ErrorResponse::new(e)));
+    }
 
-        assert!(result.is_ok());
-        let ids = result.unwrap();
-        assert_eq!(ids.len(), 1);
-        assert!(ids[0].parse::<u64>().is_ok());
+    if let Err(throttle) = rate_limit::check_http(&req, &data, count) {
+        return Ok(rate_limit::throttle_response(throttle));
     }
 
-    #[test]
-    fn test_generate_snowflakes_multiple() {
-        let sequence = Mutex::new(0);
-        let timestamp = Mutex::new(0);
+    BATCH_SIZE.observe(count as f64);
 
-        let result = generate_snowflakes(1, &sequence, &timestamp, 100);
+    // Generate the first range up front so failures can still get a proper
+    // error response; later failures can only end the stream early.
+    let first = match data.generator.next_range_async(count).await {
+        Ok(first) => first,
+        Err(e) => {
+            rate_limit::refund_http(&req, &data, count);
+            return Ok(HttpResponse::build(StatusCode::INTERNAL_SERVER_ERROR)
+                .json(ErrorResponse::new(e.to_string())));
+        }
+    };
 
-        assert!(result.is_ok());
-        let ids = result.unwrap();
-        assert_eq!(ids.len(), 100);
+    let body = if query.compact {
+        let layout = data.generator.layout();
+        let prefix = format!(
+            "{{\"layout\":{{\"timestamp_bits\":{},\"worker_id_bits\":{},\"sequence_bits\":{}}},\"epoch_ms\":{},\"ranges\":[",
+            layout.timestamp_bits(),
+            layout.worker_id_bits(),
+            layout.sequence_bits(),
+            data.generator.epoch_ms()
+        );
+        stream_ranges(data, first, count, prefix.into_bytes(), b"]}", write_range_segment, |_| None).boxed_local()
+    } else {
+        // A client reading slower than IDs can be generated can still push
+        // js_safe IDs past the bound checked above; the response then ends
+        // with an error in place of the remaining IDs
+        let write_ids = move |range: &IdRange, is_first: bool| format.write_range(output, range, is_first);
+        let error_trailer = move |message: &str| format.error_trailer(message);
+        stream_ranges(data, first, count, format.prefix(count), format.suffix(), write_ids, error_trailer).boxed_local()
+    };
+
+    Ok(HttpResponse::Ok().content_type(format.content_type()).streaming(body))
+}
+
+/// Writes the range as a single `{timestamp, worker_id, seq_start, seq_end}`
+/// object.
+fn write_range_segment(range: &IdRange, is_first: bool) -> std::result::Result<Vec<u8>, String> {
+    let mut out = String::new();
+    if !is_first {
+        out.push(',');
     }
+    // Writing to a String cannot fail
+    let _ = write!(
+        out,
+        "{{\"timestamp\":{},\"worker_id\":{},\"seq_start\":{},\"seq_end\":{}}}",
+        range.timestamp,
+        range.worker_id,
+        range.sequence_start,
+        range.sequence_end()
+    );
+    Ok(out.into_bytes())
+}
 
-    #[test]
-    fn test_generate_snowflakes_uniqueness() {
-        let sequence = Mutex::new(0);
-        let timestamp = Mutex::new(0);
+/// Streams `prefix`, then each generated range encoded with `write_range`,
+/// then `suffix`. If generating or writing a range fails, the bytes from
+/// `error_trailer` end the response in place of `suffix`, or the stream is
+/// aborted without them.
+///
+/// Ranges are generated one at a time as the response is written, so memory
+/// use is bounded by the size of a single millisecond's sequence space
+/// regardless of `count`.
+fn stream_ranges(
+    data: web::Data<AppState>,
+    first: IdRange,
+    count: u64,
+    prefix: Vec<u8>,
+    suffix: &'static [u8],
+    write_range: impl Fn(&IdRange, bool) -> std::result::Result<Vec<u8>, String> + Copy + 'static,
+    error_trailer: impl Fn(&str) -> Option<Vec<u8>> + Copy + 'static,
+) -> impl Stream<Item = std::result::Result<web::Bytes, Box<dyn std::error::Error>>> {
+    let remaining = count - first.count;
+    let end_with_error = move |e: String| match error_trailer(&e) {
+        Some(trailer) => Ok(web::Bytes::from(trailer)),
+        None => Err(e.into()),
+    };
+
+    let body = stream::unfold(
+        (data, Some(first), remaining, true, false),
+        move |(data, pending, remaining, is_first, done)| async move {
+            if done {
+                return None;
+            }
+            // `remaining` counts IDs that haven't been reserved yet
+            let (range, remaining) = match pending {
+                Some(range) => (range, remaining),
+                None if remaining == 0 => {
+                    return Some((Ok(web::Bytes::from_static(suffix)), (data, None, 0, false, true)));
+                }
+                None => {
+                    // Let other requests on this worker run between chunks
+                    tokio::task::yield_now().await;
+                    match data.generator.next_range_async(remaining).await {
+                        Ok(range) => (range, remaining - range.count),
+                        Err(e) => return Some((end_with_error(e.to_string()), (data, None, 0, false, true))),
+                    }
+                }
+            };
 
-        let result = generate_snowflakes(1, &sequence, &timestamp, 1000);
+            match write_range(&range, is_first) {
+                Ok(chunk) => Some((Ok(web::Bytes::from(chunk)), (data, None, remaining, false, false))),
+                Err(e) => Some((end_with_error(e), (data, None, 0, false, true))),
+            }
+        },
+    );
 
-        assert!(result.is_ok());
-        let ids = result.unwrap();
+    stream::once(async { Ok(web::Bytes::from(prefix)) }).chain(body)
+}
+
+/// Reports malformed query strings in the usual `ErrorResponse` shape.
+fn query_config() -> web::QueryConfig {
+    web::QueryConfig::default().error_handler(|err, _req| {
+        let response = HttpResponse::BadRequest().json(ErrorResponse::new(err.to_string()));
+        actix_web::error::InternalError::from_response(err, response).into()
+    })
+}
 
-        // Check all IDs are unique
-        let mut unique_ids: std::collections::HashSet<&String> = std::collections::HashSet::new();
-        for id in &ids {
-            assert!(unique_ids.insert(id), "Duplicate ID found: {}", id);
+#[get("/decode/{id}")]
+async fn decode(
+    data: web::Data<AppState>,
+    path: web::Path<String>,
+    query: web::Query<FormatQuery>,
+) -> Result<HttpResponse> {
+    let raw = path.into_inner();
+
+    let parsed = match query.format {
+        Some(encoding) => encoding.decode(&raw),
+        None => Encoding::decode_any(&raw),
+    };
+    let id = match parsed {
+        Some(id) => id,
+        None => {
+            return Ok(HttpResponse::BadRequest()
+                .json(ErrorResponse::new(format!("ID must be a valid number or encoded ID, got: '{}'", raw))));
         }
+    };
+
+    match data.generator.decode(id) {
+        Ok(decoded) => Ok(HttpResponse::Ok().json(DecodeResponse {
+            id: id.to_string(),
+            timestamp: decoded.timestamp,
+            unix_ms: decoded.unix_ms,
+            time: decoded.rfc3339(),
+            worker_id: decoded.worker_id,
+            sequence: decoded.sequence,
+        })),
+        Err(e) => Ok(HttpResponse::BadRequest().json(ErrorResponse::new(e.to_string()))),
     }
+}
 
-    #[test]
-    fn test_generate_snowflakes_ordering() {
-        let sequence = Mutex::new(0);
-        let timestamp = Mutex::new(0);
+/// Reads the config file from `--config`, or `CONFIG_FILE` if the flag isn't
+/// given. Without either, every setting starts at its default.
+fn load_config(flag: Option<&Path>) -> std::result::Result<Config, String> {
+    match flag.map(Path::to_path_buf).or_else(|| var("CONFIG_FILE").ok().map(PathBuf::from)) {
+        Some(path) => Config::load(&path),
+        None => Ok(Config::default()),
+    }
+}
 
-        let result = generate_snowflakes(1, &sequence, &timestamp, 100);
+fn parse_layout(config: &GeneratorConfig) -> std::result::Result<Layout, String> {
+    let bits = |name: &str, default: u8| -> std::result::Result<u8, String> {
+        match var(name) {
+            Ok(s) => s
+                .parse()
+                .map_err(|_| format!("{} must be a valid number, got: '{}'", name, s)),
+            Err(_) => Ok(default),
+        }
+    };
 
-        assert!(result.is_ok());
-        let ids: Vec<u64> = result.unwrap()
-            .iter()
-            .map(|s| s.parse::<u64>().unwrap())
-            .collect();
+    let timestamp_bits = bits("TIMESTAMP_BITS", config.timestamp_bits)?;
+    let worker_id_bits = bits("WORKER_ID_BITS", config.worker_id_bits)?;
+    let sequence_bits = bits("SEQUENCE_BITS", config.sequence_bits)?;
 
-        // IDs should be monotonically increasing
-        for i in 1..ids.len() {
-            assert!(ids[i] > ids[i-1], "IDs should be monotonically increasing");
-        }
+    Layout::new(timestamp_bits, worker_id_bits, sequence_bits).map_err(|e| e.to_string())
+}
+
+fn parse_epoch(config: &GeneratorConfig) -> std::result::Result<u64, String> {
+    match (var("EPOCH_MS"), var("EPOCH")) {
+        (Ok(_), Ok(_)) => Err("Only one of EPOCH_MS and EPOCH may be set".to_string()),
+        (Ok(epoch_ms), Err(_)) => epoch_ms
+            .parse()
+            .map_err(|_| format!("EPOCH_MS must be a valid number, got: '{}'", epoch_ms)),
+        (Err(_), Ok(epoch)) => parse_rfc3339(&epoch).ok_or_else(|| {
+            format!(
+                "EPOCH must be a UTC date (YYYY-MM-DD) or date-time (YYYY-MM-DDTHH:MM:SS[.mmm]Z), got: '{}'",
+                epoch
+            )
+        }),
+        (Err(_), Err(_)) => Ok(config.epoch_ms()?.unwrap_or(UNIX_EPOCH_OFFSET)),
     }
+}
 
-    #[test]
-    fn test_generate_snowflakes_worker_id_embedded() {
-        let sequence = Mutex::new(0);
-        let timestamp = Mutex::new(0);
-        let worker_id = 42;
+fn parse_worker_id(max_worker_id: u64, config: &WorkerConfig) -> std::result::Result<u64, String> {
+    // Priority 1: Explicit WORKER_ID environment variable
+    if let Ok(worker_id_str) = var("WORKER_ID") {
+        let worker_id: u64 = worker_id_str
+            .parse()
+            .map_err(|_| format!("WORKER_ID must be a valid number, got: '{}'", worker_id_str))?;
 
-        let result = generate_snowflakes(worker_id, &sequence, &timestamp, 10);
+        if worker_id > max_worker_id {
+            return Err(format!(
+                "WORKER_ID must be between 0 and {}, got: {}",
+                max_worker_id, worker_id
+            ));
+        }
+        return Ok(worker_id);
+    }
 
-        assert!(result.is_ok());
-        for id_str in result.unwrap() {
-            let id: u64 = id_str.parse().unwrap();
-            let extracted_worker = (id >> 12) & WORKER_ID_MASK;
-            assert_eq!(extracted_worker, worker_id, "Worker ID should be embedded in snowflake");
+    // Priority 2: worker.id from the config file, checked again in case the
+    // environment changed the layout
+    if let Some(worker_id) = config.id {
+        if worker_id > max_worker_id {
+            return Err(format!(
+                "worker.id must be between 0 and {}, got: {}",
+                max_worker_id, worker_id
+            ));
         }
+        return Ok(worker_id);
     }
 
-    #[test]
-    fn test_parse_worker_id_missing() {
-        // This test relies on WORKER_ID not being set
+    // Priority 3: Derive from POD_NAME (e.g., "id-generator-0" -> 0)
+    let pod_name = var("POD_NAME").ok().filter(|_| config.from_pod_name);
+    if let Some(pod_name) = pod_name {
+        if let Some(last_part) = pod_name.rsplit('-').next() {
+            if let Ok(id) = last_part.parse::<u64>() {
+                if id > max_worker_id {
+                    return Err(format!(
+                        "Derived worker ID from POD_NAME '{}' is {}, which exceeds max {}",
+                        pod_name, id, max_worker_id
+                    ));
+                }
+                println!("Derived WORKER_ID={} from POD_NAME='{}'", id, pod_name);
+                return Ok(id);
+            }
+        }
+    }
+
+    Err("WORKER_ID environment variable or worker.id is required, or POD_NAME must end with a number".to_string())
+}
+
+fn parse_high_water_mark(config: Option<&HighWaterMarkConfig>) -> std::result::Result<Option<HighWaterMarkOptions>, String> {
+    let mut options = match (var("HIGH_WATER_MARK_FILE"), config) {
+        (Ok(path), _) => HighWaterMarkOptions::new(path),
+        (Err(_), Some(config)) => HighWaterMarkOptions {
+            path: config.file.clone(),
+            window_ms: config.window_ms,
+        },
+        (Err(_), None) => return Ok(None),
+    };
+
+    if let Ok(window_ms) = var("HIGH_WATER_MARK_WINDOW_MS") {
+        options.window_ms = window_ms
+            .parse()
+            .map_err(|_| format!("HIGH_WATER_MARK_WINDOW_MS must be a valid number, got: '{}'", window_ms))?;
+    }
+    Ok(Some(options))
+}
+
+/// Reads `TLS_CERT_FILE` and `TLS_KEY_FILE`, which replace the file's `tls`
+/// section, and `TLS_CLIENT_CA_FILE`, `TLS_CLIENT_AUTH` and
+/// `TLS_RELOAD_INTERVAL_SECS`, which override single keys.
+fn parse_tls(config: Option<&TlsConfig>) -> std::result::Result<Option<TlsConfig>, String> {
+    let mut tls = match (var("TLS_CERT_FILE"), var("TLS_KEY_FILE"), config) {
+        (Ok(cert_file), Ok(key_file), _) => TlsConfig {
+            cert_file: cert_file.into(),
+            key_file: key_file.into(),
+            client_ca_file: None,
+            client_auth: ClientAuth::default(),
+            reload_interval_secs: DEFAULT_TLS_RELOAD_INTERVAL_SECS,
+        },
+        (Err(_), Err(_), Some(config)) => config.clone(),
+        (Err(_), Err(_), None) => {
+            if var("TLS_CLIENT_CA_FILE").is_ok() {
+                return Err("TLS_CLIENT_CA_FILE requires TLS_CERT_FILE and TLS_KEY_FILE".to_string());
+            }
+            return Ok(None);
+        }
+        _ => return Err("TLS_CERT_FILE and TLS_KEY_FILE must be set together".to_string()),
+    };
+
+    if let Ok(client_ca_file) = var("TLS_CLIENT_CA_FILE") {
+        tls.client_ca_file = Some(client_ca_file.into());
+    }
+    match var("TLS_CLIENT_AUTH").as_deref() {
+        Err(_) => {}
+        Ok("optional") => tls.client_auth = ClientAuth::Optional,
+        Ok("required") => tls.client_auth = ClientAuth::Required,
+        Ok(other) => return Err(format!("TLS_CLIENT_AUTH must be 'optional' or 'required', got: '{}'", other)),
+    }
+    if let Ok(interval) = var("TLS_RELOAD_INTERVAL_SECS") {
+        tls.reload_interval_secs = interval
+            .parse()
+            .map_err(|_| format!("TLS_RELOAD_INTERVAL_SECS must be a valid number, got: '{}'", interval))?;
+    }
+    Ok(Some(tls))
+}
+
+/// Reads `RATE_LIMIT_IDS_PER_SEC`, `RATE_LIMIT_BURST` and
+/// `RATE_LIMIT_TRUST_FORWARDED_FOR`, falling back to `rate_limit`.
+fn parse_rate_limit(config: &RateLimitConfig) -> std::result::Result<RateLimiter, String> {
+    let ids_per_sec = match var("RATE_LIMIT_IDS_PER_SEC") {
+        Ok(s) => match s.parse::<f64>() {
+            Ok(rate) if rate.is_finite() && rate > 0.0 => Some(rate),
+            _ => return Err(format!("RATE_LIMIT_IDS_PER_SEC must be a positive number, got: '{}'", s)),
+        },
+        Err(_) => config.ids_per_sec,
+    };
+    let burst = match var("RATE_LIMIT_BURST") {
+        Ok(s) => match s.parse::<u64>() {
+            Ok(burst) if burst >= 1 => Some(burst),
+            _ => return Err(format!("RATE_LIMIT_BURST must be a positive number, got: '{}'", s)),
+        },
+        Err(_) => config.burst,
+    };
+    let trust_forwarded_for = match var("RATE_LIMIT_TRUST_FORWARDED_FOR").as_deref() {
+        Err(_) => config.trust_forwarded_for,
+        Ok("true") => true,
+        Ok("false") => false,
+        Ok(other) => {
+            return Err(format!(
+                "RATE_LIMIT_TRUST_FORWARDED_FOR must be 'true' or 'false', got: '{}'",
+                other
+            ))
+        }
+    };
+
+    if ids_per_sec.is_none() && burst.is_some() {
+        return Err("RATE_LIMIT_BURST needs RATE_LIMIT_IDS_PER_SEC".to_string());
+    }
+    let default = ids_per_sec.map(|ids_per_sec| Limit::new(ids_per_sec, burst));
+    Ok(RateLimiter::new(default, trust_forwarded_for))
+}
+
+/// Loads the key file from `API_KEYS_FILE` or `auth.key_file`.
+fn parse_api_keys(config: &AuthConfig) -> std::result::Result<Option<ApiKeys>, String> {
+    var("API_KEYS_FILE")
+        .ok()
+        .map(PathBuf::from)
+        .or_else(|| config.key_file.clone())
+        .map(|path| ApiKeys::load(&path))
+        .transpose()
+}
+
+/// Selects the clock from `CLOCK_MODE`: `system` (default) reads the wall
+/// clock on every call, `monotonic` anchors it once and slews by
+/// `CLOCK_SLEW_PPM`.
+fn parse_clock(config: &ClockConfig) -> std::result::Result<Arc<dyn Clock>, String> {
+    let slew_ppm = match var("CLOCK_SLEW_PPM") {
+        Ok(s) => match s.parse::<u64>() {
+            Ok(ppm) if ppm < 1_000_000 => ppm,
+            _ => return Err(format!("CLOCK_SLEW_PPM must be a number below 1000000, got: '{}'", s)),
+        },
+        Err(_) => config.slew_ppm,
+    };
+
+    let mode = match var("CLOCK_MODE").as_deref() {
+        Err(_) => config.mode,
+        Ok("system") => ClockMode::System,
+        Ok("monotonic") => ClockMode::Monotonic,
+        Ok(other) => return Err(format!("CLOCK_MODE must be 'system' or 'monotonic', got: '{}'", other)),
+    };
+
+    match mode {
+        ClockMode::System => Ok(Arc::new(SystemClock)),
+        ClockMode::Monotonic => Ok(Arc::new(MonotonicClock::new(slew_ppm))),
+    }
+}
+
+/// Reads `CLOCK_DRIFT_POLICY` (`wait`, `fail_fast` or `borrow`, capped by
+/// `CLOCK_DRIFT_MAX_BORROW_MS`) and `CLOCK_DRIFT_TIMEOUT_MS`.
+fn parse_drift_policy(config: &ClockConfig) -> std::result::Result<(DriftPolicy, u64), String> {
+    let number = |name: &str, default: u64| -> std::result::Result<u64, String> {
+        match var(name) {
+            Ok(s) => s
+                .parse()
+                .map_err(|_| format!("{} must be a valid number, got: '{}'", name, s)),
+            Err(_) => Ok(default),
+        }
+    };
+
+    let name = match var("CLOCK_DRIFT_POLICY").as_deref() {
+        Err(_) => config.drift_policy,
+        Ok("wait") => DriftPolicyName::Wait,
+        Ok("fail_fast") => DriftPolicyName::FailFast,
+        Ok("borrow") => DriftPolicyName::Borrow,
+        Ok(other) => {
+            return Err(format!(
+                "CLOCK_DRIFT_POLICY must be 'wait', 'fail_fast' or 'borrow', got: '{}'",
+                other
+            ))
+        }
+    };
+
+    let policy = match name {
+        DriftPolicyName::Wait => DriftPolicy::Wait,
+        DriftPolicyName::FailFast => DriftPolicy::FailFast,
+        DriftPolicyName::Borrow => DriftPolicy::Borrow {
+            max_lead_ms: number("CLOCK_DRIFT_MAX_BORROW_MS", config.max_borrow_ms)?,
+        },
+    };
+
+    Ok((policy, number("CLOCK_DRIFT_TIMEOUT_MS", config.drift_timeout_ms)?))
+}
+
+fn parse_max_exhaustions(config: &ReadinessConfig) -> std::result::Result<f64, String> {
+    match var("READY_MAX_EXHAUSTIONS_PER_SEC") {
+        Ok(s) => match s.parse::<f64>() {
+            Ok(rate) if rate >= 0.0 => Ok(rate),
+            _ => Err(format!("READY_MAX_EXHAUSTIONS_PER_SEC must be a non-negative number, got: '{}'", s)),
+        },
+        Err(_) => Ok(config.max_exhaustions_per_sec),
+    }
+}
+
+fn parse_max_ids(config: &LimitsConfig) -> std::result::Result<u64, String> {
+    match var("MAX_IDS_PER_REQUEST") {
+        Ok(s) => match s.parse::<u64>() {
+            Ok(max) if max >= 1 && max <= u64::from(u32::MAX) => Ok(max),
+            _ => Err(format!("MAX_IDS_PER_REQUEST must be between 1 and {}, got: '{}'", u32::MAX, s)),
+        },
+        Err(_) => Ok(config.max_ids_per_request),
+    }
+}
+
+fn parse_grpc_port(default: u16) -> std::result::Result<u16, String> {
+    match var("GRPC_PORT") {
+        Ok(s) => s
+            .parse()
+            .map_err(|_| format!("GRPC_PORT must be a valid port number, got: '{}'", s)),
+        Err(_) => Ok(default),
+    }
+}
+
+/// Reads a comma-separated list of listeners from `name`.
+fn parse_listeners(name: &str, default: Vec<Listener>) -> std::result::Result<Vec<Listener>, String> {
+    match var(name) {
+        Ok(s) => s
+            .split(',')
+            .map(str::trim)
+            .filter(|listener| !listener.is_empty())
+            .map(|listener| listener.parse().map_err(|e| format!("{}: {}", name, e)))
+            .collect(),
+        Err(_) => Ok(default),
+    }
+}
+
+/// Works out the HTTP and admin listeners. `--listen` and `--admin-listen`
+/// override `LISTEN` and `ADMIN_LISTEN`, which override the config file.
+/// `--bind` and `--port` replace the HTTP listeners with a single TCP one,
+/// based on the first configured TCP listener.
+fn resolve_listeners(args: &ServeArgs, server: &ServerConfig) -> std::result::Result<(Vec<Listener>, Vec<Listener>), String> {
+    let mut listen = match &args.listen {
+        listen if !listen.is_empty() => listen.clone(),
+        _ => parse_listeners("LISTEN", server.listen.clone())?,
+    };
+
+    if args.bind.is_some() || args.port.is_some() {
+        let mut addr = listen
+            .iter()
+            .find_map(|listener| match listener {
+                Listener::Tcp(addr) => Some(*addr),
+                Listener::Unix(_) => None,
+            })
+            .unwrap_or(SocketAddr::from(([0, 0, 0, 0], DEFAULT_HTTP_PORT)));
+        if let Some(bind) = args.bind {
+            addr.set_ip(bind);
+        }
+        if let Some(port) = args.port {
+            addr.set_port(port);
+        }
+        listen = vec![Listener::Tcp(addr)];
+    }
+
+    let admin_listen = match &args.admin_listen {
+        admin_listen if !admin_listen.is_empty() => admin_listen.clone(),
+        _ => parse_listeners("ADMIN_LISTEN", server.admin_listen.clone())?,
+    };
+
+    if listen.is_empty() {
+        return Err("At least one HTTP listener is required".to_string());
+    }
+    if let Some(shared) = admin_listen.iter().find(|admin| listen.contains(admin)) {
+        return Err(format!("{} can't be both an HTTP and an admin listener", shared));
+    }
+    Ok((listen, admin_listen))
+}
+
+/// Uses the `--worker` flag if given, and otherwise `WORKER_ID`, `worker.id`
+/// or `POD_NAME`.
+fn resolve_worker_id(flag: Option<u64>, max_worker_id: u64, config: &WorkerConfig) -> std::result::Result<u64, String> {
+    match flag {
+        Some(worker_id) if worker_id > max_worker_id => Err(format!(
+            "--worker must be between 0 and {}, got: {}",
+            max_worker_id, worker_id
+        )),
+        Some(worker_id) => Ok(worker_id),
+        None => parse_worker_id(max_worker_id, config),
+    }
+}
+
+fn parse_workers(default: u32) -> u32 {
+    var("WORKERS")
+        .ok()
+        .and_then(|s| s.parse().ok())
+        .unwrap_or(default)
+}
+
+/// Prints IDs for the `generate` subcommand.
+fn generate_ids(args: cli::GenerateArgs, config: &Config) -> std::result::Result<(), String> {
+    let layout = parse_layout(&config.generator)?;
+    let options = GeneratorOptions {
+        layout,
+        epoch_ms: parse_epoch(&config.generator)?,
+        ..Default::default()
+    };
+    let worker_id = resolve_worker_id(args.worker, layout.max_worker_id(), &config.worker)?;
+    let generator = SnowflakeGenerator::with_options(worker_id, options).map_err(|e| e.to_string())?;
+    cli::generate(&args, &generator, std::io::stdout().lock())
+}
+
+/// Explains IDs for the `decode` subcommand.
+fn decode_ids(args: cli::DecodeArgs, config: &Config) -> std::result::Result<(), String> {
+    let layout = parse_layout(&config.generator)?;
+    cli::decode(&args, layout, parse_epoch(&config.generator)?, std::io::stdout().lock())
+}
+
+#[actix_web::main]
+async fn main() -> std::io::Result<()> {
+    let cli = Cli::parse();
+    let config = match load_config(cli.config.as_deref()) {
+        Ok(config) => config,
+        Err(e) => {
+            eprintln!("Configuration error: {}", e);
+            std::process::exit(1);
+        }
+    };
+
+    let result = match cli.command.unwrap_or(Command::Serve(ServeArgs::default())) {
+        Command::Serve(args) => return serve(args, config).await,
+        Command::Generate(args) => generate_ids(args, &config),
+        Command::Decode(args) => decode_ids(args, &config),
+        Command::ApiKey(args) => cli::api_key(&args, std::io::stdout().lock()),
+    };
+
+    if let Err(e) = result {
+        eprintln!("{}", e);
+        std::process::exit(1);
+    }
+    Ok(())
+}
+
+/// Where and how `serve` listens, alongside the `AppState` from [`build_state`].
+struct ServerSetup {
+    listen: Vec<Listener>,
+    admin_listen: Vec<Listener>,
+    grpc_listen: SocketAddr,
+    workers: u32,
+    tls: Option<Arc<TlsCertificates>>,
+    tls_config: Option<rustls::ServerConfig>,
+}
+
+/// Resolves every setting for `serve` from flags, the environment and the
+/// config file, and builds the generator.
+fn build_state(args: &ServeArgs, config: &Config) -> std::result::Result<(AppState, ServerSetup), String> {
+    let layout = parse_layout(&config.generator)?;
+    let epoch_ms = parse_epoch(&config.generator)?;
+    let worker_id = resolve_worker_id(args.worker, layout.max_worker_id(), &config.worker)?;
+
+    let mut grpc_listen = config.server.grpc_listen;
+    grpc_listen.set_port(args.grpc_port.map_or_else(|| parse_grpc_port(grpc_listen.port()), Ok)?);
+
+    let high_water_mark = parse_high_water_mark(config.high_water_mark.as_ref())?;
+    let clock = parse_clock(&config.clock)?;
+    let (drift_policy, drift_timeout_ms) = parse_drift_policy(&config.clock)?;
+    let max_exhaustions_per_sec = parse_max_exhaustions(&config.readiness)?;
+    let max_ids_per_request = parse_max_ids(&config.limits)?;
+    let (listen, admin_listen) = resolve_listeners(args, &config.server)?;
+    let tls = parse_tls(config.tls.as_ref())?.map(TlsCertificates::load).transpose()?;
+    let tls_config = tls.as_ref().map(TlsCertificates::server_config).transpose()?;
+    let api_keys = parse_api_keys(&config.auth)?;
+    let rate_limiter = parse_rate_limit(&config.rate_limit)?;
+    let workers = args.workers.unwrap_or_else(|| parse_workers(config.server.workers));
+
+    let options = GeneratorOptions {
+        layout,
+        epoch_ms,
+        high_water_mark,
+        drift_policy,
+        drift_timeout_ms,
+    };
+    let mut state = AppState::new(worker_id, options, clock).map_err(|e| e.to_string())?;
+    state.readiness.max_exhaustions_per_sec = max_exhaustions_per_sec;
+    state.max_ids_per_request = max_ids_per_request;
+    state.rate_limiter = rate_limiter;
+    state.api_keys = api_keys.map(Arc::new);
+
+    let setup = ServerSetup {
+        listen,
+        admin_listen,
+        grpc_listen,
+        workers,
+        tls,
+        tls_config,
+    };
+    Ok((state, setup))
+}
+
+async fn serve(args: ServeArgs, config: Config) -> std::io::Result<()> {
+    let (state, setup) = match build_state(&args, &config) {
+        Ok(built) => built,
+        Err(e) => {
+            eprintln!("Configuration error: {}", e);
+            std::process::exit(1);
+        }
+    };
+    let ServerSetup {
+        listen,
+        admin_listen,
+        grpc_listen,
+        workers,
+        tls,
+        tls_config,
+    } = setup;
+
+    let generator = &state.generator;
+    let layout = generator.layout();
+    let tls_mode = match &tls {
+        Some(tls) if tls.mutual() => " (mTLS)",
+        Some(_) => " (TLS)",
+        None => "",
+    };
+    println!(
+        "Starting id-generator with worker_id={}, workers={}, http={}{}, admin={}, grpc={}{}, layout={}/{}/{}, epoch_ms={}",
+        generator.worker_id(),
+        workers,
+        join_listeners(&listen),
+        tls_mode,
+        if admin_listen.is_empty() { "http".to_string() } else { join_listeners(&admin_listen) },
+        grpc_listen,
+        tls_mode,
+        layout.timestamp_bits(),
+        layout.worker_id_bits(),
+        layout.sequence_bits(),
+        generator.epoch_ms()
+    );
+
+    // Initialize metrics
+    if let Err(e) = id_generator::metrics::register(prometheus::default_registry()) {
+        eprintln!("Failed to register metrics: {}", e);
+    }
+    WORKER_ID.set(generator.worker_id() as f64);
+    MAX_SEQUENCE_PER_MS.set(layout.sequence_mask() as f64);
+
+    if let Some(limit) = state.rate_limiter.default {
+        println!("Rate limiting clients to {} IDs/s with bursts of {}", limit.ids_per_sec, limit.burst);
+    }
+    if let Some(api_keys) = &state.api_keys {
+        println!("Requiring API keys for {}, {} keys loaded", auth::API_ENDPOINTS.join(", "), api_keys.len());
+    }
+
+    let mark_ms = generator.epoch_ms() + generator.last_timestamp();
+    let now_ms = generator.clock().unix_millis();
+    if mark_ms > now_ms {
+        println!(
+            "High-water mark {} is {} ms ahead of the clock; IDs will be refused until the clock passes it",
+            id_generator::time::format_rfc3339(mark_ms),
+            mark_ms - now_ms
+        );
+    }
+
+    let data = web::Data::new(state);
+    let state = data.clone();
+    let grpc_server = grpc::serve(grpc_listen, data.clone(), tls_config.clone());
+    let metrics_config = config.metrics;
+
+    // Operational endpoints move to their own server when it has listeners
+    let admin_server = if admin_listen.is_empty() {
+        None
+    } else {
+        let metrics_config = metrics_config.clone();
+        let configure = move |cfg: &mut web::ServiceConfig| admin_routes(cfg, &metrics_config);
+        Some(http_server(data.clone(), configure, &admin_listen, 1, None)?)
+    };
+    let separate_admin = admin_server.is_some();
+
+    let configure = move |cfg: &mut web::ServiceConfig| {
+        if !separate_admin {
+            admin_routes(cfg, &metrics_config);
+        }
+        api_routes(cfg);
+    };
+    let http_server = http_server(data, configure, &listen, workers as usize, tls_config.as_ref())?;
+    if let Some(tls) = tls {
+        actix_web::rt::spawn(tls.watch());
+    }
+    let admin_server = async {
+        match admin_server {
+            Some(server) => server.await,
+            None => std::future::pending().await,
+        }
+    };
+
+    // The HTTP servers handle shutdown signals; once one has stopped the
+    // others are dropped along with it
+    let result = tokio::select! {
+        result = http_server => result,
+        result = admin_server => result,
+        result = grpc_server => result,
+    };
+
+    if let Err(e) = state.generator.flush_high_water_mark() {
+        eprintln!("Failed to flush high-water mark: {}", e);
+    }
+
+    result
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use actix_web::test::{call_and_read_body, call_and_read_body_json, call_service, init_service, read_body_json, TestRequest};
+    use id_generator::metrics::THROTTLED_REQUESTS;
+    use id_generator::CLOCK_DRIFT_TIMEOUT_MS;
+    use readiness::DEFAULT_MAX_EXHAUSTIONS_PER_SEC;
+    use std::sync::Mutex;
+
+    // Tests below mutate process-wide environment variables, so they must not
+    // run concurrently with each other.
+    static ENV_LOCK: Mutex<()> = Mutex::new(());
+
+    fn lock_env() -> std::sync::MutexGuard<'static, ()> {
+        ENV_LOCK.lock().unwrap_or_else(|e| e.into_inner())
+    }
+
+    #[test]
+    fn test_parse_worker_id_missing() {
+        let _env = lock_env();
+        // This test relies on WORKER_ID not being set
         std::env::remove_var("WORKER_ID");
-        let result = parse_worker_id();
+        let result = parse_worker_id(Layout::DEFAULT.max_worker_id(), &WorkerConfig::default());
         assert!(result.is_err());
         assert!(result.unwrap_err().contains("required"));
     }
 
     #[test]
     fn test_parse_worker_id_invalid() {
+        let _env = lock_env();
         std::env::set_var("WORKER_ID", "not_a_number");
-        let result = parse_worker_id();
+        let result = parse_worker_id(Layout::DEFAULT.max_worker_id(), &WorkerConfig::default());
         assert!(result.is_err());
         assert!(result.unwrap_err().contains("valid number"));
         std::env::remove_var("WORKER_ID");
@@ -472,8 +1389,9 @@ mod tests {
 
     #[test]
     fn test_parse_worker_id_out_of_range() {
+        let _env = lock_env();
         std::env::set_var("WORKER_ID", "1024");
-        let result = parse_worker_id();
+        let result = parse_worker_id(Layout::DEFAULT.max_worker_id(), &WorkerConfig::default());
         assert!(result.is_err());
         assert!(result.unwrap_err().contains("between 0 and 1023"));
         std::env::remove_var("WORKER_ID");
@@ -481,8 +1399,9 @@ mod tests {
 
     #[test]
     fn test_parse_worker_id_valid() {
+        let _env = lock_env();
         std::env::set_var("WORKER_ID", "512");
-        let result = parse_worker_id();
+        let result = parse_worker_id(Layout::DEFAULT.max_worker_id(), &WorkerConfig::default());
         assert!(result.is_ok());
         assert_eq!(result.unwrap(), 512);
         std::env::remove_var("WORKER_ID");
@@ -490,8 +1409,9 @@ mod tests {
 
     #[test]
     fn test_parse_worker_id_boundary_zero() {
+        let _env = lock_env();
         std::env::set_var("WORKER_ID", "0");
-        let result = parse_worker_id();
+        let result = parse_worker_id(Layout::DEFAULT.max_worker_id(), &WorkerConfig::default());
         assert!(result.is_ok());
         assert_eq!(result.unwrap(), 0);
         std::env::remove_var("WORKER_ID");
@@ -499,67 +1419,1209 @@ mod tests {
 
     #[test]
     fn test_parse_worker_id_boundary_max() {
+        let _env = lock_env();
         std::env::set_var("WORKER_ID", "1023");
-        let result = parse_worker_id();
+        let result = parse_worker_id(Layout::DEFAULT.max_worker_id(), &WorkerConfig::default());
         assert!(result.is_ok());
         assert_eq!(result.unwrap(), 1023);
         std::env::remove_var("WORKER_ID");
     }
 
+    #[test]
+    fn test_parse_worker_id_custom_layout_max() {
+        let _env = lock_env();
+        std::env::set_var("WORKER_ID", "255");
+        let layout = Layout::new(41, 8, 14).unwrap();
+        assert_eq!(parse_worker_id(layout.max_worker_id(), &WorkerConfig::default()), Ok(255));
+
+        std::env::set_var("WORKER_ID", "256");
+        let result = parse_worker_id(layout.max_worker_id(), &WorkerConfig::default());
+        assert!(result.unwrap_err().contains("between 0 and 255"));
+        std::env::remove_var("WORKER_ID");
+    }
+
+    #[test]
+    fn test_parse_worker_id_from_config() {
+        let _env = lock_env();
+        std::env::remove_var("WORKER_ID");
+        std::env::set_var("POD_NAME", "id-generator-5");
+        let max = Layout::DEFAULT.max_worker_id();
+
+        let configured = WorkerConfig { id: Some(9), ..Default::default() };
+        assert_eq!(parse_worker_id(max, &configured), Ok(9), "worker.id wins over POD_NAME");
+
+        let pod_name_disabled = WorkerConfig { id: None, from_pod_name: false };
+        assert!(parse_worker_id(max, &pod_name_disabled).unwrap_err().contains("worker.id"));
+
+        std::env::set_var("WORKER_ID", "3");
+        assert_eq!(parse_worker_id(max, &configured), Ok(3), "WORKER_ID wins over worker.id");
+        assert_eq!(resolve_worker_id(Some(4), max, &configured), Ok(4), "--worker wins over everything");
+
+        std::env::remove_var("WORKER_ID");
+        std::env::remove_var("POD_NAME");
+        let too_large = WorkerConfig { id: Some(300), ..Default::default() };
+        assert!(parse_worker_id(255, &too_large).unwrap_err().contains("between 0 and 255"));
+    }
+
+    #[test]
+    fn test_env_overrides_config_file() {
+        let _env = lock_env();
+        for name in ["CLOCK_DRIFT_POLICY", "CLOCK_DRIFT_MAX_BORROW_MS", "CLOCK_DRIFT_TIMEOUT_MS", "SEQUENCE_BITS", "WORKER_ID_BITS", "TIMESTAMP_BITS", "EPOCH", "EPOCH_MS", "MAX_IDS_PER_REQUEST"] {
+            std::env::remove_var(name);
+        }
+
+        let clock = ClockConfig {
+            drift_policy: DriftPolicyName::Borrow,
+            max_borrow_ms: 10,
+            drift_timeout_ms: 20,
+            ..Default::default()
+        };
+        assert_eq!(parse_drift_policy(&clock), Ok((DriftPolicy::Borrow { max_lead_ms: 10 }, 20)));
+        std::env::set_var("CLOCK_DRIFT_MAX_BORROW_MS", "30");
+        assert_eq!(parse_drift_policy(&clock).unwrap().0, DriftPolicy::Borrow { max_lead_ms: 30 });
+        std::env::set_var("CLOCK_DRIFT_POLICY", "wait");
+        assert_eq!(parse_drift_policy(&clock), Ok((DriftPolicy::Wait, 20)));
+
+        let generator = GeneratorConfig {
+            worker_id_bits: 8,
+            sequence_bits: 14,
+            epoch_ms: Some(1000),
+            ..Default::default()
+        };
+        assert_eq!(parse_layout(&generator), Ok(Layout::new(41, 8, 14).unwrap()));
+        assert_eq!(parse_epoch(&generator), Ok(1000));
+        std::env::set_var("EPOCH_MS", "2000");
+        assert_eq!(parse_epoch(&generator), Ok(2000));
+
+        let limits = LimitsConfig { max_ids_per_request: 100 };
+        assert_eq!(parse_max_ids(&limits), Ok(100));
+        std::env::set_var("MAX_IDS_PER_REQUEST", "0");
+        assert!(parse_max_ids(&limits).unwrap_err().contains("MAX_IDS_PER_REQUEST"));
+
+        for name in ["CLOCK_DRIFT_POLICY", "CLOCK_DRIFT_MAX_BORROW_MS", "EPOCH_MS", "MAX_IDS_PER_REQUEST"] {
+            std::env::remove_var(name);
+        }
+    }
+
+    #[test]
+    fn test_build_state() {
+        let _env = lock_env();
+        for name in ["WORKER_ID", "GRPC_PORT", "MAX_IDS_PER_REQUEST", "LISTEN", "ADMIN_LISTEN", "API_KEYS_FILE", "RATE_LIMIT_IDS_PER_SEC"] {
+            std::env::remove_var(name);
+        }
+
+        let mut config = Config::default();
+        config.worker.id = Some(7);
+        config.limits.max_ids_per_request = 100;
+        let args = ServeArgs { grpc_port: Some(6000), ..Default::default() };
+        let (state, setup) = build_state(&args, &config).unwrap();
+        assert_eq!(state.generator.worker_id(), 7);
+        assert_eq!(state.max_ids_per_request, 100);
+        assert_eq!(setup.grpc_listen.port(), 6000);
+        assert!(setup.tls.is_none() && state.api_keys.is_none());
+
+        // The first invalid setting is reported
+        std::env::set_var("MAX_IDS_PER_REQUEST", "none");
+        assert!(build_state(&args, &config).err().unwrap().contains("MAX_IDS_PER_REQUEST"));
+        std::env::remove_var("MAX_IDS_PER_REQUEST");
+    }
+
+    #[test]
+    fn test_resolve_listeners() {
+        let _env = lock_env();
+        std::env::remove_var("LISTEN");
+        std::env::remove_var("ADMIN_LISTEN");
+        let tcp = |addr: &str| Listener::Tcp(addr.parse().unwrap());
+        let server = ServerConfig {
+            listen: vec![Listener::Unix("/run/id.sock".into()), tcp("127.0.0.1:8080")],
+            ..Default::default()
+        };
+
+        let (listen, admin_listen) = resolve_listeners(&ServeArgs::default(), &server).unwrap();
+        assert_eq!(listen, server.listen);
+        assert!(admin_listen.is_empty());
+
+        std::env::set_var("LISTEN", "[::]:8000, 0.0.0.0:8000");
+        std::env::set_var("ADMIN_LISTEN", "[::]:9090");
+        let (listen, admin_listen) = resolve_listeners(&ServeArgs::default(), &server).unwrap();
+        assert_eq!(listen, [tcp("[::]:8000"), tcp("0.0.0.0:8000")]);
+        assert_eq!(admin_listen, [tcp("[::]:9090")]);
+
+        // --port keeps the address of the first TCP listener
+        std::env::remove_var("LISTEN");
+        let args = ServeArgs { port: Some(7000), ..Default::default() };
+        assert_eq!(resolve_listeners(&args, &server).unwrap().0, [tcp("127.0.0.1:7000")]);
+
+        let args = ServeArgs { listen: vec![tcp("[::]:9090")], ..Default::default() };
+        assert!(resolve_listeners(&args, &server).unwrap_err().contains("both an HTTP and an admin listener"));
+
+        std::env::set_var("ADMIN_LISTEN", "nowhere");
+        assert!(resolve_listeners(&ServeArgs::default(), &server).unwrap_err().starts_with("ADMIN_LISTEN: "));
+        std::env::remove_var("ADMIN_LISTEN");
+    }
+
+    #[actix_web::test]
+    async fn test_admin_routes_are_separate() {
+        let data = test_state();
+        let api = init_service(App::new().app_data(data.clone()).configure(api_routes)).await;
+        let metrics_config = MetricsConfig { path: "/internal/metrics".to_string(), ..Default::default() };
+        let admin = init_service(App::new().app_data(data).configure(|cfg| admin_routes(cfg, &metrics_config))).await;
+
+        for uri in ["/health", "/ready", "/internal/metrics"] {
+            let resp = call_service(&api, TestRequest::get().uri(uri).to_request()).await;
+            assert_eq!(resp.status(), StatusCode::NOT_FOUND, "{} should not be on the API listener", uri);
+            let resp = call_service(&admin, TestRequest::get().uri(uri).to_request()).await;
+            assert_eq!(resp.status(), StatusCode::OK, "{} should be on the admin listener", uri);
+        }
+
+        let resp = call_service(&api, TestRequest::get().uri("/id").to_request()).await;
+        assert_eq!(resp.status(), StatusCode::OK);
+        let resp = call_service(&admin, TestRequest::get().uri("/id").to_request()).await;
+        assert_eq!(resp.status(), StatusCode::NOT_FOUND);
+    }
+
+    #[test]
+    fn test_parse_tls() {
+        let _env = lock_env();
+        for name in ["TLS_CERT_FILE", "TLS_KEY_FILE", "TLS_CLIENT_CA_FILE", "TLS_CLIENT_AUTH", "TLS_RELOAD_INTERVAL_SECS"] {
+            std::env::remove_var(name);
+        }
+        assert_eq!(parse_tls(None), Ok(None));
+
+        let file = TlsConfig {
+            cert_file: "/file/tls.crt".into(),
+            key_file: "/file/tls.key".into(),
+            client_ca_file: Some("/file/ca.crt".into()),
+            client_auth: ClientAuth::Optional,
+            reload_interval_secs: 30,
+        };
+        assert_eq!(parse_tls(Some(&file)), Ok(Some(file.clone())));
+
+        // The certificate from the environment replaces the file's section
+        std::env::set_var("TLS_CERT_FILE", "/env/tls.crt");
+        std::env::set_var("TLS_KEY_FILE", "/env/tls.key");
+        let tls = parse_tls(Some(&file)).unwrap().unwrap();
+        assert_eq!(tls.cert_file, PathBuf::from("/env/tls.crt"));
+        assert_eq!(tls.client_ca_file, None);
+        assert_eq!(tls.client_auth, ClientAuth::Required);
+
+        std::env::set_var("TLS_CLIENT_CA_FILE", "/env/ca.crt");
+        std::env::set_var("TLS_CLIENT_AUTH", "optional");
+        std::env::set_var("TLS_RELOAD_INTERVAL_SECS", "0");
+        let tls = parse_tls(None).unwrap().unwrap();
+        assert_eq!(tls.client_ca_file, Some(PathBuf::from("/env/ca.crt")));
+        assert_eq!(tls.client_auth, ClientAuth::Optional);
+        assert_eq!(tls.reload_interval_secs, 0);
+
+        std::env::set_var("TLS_CLIENT_AUTH", "sometimes");
+        assert!(parse_tls(None).unwrap_err().contains("TLS_CLIENT_AUTH"));
+        std::env::remove_var("TLS_CLIENT_AUTH");
+        std::env::remove_var("TLS_RELOAD_INTERVAL_SECS");
+
+        std::env::remove_var("TLS_KEY_FILE");
+        assert!(parse_tls(None).unwrap_err().contains("must be set together"));
+        std::env::remove_var("TLS_CERT_FILE");
+        assert!(parse_tls(None).unwrap_err().contains("requires TLS_CERT_FILE"));
+        std::env::remove_var("TLS_CLIENT_CA_FILE");
+    }
+
+    #[test]
+    fn test_parse_rate_limit() {
+        let _env = lock_env();
+        for name in ["RATE_LIMIT_IDS_PER_SEC", "RATE_LIMIT_BURST", "RATE_LIMIT_TRUST_FORWARDED_FOR"] {
+            std::env::remove_var(name);
+        }
+        let limiter = parse_rate_limit(&RateLimitConfig::default()).unwrap();
+        assert_eq!(limiter.default, None);
+        assert!(!limiter.trust_forwarded_for);
+
+        let file = RateLimitConfig { ids_per_sec: Some(500.0), burst: Some(5000), trust_forwarded_for: true };
+        let limiter = parse_rate_limit(&file).unwrap();
+        assert_eq!(limiter.default, Some(Limit { ids_per_sec: 500.0, burst: 5000 }));
+        assert!(limiter.trust_forwarded_for);
+
+        std::env::set_var("RATE_LIMIT_IDS_PER_SEC", "2.5");
+        std::env::set_var("RATE_LIMIT_TRUST_FORWARDED_FOR", "false");
+        let limiter = parse_rate_limit(&RateLimitConfig::default()).unwrap();
+        assert_eq!(limiter.default, Some(Limit { ids_per_sec: 2.5, burst: 3 }));
+        assert!(!limiter.trust_forwarded_for);
+
+        std::env::set_var("RATE_LIMIT_IDS_PER_SEC", "0");
+        assert!(parse_rate_limit(&file).err().unwrap().contains("RATE_LIMIT_IDS_PER_SEC"));
+        std::env::remove_var("RATE_LIMIT_IDS_PER_SEC");
+
+        std::env::set_var("RATE_LIMIT_BURST", "100");
+        assert!(parse_rate_limit(&RateLimitConfig::default()).err().unwrap().contains("needs RATE_LIMIT_IDS_PER_SEC"));
+        std::env::set_var("RATE_LIMIT_BURST", "0");
+        assert!(parse_rate_limit(&file).err().unwrap().contains("RATE_LIMIT_BURST"));
+        std::env::remove_var("RATE_LIMIT_BURST");
+
+        std::env::set_var("RATE_LIMIT_TRUST_FORWARDED_FOR", "yes");
+        assert!(parse_rate_limit(&file).err().unwrap().contains("RATE_LIMIT_TRUST_FORWARDED_FOR"));
+        std::env::remove_var("RATE_LIMIT_TRUST_FORWARDED_FOR");
+    }
+
+    #[test]
+    fn test_parse_high_water_mark() {
+        let _env = lock_env();
+        std::env::remove_var("HIGH_WATER_MARK_FILE");
+        std::env::remove_var("HIGH_WATER_MARK_WINDOW_MS");
+        assert_eq!(parse_high_water_mark(None), Ok(None));
+
+        std::env::set_var("HIGH_WATER_MARK_FILE", "/data/mark");
+        let options = parse_high_water_mark(None).unwrap().unwrap();
+        assert_eq!(options, HighWaterMarkOptions::new("/data/mark"));
+
+        std::env::set_var("HIGH_WATER_MARK_WINDOW_MS", "250");
+        assert_eq!(parse_high_water_mark(None).unwrap().unwrap().window_ms, 250);
+
+        std::env::set_var("HIGH_WATER_MARK_WINDOW_MS", "soon");
+        assert!(parse_high_water_mark(None).unwrap_err().contains("HIGH_WATER_MARK_WINDOW_MS"));
+
+        // The environment overrides the file's path and window
+        let configured = HighWaterMarkConfig { file: "/config/mark".into(), window_ms: 500 };
+        std::env::set_var("HIGH_WATER_MARK_WINDOW_MS", "250");
+        assert_eq!(parse_high_water_mark(Some(&configured)).unwrap().unwrap().path, PathBuf::from("/data/mark"));
+        std::env::remove_var("HIGH_WATER_MARK_FILE");
+        assert_eq!(
+            parse_high_water_mark(Some(&configured)),
+            Ok(Some(HighWaterMarkOptions { path: "/config/mark".into(), window_ms: 250 }))
+        );
+        std::env::remove_var("HIGH_WATER_MARK_FILE");
+        std::env::remove_var("HIGH_WATER_MARK_WINDOW_MS");
+    }
+
+    #[test]
+    fn test_parse_clock() {
+        let _env = lock_env();
+        std::env::remove_var("CLOCK_MODE");
+        std::env::remove_var("CLOCK_SLEW_PPM");
+        assert!(parse_clock(&ClockConfig::default()).is_ok());
+
+        std::env::set_var("CLOCK_MODE", "monotonic");
+        let clock = parse_clock(&ClockConfig::default()).unwrap();
+        assert!(clock.unix_millis().abs_diff(id_generator::time::unix_millis()) < 1000);
+
+        std::env::set_var("CLOCK_SLEW_PPM", "1000000");
+        assert!(parse_clock(&ClockConfig::default()).err().unwrap().contains("CLOCK_SLEW_PPM"));
+
+        std::env::remove_var("CLOCK_SLEW_PPM");
+        std::env::set_var("CLOCK_MODE", "atomic");
+        assert!(parse_clock(&ClockConfig::default()).err().unwrap().contains("CLOCK_MODE"));
+        std::env::remove_var("CLOCK_MODE");
+    }
+
+    #[test]
+    fn test_parse_drift_policy() {
+        let _env = lock_env();
+        std::env::remove_var("CLOCK_DRIFT_POLICY");
+        std::env::remove_var("CLOCK_DRIFT_MAX_BORROW_MS");
+        std::env::remove_var("CLOCK_DRIFT_TIMEOUT_MS");
+        assert_eq!(parse_drift_policy(&ClockConfig::default()), Ok((DriftPolicy::Wait, CLOCK_DRIFT_TIMEOUT_MS)));
+
+        std::env::set_var("CLOCK_DRIFT_POLICY", "fail_fast");
+        std::env::set_var("CLOCK_DRIFT_TIMEOUT_MS", "250");
+        assert_eq!(parse_drift_policy(&ClockConfig::default()), Ok((DriftPolicy::FailFast, 250)));
+
+        std::env::set_var("CLOCK_DRIFT_POLICY", "borrow");
+        assert_eq!(parse_drift_policy(&ClockConfig::default()).unwrap().0, DriftPolicy::Borrow { max_lead_ms: DEFAULT_MAX_BORROW_MS });
+        std::env::set_var("CLOCK_DRIFT_MAX_BORROW_MS", "50");
+        assert_eq!(parse_drift_policy(&ClockConfig::default()).unwrap().0, DriftPolicy::Borrow { max_lead_ms: 50 });
+
+        std::env::set_var("CLOCK_DRIFT_TIMEOUT_MS", "soon");
+        assert!(parse_drift_policy(&ClockConfig::default()).unwrap_err().contains("CLOCK_DRIFT_TIMEOUT_MS"));
+
+        std::env::set_var("CLOCK_DRIFT_POLICY", "panic");
+        assert!(parse_drift_policy(&ClockConfig::default()).unwrap_err().contains("CLOCK_DRIFT_POLICY"));
+        std::env::remove_var("CLOCK_DRIFT_POLICY");
+        std::env::remove_var("CLOCK_DRIFT_MAX_BORROW_MS");
+        std::env::remove_var("CLOCK_DRIFT_TIMEOUT_MS");
+    }
+
+    #[test]
+    fn test_parse_max_exhaustions() {
+        let _env = lock_env();
+        std::env::remove_var("READY_MAX_EXHAUSTIONS_PER_SEC");
+        assert_eq!(parse_max_exhaustions(&ReadinessConfig::default()), Ok(DEFAULT_MAX_EXHAUSTIONS_PER_SEC));
+
+        std::env::set_var("READY_MAX_EXHAUSTIONS_PER_SEC", "20.5");
+        assert_eq!(parse_max_exhaustions(&ReadinessConfig::default()), Ok(20.5));
+
+        std::env::set_var("READY_MAX_EXHAUSTIONS_PER_SEC", "-1");
+        assert!(parse_max_exhaustions(&ReadinessConfig::default()).is_err());
+        std::env::remove_var("READY_MAX_EXHAUSTIONS_PER_SEC");
+    }
+
+    #[actix_web::test]
+    async fn test_requests_counted_by_endpoint_and_status() {
+        let counter = |endpoint: &str, status: &str| HTTP_REQUESTS.with_label_values(&[endpoint, status]).get();
+        let (ok, bad_request, unmatched) = (
+            counter("/ids/{count}", "200"),
+            counter("/ids/{count}", "400"),
+            counter("unmatched", "404"),
+        );
+
+        let state = web::Data::new(AppState::with_generator(SnowflakeGenerator::new(1).unwrap()));
+        let app = init_service(
+            App::new()
+                .wrap(from_fn(count_requests))
+                .app_data(state)
+                .service(snowflakes),
+        )
+        .await;
+        for uri in ["/ids/3", "/ids/0", "/nope"] {
+            call_service(&app, TestRequest::get().uri(uri).to_request()).await;
+        }
+
+        // Other tests share the counters, so only check they moved
+        assert!(counter("/ids/{count}", "200") > ok);
+        assert!(counter("/ids/{count}", "400") > bad_request);
+        assert!(counter("unmatched", "404") > unmatched);
+    }
+
+    #[actix_web::test]
+    async fn test_api_keys() {
+        let key = |id: &str, token: &str| ApiKey {
+            id: id.to_string(),
+            secret_sha256: auth::hash_token(token),
+            max_ids_per_request: None,
+            endpoints: None,
+            namespaces: None,
+            ids_per_sec: None,
+            burst: None,
+            client_subject: None,
+        };
+        let keys = ApiKeys::new(vec![
+            ApiKey { max_ids_per_request: Some(10), ..key("billing", "billing-token") },
+            ApiKey {
+                endpoints: Some(vec!["/decode".to_string()]),
+                namespaces: Some(vec!["orders".to_string()]),
+                ..key("orders", "orders-token")
+            },
+            ApiKey {
+                namespaces: Some(vec!["shipping".to_string()]),
+                ..key("shipping", "shipping-token")
+            },
+        ])
+        .unwrap();
+        let mut state = AppState::with_generator(SnowflakeGenerator::new(1).unwrap());
+        state.api_keys = Some(Arc::new(keys));
+        let app = init_service(
+            App::new()
+                .wrap(from_fn(auth::authenticate))
+                .app_data(web::Data::new(state))
+                .configure(|cfg| admin_routes(cfg, &MetricsConfig::default()))
+                .configure(api_routes),
+        )
+        .await;
+        let request = |uri: &str, token: Option<&str>| {
+            let mut req = TestRequest::get().uri(uri);
+            if let Some(token) = token {
+                req = req.insert_header((actix_web::http::header::AUTHORIZATION, format!("Bearer {}", token)));
+            }
+            req.to_request()
+        };
+
+        // Operational endpoints stay open
+        let resp = call_service(&app, request("/health", None)).await;
+        assert_eq!(resp.status(), StatusCode::OK);
+
+        let resp = call_service(&app, request("/id", None)).await;
+        assert_eq!(resp.status(), StatusCode::UNAUTHORIZED);
+        assert_eq!(resp.headers().get("www-authenticate").unwrap(), "Bearer");
+        let body: ErrorResponse = read_body_json(resp).await;
+        assert!(body.error.contains("API key is required"));
+
+        let resp = call_service(&app, request("/id", Some("guess"))).await;
+        assert_eq!(resp.status(), StatusCode::UNAUTHORIZED);
+
+        let resp = call_service(&app, request("/id", Some("billing-token"))).await;
+        assert_eq!(resp.status(), StatusCode::OK);
+        let resp = call_service(&app, request("/ids/10", Some("billing-token"))).await;
+        assert_eq!(resp.status(), StatusCode::OK);
+        let resp = call_service(&app, request("/ids/11", Some("billing-token"))).await;
+        assert_eq!(resp.status(), StatusCode::BAD_REQUEST);
+        let body: ErrorResponse = read_body_json(resp).await;
+        assert_eq!(body.error, "Count must be less than or equal to 10");
+
+        let id = SnowflakeGenerator::new(1).unwrap().next_id().unwrap();
+        let resp = call_service(&app, request("/id?namespace=orders", Some("orders-token"))).await;
+        assert_eq!(resp.status(), StatusCode::FORBIDDEN);
+        let body: ErrorResponse = read_body_json(resp).await;
+        assert_eq!(body.error, "API key 'orders' may not call /id");
+        let resp = call_service(&app, request(&format!("/decode/{}", id), Some("orders-token"))).await;
+        assert_eq!(resp.status(), StatusCode::FORBIDDEN);
+        let resp = call_service(&app, request(&format!("/decode/{}?namespace=orders", id), Some("orders-token"))).await;
+        assert_eq!(resp.status(), StatusCode::OK);
+
+        // A key limited to namespaces can't skip the check by leaving the
+        // parameter out, even on endpoints it may call
+        let resp = call_service(&app, request("/id", Some("shipping-token"))).await;
+        assert_eq!(resp.status(), StatusCode::FORBIDDEN);
+        let body: ErrorResponse = read_body_json(resp).await;
+        assert_eq!(body.error, "API key 'shipping' must name a namespace");
+        let resp = call_service(&app, request("/ids/5?namespace=", Some("shipping-token"))).await;
+        assert_eq!(resp.status(), StatusCode::FORBIDDEN);
+        let resp = call_service(&app, request("/id?namespace=orders", Some("shipping-token"))).await;
+        assert_eq!(resp.status(), StatusCode::FORBIDDEN);
+        let body: ErrorResponse = read_body_json(resp).await;
+        assert_eq!(body.error, "API key 'shipping' may not use namespace 'orders'");
+        let resp = call_service(&app, request("/id?namespace=shipping", Some("shipping-token"))).await;
+        assert_eq!(resp.status(), StatusCode::OK);
+    }
+
+    #[actix_web::test]
+    async fn test_rate_limit() {
+        let keys = ApiKeys::new(vec![ApiKey {
+            id: "bulk".to_string(),
+            secret_sha256: auth::hash_token("bulk-token"),
+            max_ids_per_request: None,
+            endpoints: None,
+            namespaces: None,
+            ids_per_sec: Some(1000.0),
+            burst: None,
+            client_subject: None,
+        }])
+        .unwrap();
+        let mut state = AppState::with_generator(SnowflakeGenerator::new(1).unwrap());
+        state.rate_limiter = RateLimiter::new(Some(Limit::new(10.0, None)), false);
+        let app = init_service(App::new().app_data(web::Data::new(state)).configure(api_routes)).await;
+        let request = |uri: &str, ip: &str| {
+            TestRequest::get().uri(uri).peer_addr(format!("{}:40000", ip).parse().unwrap()).to_request()
+        };
+        let throttled = || THROTTLED_REQUESTS.with_label_values(&["ip"]).get();
+        let before = throttled();
+
+        // Limits count IDs, so one batch empties the bucket
+        let resp = call_service(&app, request("/ids/10", "10.0.0.1")).await;
+        assert_eq!(resp.status(), StatusCode::OK);
+        let resp = call_service(&app, request("/id", "10.0.0.1")).await;
+        assert_eq!(resp.status(), StatusCode::TOO_MANY_REQUESTS);
+        assert_eq!(resp.headers().get("retry-after").unwrap(), "1");
+        let body: ErrorResponse = read_body_json(resp).await;
+        assert_eq!(body.error, "Rate limit exceeded, retry in 1 s");
+        assert!(throttled() > before);
+        // Addresses are never labels
+        let families = prometheus::core::Collector::collect(&*THROTTLED_REQUESTS);
+        let labels: Vec<&str> = families[0].get_metric().iter().map(|metric| metric.get_label()[0].get_value()).collect();
+        assert!(labels.iter().all(|label| !label.starts_with("ip:")), "{:?}", labels);
+
+        // Other clients have their own buckets
+        let resp = call_service(&app, request("/id", "10.0.0.2")).await;
+        assert_eq!(resp.status(), StatusCode::OK);
+
+        let before = throttled();
+        let resp = call_service(&app, request("/ids/11", "10.0.0.3")).await;
+        assert_eq!(resp.status(), StatusCode::BAD_REQUEST);
+        let body: ErrorResponse = read_body_json(resp).await;
+        assert_eq!(body.error, "Count must be less than or equal to 10, the rate limit burst");
+        assert!(throttled() > before);
+
+        // A key's own limit replaces the default
+        let mut state = AppState::with_generator(SnowflakeGenerator::new(1).unwrap());
+        state.api_keys = Some(Arc::new(keys));
+        state.rate_limiter = RateLimiter::new(Some(Limit::new(10.0, None)), false);
+        let app = init_service(
+            App::new().wrap(from_fn(auth::authenticate)).app_data(web::Data::new(state)).configure(api_routes),
+        )
+        .await;
+        let resp = call_service(
+            &app,
+            TestRequest::get()
+                .uri("/ids/1000")
+                .insert_header((actix_web::http::header::AUTHORIZATION, "Bearer bulk-token"))
+                .to_request(),
+        )
+        .await;
+        assert_eq!(resp.status(), StatusCode::OK);
+    }
+
+    #[actix_web::test]
+    async fn test_ready() {
+        let clock = Arc::new(id_generator::clock::ManualClock::new(UNIX_EPOCH_OFFSET + 1_000_000));
+        let state = web::Data::new(AppState::new(1, GeneratorOptions::default(), clock.clone()).unwrap());
+        let app = init_service(App::new().app_data(state.clone()).service(ready)).await;
+
+        let req = TestRequest::get().uri("/ready").to_request();
+        let resp = call_service(&app, req).await;
+        assert_eq!(resp.status(), StatusCode::OK);
+        let body: ReadyResponse = read_body_json(resp).await;
+        assert_eq!(body.status, "ready");
+        assert!(body.reasons.is_empty());
+
+        state.generator.next_id().unwrap();
+        clock.rewind(1000);
+
+        let req = TestRequest::get().uri("/ready").to_request();
+        let resp = call_service(&app, req).await;
+        assert_eq!(resp.status(), StatusCode::SERVICE_UNAVAILABLE);
+        let body: ReadyResponse = read_body_json(resp).await;
+        assert_eq!(body.status, "not_ready");
+        assert_eq!(body.reasons[0].check, "clock");
+    }
+
+    #[test]
+    fn test_parse_grpc_port() {
+        let _env = lock_env();
+        std::env::remove_var("GRPC_PORT");
+        assert_eq!(parse_grpc_port(DEFAULT_GRPC_PORT), Ok(DEFAULT_GRPC_PORT));
+
+        std::env::set_var("GRPC_PORT", "9090");
+        assert_eq!(parse_grpc_port(DEFAULT_GRPC_PORT), Ok(9090));
+
+        std::env::set_var("GRPC_PORT", "70000");
+        assert!(parse_grpc_port(DEFAULT_GRPC_PORT).unwrap_err().contains("GRPC_PORT"));
+        std::env::remove_var("GRPC_PORT");
+    }
+
+    #[test]
+    fn test_parse_layout_default() {
+        let _env = lock_env();
+        std::env::remove_var("TIMESTAMP_BITS");
+        std::env::remove_var("WORKER_ID_BITS");
+        std::env::remove_var("SEQUENCE_BITS");
+        assert_eq!(parse_layout(&GeneratorConfig::default()), Ok(Layout::DEFAULT));
+    }
+
+    #[test]
+    fn test_parse_layout_custom() {
+        let _env = lock_env();
+        std::env::set_var("WORKER_ID_BITS", "8");
+        std::env::set_var("SEQUENCE_BITS", "14");
+        let result = parse_layout(&GeneratorConfig::default());
+        std::env::remove_var("WORKER_ID_BITS");
+        std::env::remove_var("SEQUENCE_BITS");
+
+        let layout = result.unwrap();
+        assert_eq!(layout.worker_id_bits(), 8);
+        assert_eq!(layout.sequence_bits(), 14);
+    }
+
+    #[test]
+    fn test_parse_layout_invalid_sum() {
+        let _env = lock_env();
+        std::env::set_var("SEQUENCE_BITS", "14");
+        let result = parse_layout(&GeneratorConfig::default());
+        std::env::remove_var("SEQUENCE_BITS");
+        assert!(result.unwrap_err().contains("sum to 63 or 64"));
+    }
+
+    #[test]
+    fn test_parse_epoch_default() {
+        let _env = lock_env();
+        std::env::remove_var("EPOCH_MS");
+        std::env::remove_var("EPOCH");
+        assert_eq!(parse_epoch(&GeneratorConfig::default()), Ok(UNIX_EPOCH_OFFSET));
+    }
+
+    #[test]
+    fn test_parse_epoch_ms() {
+        let _env = lock_env();
+        std::env::set_var("EPOCH_MS", "1288834974657");
+        let result = parse_epoch(&GeneratorConfig::default());
+        std::env::remove_var("EPOCH_MS");
+        assert_eq!(result, Ok(1288834974657));
+    }
+
+    #[test]
+    fn test_parse_epoch_date() {
+        let _env = lock_env();
+        std::env::set_var("EPOCH", "2020-01-01");
+        let result = parse_epoch(&GeneratorConfig::default());
+        std::env::remove_var("EPOCH");
+        assert_eq!(result, Ok(1_577_836_800_000));
+    }
+
+    #[test]
+    fn test_parse_epoch_invalid() {
+        let _env = lock_env();
+        std::env::set_var("EPOCH", "yesterday");
+        let result = parse_epoch(&GeneratorConfig::default());
+        assert!(result.unwrap_err().contains("UTC date"));
+
+        std::env::set_var("EPOCH_MS", "0");
+        let result = parse_epoch(&GeneratorConfig::default());
+        std::env::remove_var("EPOCH");
+        std::env::remove_var("EPOCH_MS");
+        assert!(result.unwrap_err().contains("Only one"));
+    }
+
     #[test]
     fn test_parse_workers_default() {
+        let _env = lock_env();
         std::env::remove_var("WORKERS");
-        let workers = parse_workers();
+        let workers = parse_workers(1);
         assert_eq!(workers, 1);
     }
 
     #[test]
     fn test_parse_workers_custom() {
+        let _env = lock_env();
         std::env::set_var("WORKERS", "4");
-        let workers = parse_workers();
+        let workers = parse_workers(1);
         assert_eq!(workers, 4);
         std::env::remove_var("WORKERS");
     }
 
     #[test]
     fn test_parse_workers_invalid_falls_back() {
+        let _env = lock_env();
         std::env::set_var("WORKERS", "invalid");
-        let workers = parse_workers();
+        let workers = parse_workers(1);
         assert_eq!(workers, 1);
         std::env::remove_var("WORKERS");
     }
 
-    #[test]
-    fn test_error_response_new() {
-        let err = ErrorResponse::new("test error");
-        assert_eq!(err.error, "test error");
+    #[derive(Deserialize)]
+    struct Bulk {
+        ids: Vec<String>,
     }
 
-    #[test]
-    fn test_snowflake_error_display() {
-        assert_eq!(
-            SnowflakeError::MutexPoisoned.to_string(),
-            "Internal state error"
-        );
-        assert_eq!(
-            SnowflakeError::ClockDriftTimeout.to_string(),
-            "Clock drift timeout exceeded"
-        );
+    fn test_state() -> web::Data<AppState> {
+        web::Data::new(AppState::with_generator(SnowflakeGenerator::new(1).unwrap()))
+    }
+
+    #[actix_web::test]
+    async fn test_errors_with_manual_clock() {
+        let clock = Arc::new(id_generator::clock::ManualClock::new(UNIX_EPOCH_OFFSET + 1_000_000));
+        let state = web::Data::new(AppState::new(1, GeneratorOptions::default(), clock.clone()).unwrap());
+        let app = init_service(App::new().app_data(state).service(snowflake).service(decode)).await;
+
+        let req = TestRequest::get().uri("/id").to_request();
+        let body: Id = call_and_read_body_json(&app, req).await;
+
+        // An ID from the future, as seen after a backward clock step
+        clock.rewind(10);
+        let req = TestRequest::get().uri(&format!("/decode/{}", body.id)).to_request();
+        let resp = call_service(&app, req).await;
+        assert_eq!(resp.status(), StatusCode::BAD_REQUEST);
+
+        let req = TestRequest::get().uri("/id").to_request();
+        let resp = call_service(&app, req).await;
+        assert_eq!(resp.status(), StatusCode::INTERNAL_SERVER_ERROR);
+        let err: ErrorResponse = read_body_json(resp).await;
+        assert_eq!(err.error, "Clock drift timeout exceeded");
+    }
+
+    #[actix_web::test]
+    async fn test_rate_limit_refunded_on_errors() {
+        let clock = Arc::new(id_generator::clock::ManualClock::new(UNIX_EPOCH_OFFSET + 1_000_000));
+        let mut state = AppState::new(1, GeneratorOptions::default(), clock.clone()).unwrap();
+        state.rate_limiter = RateLimiter::new(Some(Limit::new(0.001, Some(2))), false);
+        let app = init_service(App::new().app_data(web::Data::new(state)).configure(api_routes)).await;
+
+        let req = TestRequest::get().uri("/id").to_request();
+        assert_eq!(call_service(&app, req).await.status(), StatusCode::OK);
+
+        // Failed requests hand their IDs back
+        clock.rewind(10);
+        for uri in ["/id", "/ids/1", "/id"] {
+            let req = TestRequest::get().uri(uri).to_request();
+            assert_eq!(call_service(&app, req).await.status(), StatusCode::INTERNAL_SERVER_ERROR);
+        }
+
+        clock.advance(20);
+        let req = TestRequest::get().uri("/id").to_request();
+        assert_eq!(call_service(&app, req).await.status(), StatusCode::OK);
+        let req = TestRequest::get().uri("/id").to_request();
+        assert_eq!(call_service(&app, req).await.status(), StatusCode::TOO_MANY_REQUESTS);
+    }
+
+    #[actix_web::test]
+    async fn test_snowflakes_streams_json() {
+        let app = init_service(App::new().app_data(test_state()).service(snowflakes)).await;
+
+        let req = TestRequest::get().uri("/ids/10000").to_request();
+        let bulk: Bulk = call_and_read_body_json(&app, req).await;
+
+        assert_eq!(bulk.ids.len(), 10_000);
+        let ids: Vec<u64> = bulk.ids.iter().map(|s| s.parse().unwrap()).collect();
+        for i in 1..ids.len() {
+            assert!(ids[i] > ids[i-1], "IDs should be monotonically increasing");
+        }
+    }
+
+    #[actix_web::test]
+    async fn test_snowflakes_single_chunk() {
+        let app = init_service(App::new().app_data(test_state()).service(snowflakes)).await;
+
+        let req = TestRequest::get().uri("/ids/1").to_request();
+        let body = call_and_read_body(&app, req).await;
+
+        let bulk: Bulk = serde_json::from_slice(&body).unwrap();
+        assert_eq!(bulk.ids.len(), 1);
+    }
+
+    #[derive(Deserialize)]
+    struct Segment {
+        timestamp: u64,
+        worker_id: u64,
+        seq_start: u64,
+        seq_end: u64,
+    }
+
+    #[derive(Deserialize)]
+    struct CompactBulk {
+        layout: serde_json::Value,
+        epoch_ms: u64,
+        ranges: Vec<Segment>,
+    }
+
+    #[actix_web::test]
+    async fn test_snowflakes_compact() {
+        let state = test_state();
+        let app = init_service(App::new().app_data(state.clone()).service(snowflakes)).await;
+
+        let req = TestRequest::get().uri("/ids/10000?compact=true").to_request();
+        let bulk: CompactBulk = call_and_read_body_json(&app, req).await;
+
+        assert_eq!(bulk.layout["sequence_bits"], 12);
+        assert_eq!(bulk.epoch_ms, UNIX_EPOCH_OFFSET);
+
+        // Expanding the segments should give the same kind of IDs as the full response
+        let layout = state.generator.layout();
+        let mut ids = Vec::new();
+        for segment in &bulk.ranges {
+            assert_eq!(segment.worker_id, 1);
+            for sequence in segment.seq_start..=segment.seq_end {
+                ids.push(layout.format(segment.worker_id, sequence, segment.timestamp));
+            }
+        }
+        assert_eq!(ids.len(), 10_000);
+        for i in 1..ids.len() {
+            assert!(ids[i] > ids[i-1], "IDs should be monotonically increasing");
+        }
+        assert!(bulk.ranges.len() < 100, "Segments should cover many IDs each");
+    }
+
+    #[actix_web::test]
+    async fn test_snowflakes_invalid_query() {
+        let app = init_service(
+            App::new().app_data(test_state()).app_data(query_config()).service(snowflakes),
+        )
+        .await;
+
+        let req = TestRequest::get().uri("/ids/10?compact=maybe").to_request();
+        let resp = call_service(&app, req).await;
+        assert_eq!(resp.status(), StatusCode::BAD_REQUEST);
+
+        let err: ErrorResponse = read_body_json(resp).await;
+        assert!(!err.error.is_empty());
+    }
+
+    #[actix_web::test]
+    async fn test_snowflake_format() {
+        let app = init_service(App::new().app_data(test_state()).service(snowflake)).await;
+
+        for encoding in Encoding::ALL {
+            let req = TestRequest::get().uri(&format!("/id?format={}", encoding.name())).to_request();
+            let id: Id = call_and_read_body_json(&app, req).await;
+            assert!(encoding.decode(&id.id).is_some(), "{:?} {}", encoding, id.id);
+            if let Some(width) = encoding.width() {
+                assert_eq!(id.id.len(), width);
+            }
+        }
+    }
+
+    #[actix_web::test]
+    async fn test_snowflakes_format_sorts_lexicographically() {
+        let app = init_service(App::new().app_data(test_state()).service(snowflakes)).await;
+
+        let req = TestRequest::get().uri("/ids/5000?format=base62").to_request();
+        let bulk: Bulk = call_and_read_body_json(&app, req).await;
+
+        assert_eq!(bulk.ids.len(), 5000);
+        for i in 1..bulk.ids.len() {
+            assert!(bulk.ids[i] > bulk.ids[i-1], "Encoded IDs should sort in generation order");
+        }
+    }
+
+    #[actix_web::test]
+    async fn test_snowflakes_format_with_compact_rejected() {
+        let app = init_service(App::new().app_data(test_state()).service(snowflakes)).await;
+
+        let req = TestRequest::get().uri("/ids/10?compact=true&format=hex").to_request();
+        let resp = call_service(&app, req).await;
+        assert_eq!(resp.status(), StatusCode::BAD_REQUEST);
+    }
+
+    #[actix_web::test]
+    async fn test_decode_accepts_encodings() {
+        let state = test_state();
+        let id = state.generator.next_id().unwrap();
+        let app = init_service(App::new().app_data(state).service(decode)).await;
+
+        for encoding in Encoding::ALL {
+            let uri = format!("/decode/{}", encoding.encode(id));
+            let decoded: DecodeResponse = call_and_read_body_json(&app, TestRequest::get().uri(&uri).to_request()).await;
+            assert_eq!(decoded.id, id.to_string());
+            assert_eq!(decoded.worker_id, 1);
+
+            let uri = format!("/decode/{}?format={}", encoding.encode(id), encoding.name());
+            let decoded: DecodeResponse = call_and_read_body_json(&app, TestRequest::get().uri(&uri).to_request()).await;
+            assert_eq!(decoded.id, id.to_string());
+        }
+
+        let req = TestRequest::get().uri("/decode/not-an-id").to_request();
+        let resp = call_service(&app, req).await;
+        assert_eq!(resp.status(), StatusCode::BAD_REQUEST);
+    }
+
+    #[actix_web::test]
+    async fn test_decode_digit_only_hex_needs_format() {
+        let app = init_service(App::new().app_data(test_state()).service(decode)).await;
+        // A valid ID whose hex form has no letters, so it reads as decimal
+        let id: u64 = 0x0123456789012345;
+        let hex = Encoding::Hex.encode(id);
+
+        let uri = format!("/decode/{}", hex);
+        let decoded: DecodeResponse = call_and_read_body_json(&app, TestRequest::get().uri(&uri).to_request()).await;
+        assert_eq!(decoded.id, "123456789012345");
+
+        let uri = format!("/decode/{}?format=hex", hex);
+        let decoded: DecodeResponse = call_and_read_body_json(&app, TestRequest::get().uri(&uri).to_request()).await;
+        assert_eq!(decoded.id, id.to_string());
+    }
+
+    #[derive(Deserialize)]
+    struct NumericBulk {
+        ids: Vec<u64>,
+    }
+
+    fn js_safe_state() -> web::Data<AppState> {
+        web::Data::new(AppState::with_generator(js_unsafe_generator()))
+    }
+
+    fn js_unsafe_generator() -> SnowflakeGenerator {
+        // A 1970 epoch with a 42-bit timestamp gives IDs well above 2^53
+        let options = GeneratorOptions {
+            layout: Layout::new(42, 10, 12).unwrap(),
+            epoch_ms: 0,
+            ..Default::default()
+        };
+        SnowflakeGenerator::with_options(1, options).unwrap()
+    }
+
+    fn small_id_state() -> web::Data<AppState> {
+        // An epoch a second ago keeps IDs far below 2^53
+        let options = GeneratorOptions {
+            epoch_ms: id_generator::time::unix_millis() - 1000,
+            ..Default::default()
+        };
+        web::Data::new(AppState::with_generator(SnowflakeGenerator::with_options(1, options).unwrap()))
+    }
+
+    #[actix_web::test]
+    async fn test_snowflake_numeric() {
+        let state = test_state();
+        let app = init_service(App::new().app_data(state).service(snowflake)).await;
+
+        let req = TestRequest::get().uri("/id?numeric=true").to_request();
+        let id: NumericId = call_and_read_body_json(&app, req).await;
+        assert!(id.id > 0);
+
+        let req = TestRequest::get().uri("/id?numeric=true&format=hex").to_request();
+        let resp = call_service(&app, req).await;
+        assert_eq!(resp.status(), StatusCode::BAD_REQUEST);
+    }
+
+    #[actix_web::test]
+    async fn test_snowflake_js_safe() {
+        let app = init_service(App::new().app_data(js_safe_state()).service(snowflake)).await;
+        let req = TestRequest::get().uri("/id?numeric=js_safe").to_request();
+        let resp = call_service(&app, req).await;
+        assert_eq!(resp.status(), StatusCode::UNPROCESSABLE_ENTITY);
+        let err: ErrorResponse = read_body_json(resp).await;
+        assert!(err.error.contains("2^53"));
+
+        // Refused before an ID or a rate limit token is taken
+        let mut state = AppState::with_generator(js_unsafe_generator());
+        state.rate_limiter = RateLimiter::new(Some(Limit::new(0.001, Some(1))), false);
+        let state = web::Data::new(state);
+        let app = init_service(App::new().app_data(state.clone()).service(snowflake)).await;
+        for _ in 0..2 {
+            let req = TestRequest::get().uri("/id?numeric=js_safe").to_request();
+            assert_eq!(call_service(&app, req).await.status(), StatusCode::UNPROCESSABLE_ENTITY);
+        }
+        assert_eq!(state.generator.last_timestamp(), 0);
+        let req = TestRequest::get().uri("/id").to_request();
+        assert_eq!(call_service(&app, req).await.status(), StatusCode::OK);
+
+        let app = init_service(App::new().app_data(small_id_state()).service(snowflake)).await;
+        let req = TestRequest::get().uri("/id?numeric=js_safe").to_request();
+        let id: NumericId = call_and_read_body_json(&app, req).await;
+        assert!(id.id <= JS_MAX_SAFE_INTEGER);
+    }
+
+    #[actix_web::test]
+    async fn test_snowflakes_numeric() {
+        let app = init_service(App::new().app_data(test_state()).service(snowflakes)).await;
+
+        let req = TestRequest::get().uri("/ids/5000?numeric=true").to_request();
+        let bulk: NumericBulk = call_and_read_body_json(&app, req).await;
+
+        assert_eq!(bulk.ids.len(), 5000);
+        for i in 1..bulk.ids.len() {
+            assert!(bulk.ids[i] > bulk.ids[i-1], "IDs should be monotonically increasing");
+        }
+
+        let app = init_service(App::new().app_data(js_safe_state()).service(snowflakes)).await;
+        let req = TestRequest::get().uri("/ids/10?numeric=js_safe").to_request();
+        let resp = call_service(&app, req).await;
+        assert_eq!(resp.status(), StatusCode::UNPROCESSABLE_ENTITY);
+    }
+
+    #[actix_web::test]
+    async fn test_snowflakes_js_safe_bounds_whole_request() {
+        // Half a second of timestamps left below 2^53 with the default layout
+        let js_safe_ms = 1 << (53 - 22);
+        let options = GeneratorOptions {
+            epoch_ms: id_generator::time::unix_millis() - js_safe_ms + 500,
+            ..Default::default()
+        };
+        let state = web::Data::new(AppState::with_generator(SnowflakeGenerator::with_options(1, options).unwrap()));
+        let app = init_service(App::new().app_data(state).service(snowflakes)).await;
+
+        let req = TestRequest::get().uri("/ids/10?numeric=js_safe").to_request();
+        let bulk: NumericBulk = call_and_read_body_json(&app, req).await;
+        assert!(bulk.ids.iter().all(|id| *id <= JS_MAX_SAFE_INTEGER));
+
+        // A thousand milliseconds' worth would cross it, so nothing is sent
+        let req = TestRequest::get().uri("/ids/4096000?numeric=js_safe").to_request();
+        let resp = call_service(&app, req).await;
+        assert_eq!(resp.status(), StatusCode::UNPROCESSABLE_ENTITY);
+        let err: ErrorResponse = read_body_json(resp).await;
+        assert!(err.error.contains("could reach"), "{}", err.error);
+    }
+
+    #[actix_web::test]
+    async fn test_stream_ranges_ends_with_error_trailer() {
+        let state = test_state();
+        let first = state.generator.next_range(4096).unwrap();
+
+        for format in [BulkFormat::Json, BulkFormat::Ndjson] {
+            let write_ids = move |range: &IdRange, is_first: bool| {
+                if is_first {
+                    format.write_range(IdOutput::Number, range, is_first)
+                } else {
+                    Err("too large".to_string())
+                }
+            };
+            let trailer = move |message: &str| format.error_trailer(message);
+            let chunks: Vec<_> =
+                stream_ranges(state.clone(), first, 10_000, format.prefix(10_000), format.suffix(), write_ids, trailer)
+                    .collect()
+                    .await;
+            let body: Vec<u8> = chunks.into_iter().flat_map(|chunk| chunk.unwrap()).collect();
+            let last_line = body.split(|b| *b == b'\n').rfind(|line| !line.is_empty()).unwrap();
+            let value: serde_json::Value = match format {
+                BulkFormat::Json => serde_json::from_slice(&body).unwrap(),
+                _ => serde_json::from_slice(last_line).unwrap(),
+            };
+            assert_eq!(value["error"], "too large");
+        }
+    }
+
+    #[actix_web::test]
+    async fn test_stream_ranges_ends_with_generator_error() {
+        for format in [BulkFormat::Json, BulkFormat::Ndjson] {
+            let clock = Arc::new(id_generator::clock::ManualClock::new(UNIX_EPOCH_OFFSET + 1_000_000));
+            let state = web::Data::new(AppState::new(1, GeneratorOptions::default(), clock.clone()).unwrap());
+            let first = state.generator.next_range(10_000).unwrap();
+            // The next range waits out a backward step and times out
+            clock.rewind(10);
+
+            let write_ids = move |range: &IdRange, is_first: bool| format.write_range(IdOutput::Number, range, is_first);
+            let trailer = move |message: &str| format.error_trailer(message);
+            let chunks: Vec<_> =
+                stream_ranges(state.clone(), first, 10_000, format.prefix(10_000), format.suffix(), write_ids, trailer)
+                    .collect()
+                    .await;
+            let body: Vec<u8> = chunks.into_iter().flat_map(|chunk| chunk.unwrap()).collect();
+            let last_line = body.split(|b| *b == b'\n').rfind(|line| !line.is_empty()).unwrap();
+            let value: serde_json::Value = match format {
+                BulkFormat::Json => serde_json::from_slice(&body).unwrap(),
+                _ => serde_json::from_slice(last_line).unwrap(),
+            };
+            assert_eq!(value["error"], "Clock drift timeout exceeded");
+            if format == BulkFormat::Json {
+                assert_eq!(value["ids"].as_array().unwrap().len() as u64, first.count);
+            }
+        }
+    }
+
+    async fn get_ids(state: web::Data<AppState>, uri: &str, accept: &str) -> (StatusCode, String, web::Bytes) {
+        let app = init_service(App::new().app_data(state).service(snowflakes)).await;
+        let req = TestRequest::get()
+            .uri(uri)
+            .insert_header(("Accept", accept))
+            .to_request();
+        let resp = call_service(&app, req).await;
+        let status = resp.status();
+        let content_type = resp
+            .headers()
+            .get("Content-Type")
+            .map(|v| v.to_str().unwrap().to_string())
+            .unwrap_or_default();
+        (status, content_type, actix_web::test::read_body(resp).await)
+    }
+
+    fn assert_increasing(ids: &[u64], count: usize) {
+        assert_eq!(ids.len(), count);
+        for i in 1..ids.len() {
+            assert!(ids[i] > ids[i-1], "IDs should be monotonically increasing");
+        }
     }
 
     #[test]
-    fn test_sequence_overflow_handling() {
-        let sequence = Mutex::new(SEQUENCE_MASK); // Start at max
-        let timestamp = Mutex::new(0);
+    fn test_bulk_format_negotiation() {
+        let negotiate = |header: &str| {
+            use actix_web::http::header::Header;
+            let req = TestRequest::default().insert_header(("Accept", header)).to_http_request();
+            let accept = Accept::parse(&req).unwrap();
+            BulkFormat::negotiate(Some(&accept))
+        };
+
+        assert_eq!(BulkFormat::negotiate(None), Some(BulkFormat::Json));
+        assert_eq!(negotiate("*/*"), Some(BulkFormat::Json));
+        assert_eq!(negotiate("application/x-ndjson"), Some(BulkFormat::Ndjson));
+        assert_eq!(negotiate("text/csv"), Some(BulkFormat::Csv));
+        assert_eq!(negotiate("application/octet-stream"), Some(BulkFormat::Binary { big_endian: false }));
+        assert_eq!(negotiate("application/octet-stream; endian=big"), Some(BulkFormat::Binary { big_endian: true }));
+        assert_eq!(negotiate("application/octet-stream; endian=middle"), None);
+        assert_eq!(negotiate("application/msgpack"), Some(BulkFormat::MessagePack));
+        assert_eq!(negotiate("application/x-protobuf"), Some(BulkFormat::Protobuf));
+        assert_eq!(negotiate("text/html, application/msgpack;q=0.5, text/csv;q=0.9"), Some(BulkFormat::Csv));
+        assert_eq!(negotiate("image/png"), None);
+    }
 
-        // This should trigger sequence overflow handling
-        let result = generate_snowflakes(1, &sequence, &timestamp, 2);
+    #[actix_web::test]
+    async fn test_snowflakes_not_acceptable() {
+        let (status, content_type, body) = get_ids(test_state(), "/ids/10", "image/png").await;
+        assert_eq!(status, StatusCode::NOT_ACCEPTABLE);
+        assert_eq!(content_type, "application/json");
+        let err: ErrorResponse = serde_json::from_slice(&body).unwrap();
+        assert!(err.error.contains("application/msgpack"));
+    }
 
-        assert!(result.is_ok());
-        let ids = result.unwrap();
-        assert_eq!(ids.len(), 2);
+    #[actix_web::test]
+    async fn test_snowflakes_binary() {
+        let (status, content_type, body) = get_ids(test_state(), "/ids/5000", "application/octet-stream").await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(content_type, "application/octet-stream; endian=little");
+        let ids: Vec<u64> = body.chunks(8).map(|c| u64::from_le_bytes(c.try_into().unwrap())).collect();
+        assert_increasing(&ids, 5000);
+
+        let (_, _, body) = get_ids(test_state(), "/ids/5000", "application/octet-stream; endian=big").await;
+        let ids: Vec<u64> = body.chunks(8).map(|c| u64::from_be_bytes(c.try_into().unwrap())).collect();
+        assert_increasing(&ids, 5000);
+    }
+
+    #[actix_web::test]
+    async fn test_snowflakes_ndjson() {
+        let (status, _, body) = get_ids(test_state(), "/ids/5000", "application/x-ndjson").await;
+        assert_eq!(status, StatusCode::OK);
+        let ids: Vec<u64> = std::str::from_utf8(&body)
+            .unwrap()
+            .lines()
+            .map(|line| serde_json::from_str::<String>(line).unwrap().parse().unwrap())
+            .collect();
+        assert_increasing(&ids, 5000);
+
+        let (_, _, body) = get_ids(test_state(), "/ids/10?numeric=true", "application/x-ndjson").await;
+        let ids: Vec<u64> = std::str::from_utf8(&body).unwrap().lines().map(|l| l.parse().unwrap()).collect();
+        assert_increasing(&ids, 10);
+    }
+
+    #[actix_web::test]
+    async fn test_snowflakes_csv() {
+        let (status, _, body) = get_ids(test_state(), "/ids/5000?format=hex", "text/csv").await;
+        assert_eq!(status, StatusCode::OK);
+        let text = std::str::from_utf8(&body).unwrap();
+        let mut lines = text.lines();
+        assert_eq!(lines.next(), Some("id"));
+        let ids: Vec<u64> = lines.map(|l| Encoding::Hex.decode(l).unwrap()).collect();
+        assert_increasing(&ids, 5000);
+
+        let (status, _, _) = get_ids(test_state(), "/ids/10?numeric=true", "text/csv").await;
+        assert_eq!(status, StatusCode::BAD_REQUEST);
+    }
+
+    #[actix_web::test]
+    async fn test_snowflakes_msgpack() {
+        for count in [3usize, 300, 70_000] {
+            let (status, _, body) = get_ids(test_state(), &format!("/ids/{}", count), "application/msgpack").await;
+            assert_eq!(status, StatusCode::OK);
+
+            let (len, header) = match body[0] {
+                b if b & 0xf0 == 0x90 => ((b & 0x0f) as usize, 1),
+                0xdc => (u16::from_be_bytes([body[1], body[2]]) as usize, 3),
+                0xdd => (u32::from_be_bytes([body[1], body[2], body[3], body[4]]) as usize, 5),
+                b => panic!("Unexpected array header {:#x}", b),
+            };
+            assert_eq!(len, count);
+            let ids: Vec<u64> = body[header..]
+                .chunks(9)
+                .map(|c| {
+                    assert_eq!(c[0], 0xcf);
+                    u64::from_be_bytes(c[1..].try_into().unwrap())
+                })
+                .collect();
+            assert_increasing(&ids, count);
+        }
+    }
+
+    #[actix_web::test]
+    async fn test_snowflakes_protobuf() {
+        let (status, _, body) = get_ids(test_state(), "/ids/5000", "application/x-protobuf").await;
+        assert_eq!(status, StatusCode::OK);
+
+        assert_eq!(body[0], 0x0a);
+        // 40000 as a varint
+        assert_eq!(&body[1..4], &[0xc0, 0xb8, 0x02]);
+        let ids: Vec<u64> = body[4..].chunks(8).map(|c| u64::from_le_bytes(c.try_into().unwrap())).collect();
+        assert_increasing(&ids, 5000);
+    }
+
+    #[actix_web::test]
+    async fn test_snowflakes_binary_rejects_format() {
+        let (status, _, _) = get_ids(test_state(), "/ids/10?format=hex", "application/octet-stream").await;
+        assert_eq!(status, StatusCode::BAD_REQUEST);
+
+        let (status, _, _) = get_ids(test_state(), "/ids/10?compact=true", "application/msgpack").await;
+        assert_eq!(status, StatusCode::BAD_REQUEST);
+    }
 
-        // Both should be valid and unique
-        assert_ne!(ids[0], ids[1]);
+    #[actix_web::test]
+    async fn test_snowflakes_rejects_invalid_count() {
+        let app = init_service(App::new().app_data(test_state()).service(snowflakes)).await;
+
+        let req = TestRequest::get().uri("/ids/0").to_request();
+        let resp = call_service(&app, req).await;
+        assert_eq!(resp.status(), StatusCode::BAD_REQUEST);
+
+        let req = TestRequest::get().uri(&format!("/ids/{}", MAX_IDS_PER_REQUEST + 1)).to_request();
+        let err: ErrorResponse = call_and_read_body_json(&app, req).await;
+        assert!(err.error.contains("less than or equal"));
+    }
+
+    #[actix_web::test]
+    async fn test_snowflakes_respects_configured_limit() {
+        let mut state = AppState::with_generator(SnowflakeGenerator::new(1).unwrap());
+        state.max_ids_per_request = 10;
+        let app = init_service(App::new().app_data(web::Data::new(state)).service(snowflakes)).await;
+
+        let resp = call_service(&app, TestRequest::get().uri("/ids/10").to_request()).await;
+        assert_eq!(resp.status(), StatusCode::OK);
+
+        let req = TestRequest::get().uri("/ids/11").to_request();
+        let err: ErrorResponse = call_and_read_body_json(&app, req).await;
+        assert_eq!(err.error, "Count must be less than or equal to 10");
+    }
+
+    #[test]
+    fn test_error_response_new() {
+        let err = ErrorResponse::new("test error");
+        assert_eq!(err.error, "test error");
     }
 }
@@ -0,0 +1,201 @@
+use prometheus::core::Collector;
+use prometheus::{exponential_buckets, Gauge, Histogram, HistogramOpts, IntCounter, IntCounterVec, Opts, Registry};
+use lazy_static::lazy_static;
+
+lazy_static! {
+    pub static ref IDS_GENERATED: IntCounter = IntCounter::new(
+        "id_generator_ids_generated_total",
+        "Total IDs generated"
+    ).unwrap();
+
+    pub static ref SEQUENCE_EXHAUSTED: IntCounter = IntCounter::new(
+        "id_generator_sequence_exhausted_total",
+        "Times sequence was exhausted within a millisecond"
+    ).unwrap();
+
+    pub static ref WORKER_ID: Gauge = Gauge::new(
+        "id_generator_worker_id",
+        "Worker ID of this instance"
+    ).unwrap();
+
+    pub static ref CURRENT_SEQUENCE: Gauge = Gauge::new(
+        "id_generator_current_sequence",
+        "Current sequence number this ms"
+    ).unwrap();
+
+    pub static ref MAX_SEQUENCE_PER_MS: Gauge = Gauge::new(
+        "id_generator_max_sequence_per_ms",
+        "Maximum sequence number per millisecond"
+    ).unwrap();
+
+    pub static ref BORROWED_LEAD_MS: Gauge = Gauge::new(
+        "id_generator_borrowed_lead_ms",
+        "How far ahead of the clock the last issued ID was, in milliseconds"
+    ).unwrap();
+
+    pub static ref CLOCK_WALL_GAP_MS: Gauge = Gauge::new(
+        "id_generator_clock_wall_gap_ms",
+        "Anchored clock minus wall clock in milliseconds (monotonic clock mode)"
+    ).unwrap();
+
+    pub static ref CLOCK_CORRECTION_MS: Gauge = Gauge::new(
+        "id_generator_clock_correction_ms",
+        "Total correction slewed into the anchored clock since startup in milliseconds (monotonic clock mode)"
+    ).unwrap();
+
+    pub static ref CLOCK_BACKWARD: IntCounter = IntCounter::new(
+        "id_generator_clock_backward_total",
+        "Times the clock was found behind the last issued timestamp by more than the drift policy allows"
+    ).unwrap();
+
+    pub static ref CLOCK_BACKWARD_MS: Histogram = Histogram::with_opts(
+        HistogramOpts::new(
+            "id_generator_clock_backward_ms",
+            "How far behind the last issued timestamp the clock was when it went backwards, in milliseconds"
+        ).buckets(vec![1.0, 5.0, 10.0, 50.0, 100.0, 500.0, 1000.0, 5000.0, 10000.0, 60000.0])
+    ).unwrap();
+
+    pub static ref CLOCK_DRIFT_TIMEOUTS: IntCounter = IntCounter::new(
+        "id_generator_clock_drift_timeouts_total",
+        "Requests that gave up waiting for the clock to catch up"
+    ).unwrap();
+
+    pub static ref LAST_TIMESTAMP_OFFSET_MS: Gauge = Gauge::new(
+        "id_generator_last_timestamp_offset_ms",
+        "Last issued timestamp minus the wall clock in milliseconds; positive when ahead of the wall clock"
+    ).unwrap();
+
+    pub static ref GENERATION_DURATION: Histogram = Histogram::with_opts(
+        HistogramOpts::new(
+            "id_generator_generation_duration_seconds",
+            "Time to reserve a run of IDs, including any wait for the clock"
+        ).buckets(vec![0.00001, 0.00005, 0.0001, 0.0005, 0.001, 0.005, 0.01, 0.05, 0.1, 0.5, 1.0])
+    ).unwrap();
+
+    pub static ref LOCK_WAIT: Histogram = Histogram::with_opts(
+        HistogramOpts::new(
+            "id_generator_lock_wait_seconds",
+            "Time spent retrying the compare-and-swap on the generator state under contention"
+        ).buckets(vec![0.0000001, 0.000001, 0.00001, 0.0001, 0.001, 0.01])
+    ).unwrap();
+
+    pub static ref SLEEP_DURATION: Histogram = Histogram::with_opts(
+        HistogramOpts::new(
+            "id_generator_sleep_seconds",
+            "Time spent waiting for the clock to reach the next usable millisecond"
+        ).buckets(vec![0.0001, 0.0005, 0.001, 0.005, 0.01, 0.05, 0.1, 0.5, 1.0])
+    ).unwrap();
+
+    pub static ref BATCH_SIZE: Histogram = Histogram::with_opts(
+        HistogramOpts::new(
+            "id_generator_batch_size",
+            "Number of IDs requested by bulk requests"
+        ).buckets(exponential_buckets(1.0, 4.0, 12).unwrap())
+    ).unwrap();
+
+    pub static ref HTTP_REQUESTS: IntCounterVec = IntCounterVec::new(
+        Opts::new("id_generator_http_requests_total", "HTTP requests by endpoint and status"),
+        &["endpoint", "status"]
+    ).unwrap();
+
+    pub static ref GRPC_REQUESTS: IntCounterVec = IntCounterVec::new(
+        Opts::new("id_generator_grpc_requests_total", "gRPC requests by method and status code"),
+        &["method", "code"]
+    ).unwrap();
+
+    pub static ref HTTP_CLIENT_REQUESTS: IntCounterVec = IntCounterVec::new(
+        Opts::new("id_generator_http_client_requests_total", "HTTP requests over mutual TLS by client certificate subject"),
+        &["subject"]
+    ).unwrap();
+
+    pub static ref THROTTLED_REQUESTS: IntCounterVec = IntCounterVec::new(
+        Opts::new("id_generator_throttled_requests_total", "Requests refused by the rate limit by API key or client type"),
+        &["client"]
+    ).unwrap();
+
+    pub static ref TLS_RELOADS: IntCounterVec = IntCounterVec::new(
+        Opts::new("id_generator_tls_reloads_total", "Attempts to reload changed TLS certificate files by result"),
+        &["result"]
+    ).unwrap();
+
+    pub static ref TLS_CERTIFICATE_EXPIRY: Gauge = Gauge::new(
+        "id_generator_tls_certificate_expiry_timestamp_seconds",
+        "Unix time at which the served TLS certificate expires"
+    ).unwrap();
+}
+
+/// Registers every metric above with `registry`. Metrics that aren't
+/// registered are still updated but never exported.
+pub fn register(registry: &Registry) -> prometheus::Result<()> {
+    let collectors: Vec<Box<dyn Collector>> = vec![
+        Box::new(IDS_GENERATED.clone()),
+        Box::new(SEQUENCE_EXHAUSTED.clone()),
+        Box::new(WORKER_ID.clone()),
+        Box::new(CURRENT_SEQUENCE.clone()),
+        Box::new(MAX_SEQUENCE_PER_MS.clone()),
+        Box::new(BORROWED_LEAD_MS.clone()),
+        Box::new(CLOCK_WALL_GAP_MS.clone()),
+        Box::new(CLOCK_CORRECTION_MS.clone()),
+        Box::new(CLOCK_BACKWARD.clone()),
+        Box::new(CLOCK_BACKWARD_MS.clone()),
+        Box::new(CLOCK_DRIFT_TIMEOUTS.clone()),
+        Box::new(LAST_TIMESTAMP_OFFSET_MS.clone()),
+        Box::new(GENERATION_DURATION.clone()),
+        Box::new(LOCK_WAIT.clone()),
+        Box::new(SLEEP_DURATION.clone()),
+        Box::new(BATCH_SIZE.clone()),
+        Box::new(HTTP_REQUESTS.clone()),
+        Box::new(GRPC_REQUESTS.clone()),
+        Box::new(HTTP_CLIENT_REQUESTS.clone()),
+        Box::new(THROTTLED_REQUESTS.clone()),
+        Box::new(TLS_RELOADS.clone()),
+        Box::new(TLS_CERTIFICATE_EXPIRY.clone()),
+    ];
+
+    for collector in collectors {
+        registry.register(collector)?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_register_exports_all_metrics() {
+        let registry = Registry::new();
+        register(&registry).unwrap();
+
+        // Label vectors only show up once a label combination is used
+        HTTP_REQUESTS.with_label_values(&["/id", "200"]).inc();
+        GRPC_REQUESTS.with_label_values(&["NextId", "Ok"]).inc();
+        HTTP_CLIENT_REQUESTS.with_label_values(&["CN=client"]).inc();
+        TLS_RELOADS.with_label_values(&["success"]).inc();
+        THROTTLED_REQUESTS.with_label_values(&["key:billing"]).inc();
+
+        let names: Vec<String> = registry.gather().iter().map(|family| family.get_name().to_string()).collect();
+        for name in [
+            "id_generator_ids_generated_total",
+            "id_generator_sequence_exhausted_total",
+            "id_generator_clock_backward_total",
+            "id_generator_clock_backward_ms",
+            "id_generator_clock_drift_timeouts_total",
+            "id_generator_last_timestamp_offset_ms",
+            "id_generator_generation_duration_seconds",
+            "id_generator_lock_wait_seconds",
+            "id_generator_sleep_seconds",
+            "id_generator_batch_size",
+            "id_generator_http_requests_total",
+            "id_generator_grpc_requests_total",
+            "id_generator_http_client_requests_total",
+            "id_generator_tls_reloads_total",
+            "id_generator_throttled_requests_total",
+            "id_generator_tls_certificate_expiry_timestamp_seconds",
+        ] {
+            assert!(names.iter().any(|n| n == name), "{} should be registered", name);
+        }
+
+        assert!(register(&registry).is_err(), "Registering twice should fail");
+    }
+}
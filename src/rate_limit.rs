@@ -0,0 +1,346 @@
+use std::collections::HashMap;
+use std::net::{IpAddr, SocketAddr};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use actix_web::http::header::RETRY_AFTER;
+use actix_web::{HttpMessage, HttpRequest, HttpResponse};
+use id_generator::metrics::THROTTLED_REQUESTS;
+
+use crate::auth::ApiKey;
+use crate::{AppState, ErrorResponse};
+
+/// Number of clients tracked before idle buckets are dropped.
+const PRUNE_THRESHOLD: usize = 10_000;
+
+/// How fast a client may take IDs. Buckets hold up to `burst` IDs and refill
+/// at `ids_per_sec`.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Limit {
+    pub ids_per_sec: f64,
+    pub burst: u64,
+}
+
+impl Limit {
+    /// `burst` defaults to one second's worth of IDs.
+    pub fn new(ids_per_sec: f64, burst: Option<u64>) -> Self {
+        Self {
+            ids_per_sec,
+            burst: burst.unwrap_or_else(|| (ids_per_sec.ceil() as u64).max(1)),
+        }
+    }
+}
+
+/// Why IDs weren't handed out.
+#[derive(Debug, PartialEq)]
+pub enum Throttle {
+    /// The bucket will hold enough IDs after this long.
+    RetryAfter(Duration),
+    /// More IDs than the bucket can ever hold.
+    ExceedsBurst(u64),
+}
+
+struct Bucket {
+    tokens: f64,
+    updated: Instant,
+    limit: Limit,
+}
+
+impl Bucket {
+    fn refill(&mut self, now: Instant) {
+        let elapsed = now.saturating_duration_since(self.updated).as_secs_f64();
+        self.tokens = (self.tokens + elapsed * self.limit.ids_per_sec).min(self.limit.burst as f64);
+        self.updated = now;
+    }
+}
+
+/// Token buckets counting IDs, not requests, per API key or client IP.
+pub struct RateLimiter {
+    /// Limit for clients whose API key doesn't set one. `None` leaves them
+    /// unlimited.
+    pub default: Option<Limit>,
+    /// Take the client IP from the first `X-Forwarded-For` address. Only safe
+    /// behind a proxy that sets the header.
+    pub trust_forwarded_for: bool,
+    buckets: Mutex<HashMap<String, Bucket>>,
+}
+
+impl Default for RateLimiter {
+    fn default() -> Self {
+        Self::new(None, false)
+    }
+}
+
+impl RateLimiter {
+    pub fn new(default: Option<Limit>, trust_forwarded_for: bool) -> Self {
+        Self {
+            default,
+            trust_forwarded_for,
+            buckets: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Takes `count` IDs from `client`'s bucket.
+    pub fn acquire(&self, client: &str, limit: Limit, count: u64) -> Result<(), Throttle> {
+        self.acquire_at(client, limit, count, Instant::now())
+    }
+
+    fn acquire_at(&self, client: &str, limit: Limit, count: u64, now: Instant) -> Result<(), Throttle> {
+        if count > limit.burst {
+            return Err(Throttle::ExceedsBurst(limit.burst));
+        }
+
+        let mut buckets = self.buckets.lock().unwrap_or_else(|e| e.into_inner());
+        if !buckets.contains_key(client) && buckets.len() >= PRUNE_THRESHOLD {
+            // A full bucket is the same as no bucket
+            buckets.retain(|_, bucket| {
+                bucket.refill(now);
+                bucket.tokens < bucket.limit.burst as f64
+            });
+        }
+
+        let bucket = buckets.entry(client.to_string()).or_insert_with(|| Bucket {
+            tokens: limit.burst as f64,
+            updated: now,
+            limit,
+        });
+        bucket.refill(now);
+
+        let count = count as f64;
+        if bucket.tokens >= count {
+            bucket.tokens -= count;
+            Ok(())
+        } else {
+            Err(Throttle::RetryAfter(Duration::from_secs_f64(
+                (count - bucket.tokens) / limit.ids_per_sec,
+            )))
+        }
+    }
+
+    /// Puts back `count` IDs taken from `client`'s bucket that were never
+    /// handed out.
+    pub fn release(&self, client: &str, count: u64) {
+        if let Some(bucket) = self.buckets.lock().unwrap_or_else(|e| e.into_inner()).get_mut(client) {
+            bucket.tokens = (bucket.tokens + count as f64).min(bucket.limit.burst as f64);
+        }
+    }
+
+    /// Takes `count` IDs for the caller with `key`, or else from `ip`, using
+    /// the key's own limit if it sets one. Rejections are counted by
+    /// [`client_label`].
+    fn check(&self, key: Option<&ApiKey>, ip: Option<IpAddr>, count: u64) -> Result<(), Throttle> {
+        let Some(limit) = key.and_then(ApiKey::rate_limit).or(self.default) else {
+            return Ok(());
+        };
+        let result = self.acquire(&client_name(key, ip), limit, count);
+        if result.is_err() {
+            THROTTLED_REQUESTS.with_label_values(&[&client_label(key, ip)]).inc();
+        }
+        result
+    }
+
+    /// Undoes a successful [`check`](Self::check) with the same arguments.
+    fn refund(&self, key: Option<&ApiKey>, ip: Option<IpAddr>, count: u64) {
+        if key.and_then(ApiKey::rate_limit).or(self.default).is_some() {
+            self.release(&client_name(key, ip), count);
+        }
+    }
+
+    fn client_ip(&self, req: &HttpRequest) -> Option<IpAddr> {
+        if self.trust_forwarded_for {
+            let forwarded = req
+                .headers()
+                .get("x-forwarded-for")
+                .and_then(|value| value.to_str().ok())
+                .and_then(|value| value.split(',').next())
+                .and_then(|first| first.trim().parse().ok());
+            if forwarded.is_some() {
+                return forwarded;
+            }
+        }
+        req.peer_addr().map(|addr| addr.ip())
+    }
+}
+
+/// Names a client's bucket: `key:<id>`, `ip:<address>` or `unix` for Unix
+/// socket connections.
+fn client_name(key: Option<&ApiKey>, ip: Option<IpAddr>) -> String {
+    match (key, ip) {
+        (Some(key), _) => format!("key:{}", key.id),
+        (None, Some(ip)) => format!("ip:{}", ip),
+        (None, None) => "unix".to_string(),
+    }
+}
+
+/// Names a client in metrics: `key:<id>`, or only `ip` or `unix` for callers
+/// without a key, so addresses can't add a series each.
+fn client_label(key: Option<&ApiKey>, ip: Option<IpAddr>) -> String {
+    match (key, ip) {
+        (Some(key), _) => format!("key:{}", key.id),
+        (None, Some(_)) => "ip".to_string(),
+        (None, None) => "unix".to_string(),
+    }
+}
+
+/// Whole seconds for `Retry-After`, rounded up so retrying on time succeeds.
+pub fn retry_after_secs(wait: Duration) -> u64 {
+    wait.as_secs() + u64::from(wait.subsec_nanos() > 0)
+}
+
+/// Takes `count` IDs for the caller of an HTTP request, keyed by its API key
+/// or IP.
+pub fn check_http(req: &HttpRequest, data: &AppState, count: u64) -> Result<(), Throttle> {
+    let key = req.extensions().get::<Arc<ApiKey>>().cloned();
+    data.rate_limiter.check(key.as_deref(), data.rate_limiter.client_ip(req), count)
+}
+
+/// Returns IDs taken by [`check_http`] when the request fails before any are
+/// generated.
+pub fn refund_http(req: &HttpRequest, data: &AppState, count: u64) {
+    let key = req.extensions().get::<Arc<ApiKey>>().cloned();
+    data.rate_limiter.refund(key.as_deref(), data.rate_limiter.client_ip(req), count)
+}
+
+/// The HTTP response for a rate limit rejection, with the wait in
+/// `Retry-After`.
+pub fn throttle_response(throttle: Throttle) -> HttpResponse {
+    match throttle {
+        Throttle::ExceedsBurst(burst) => HttpResponse::BadRequest().json(ErrorResponse::new(format!(
+            "Count must be less than or equal to {}, the rate limit burst",
+            burst
+        ))),
+        Throttle::RetryAfter(wait) => {
+            let secs = retry_after_secs(wait);
+            HttpResponse::TooManyRequests()
+                .insert_header((RETRY_AFTER, secs.to_string()))
+                .json(ErrorResponse::new(format!("Rate limit exceeded, retry in {} s", secs)))
+        }
+    }
+}
+
+/// As [`check_http`] for gRPC calls, given the key the call was authorized
+/// with.
+pub fn check_grpc(
+    data: &AppState,
+    key: Option<&ApiKey>,
+    remote_addr: Option<SocketAddr>,
+    count: u64,
+) -> Result<(), Throttle> {
+    data.rate_limiter.check(key, remote_addr.map(|addr| addr.ip()), count)
+}
+
+/// As [`refund_http`] for gRPC calls.
+pub fn refund_grpc(data: &AppState, key: Option<&ApiKey>, remote_addr: Option<SocketAddr>, count: u64) {
+    data.rate_limiter.refund(key, remote_addr.map(|addr| addr.ip()), count)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use actix_web::test::TestRequest;
+
+    #[test]
+    fn test_acquire_refills_over_time() {
+        let limiter = RateLimiter::default();
+        let limit = Limit::new(100.0, Some(200));
+        let start = Instant::now();
+
+        assert_eq!(limiter.acquire_at("a", limit, 200, start), Ok(()));
+        assert_eq!(
+            limiter.acquire_at("a", limit, 50, start),
+            Err(Throttle::RetryAfter(Duration::from_millis(500)))
+        );
+        // Other clients start with a full bucket
+        assert_eq!(limiter.acquire_at("b", limit, 200, start), Ok(()));
+
+        let later = start + Duration::from_millis(500);
+        assert_eq!(limiter.acquire_at("a", limit, 50, later), Ok(()));
+        assert!(limiter.acquire_at("a", limit, 1, later).is_err());
+
+        // Buckets never hold more than the burst
+        let much_later = start + Duration::from_secs(60);
+        assert_eq!(limiter.acquire_at("a", limit, 200, much_later), Ok(()));
+        assert!(limiter.acquire_at("a", limit, 1, much_later).is_err());
+    }
+
+    #[test]
+    fn test_release() {
+        let limiter = RateLimiter::default();
+        let limit = Limit::new(0.001, Some(2));
+        limiter.acquire("a", limit, 2).unwrap();
+        limiter.release("a", 1);
+        limiter.acquire("a", limit, 1).unwrap();
+        assert!(limiter.acquire("a", limit, 1).is_err());
+
+        // Never past the burst, and unknown clients are ignored
+        limiter.release("a", 5);
+        assert_eq!(limiter.acquire("a", limit, 3), Err(Throttle::ExceedsBurst(2)));
+        limiter.acquire("a", limit, 2).unwrap();
+        assert!(limiter.acquire("a", limit, 1).is_err());
+        limiter.release("b", 1);
+        assert!(limiter.buckets.lock().unwrap().get("b").is_none());
+    }
+
+    #[test]
+    fn test_survives_poisoned_lock() {
+        let limiter = RateLimiter::default();
+        let limit = Limit::new(0.001, Some(2));
+        limiter.acquire("a", limit, 1).unwrap();
+        let _ = std::panic::catch_unwind(|| {
+            let _buckets = limiter.buckets.lock().unwrap();
+            panic!("poison the lock");
+        });
+
+        limiter.release("a", 1);
+        limiter.acquire("a", limit, 2).unwrap();
+        assert!(limiter.acquire("a", limit, 1).is_err());
+    }
+
+    #[test]
+    fn test_acquire_more_than_burst() {
+        let limiter = RateLimiter::default();
+        assert_eq!(limiter.acquire("a", Limit::new(10.0, None), 11), Err(Throttle::ExceedsBurst(10)));
+        assert_eq!(Limit::new(0.5, None).burst, 1);
+    }
+
+    #[test]
+    fn test_idle_buckets_pruned() {
+        let limiter = RateLimiter::default();
+        let limit = Limit::new(1.0, None);
+        let start = Instant::now();
+        for i in 0..PRUNE_THRESHOLD {
+            limiter.acquire_at(&i.to_string(), limit, 1, start).unwrap();
+        }
+
+        limiter.acquire_at("new", limit, 1, start).unwrap();
+        assert_eq!(limiter.buckets.lock().unwrap().len(), PRUNE_THRESHOLD + 1);
+
+        // Nothing has refilled yet, so nothing can go
+        limiter.acquire_at("newer", limit, 1, start + Duration::from_millis(500)).unwrap();
+        assert_eq!(limiter.buckets.lock().unwrap().len(), PRUNE_THRESHOLD + 2);
+
+        // Once they've all refilled they're dropped
+        limiter.acquire_at("newest", limit, 1, start + Duration::from_secs(2)).unwrap();
+        assert_eq!(limiter.buckets.lock().unwrap().len(), 1);
+    }
+
+    #[test]
+    fn test_client_ip() {
+        let req = TestRequest::default()
+            .peer_addr("10.0.0.1:40000".parse().unwrap())
+            .insert_header(("x-forwarded-for", "203.0.113.7, 10.0.0.5"))
+            .to_http_request();
+        assert_eq!(RateLimiter::new(None, false).client_ip(&req), Some("10.0.0.1".parse().unwrap()));
+        assert_eq!(RateLimiter::new(None, true).client_ip(&req), Some("203.0.113.7".parse().unwrap()));
+
+        let req = TestRequest::default().to_http_request();
+        assert_eq!(client_name(None, RateLimiter::default().client_ip(&req)), "unix");
+    }
+
+    #[test]
+    fn test_retry_after_secs() {
+        assert_eq!(retry_after_secs(Duration::from_millis(1)), 1);
+        assert_eq!(retry_after_secs(Duration::from_secs(2)), 2);
+        assert_eq!(retry_after_secs(Duration::from_millis(2001)), 3);
+    }
+}
@@ -0,0 +1,204 @@
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use id_generator::SnowflakeGenerator;
+use serde::{Deserialize, Serialize};
+
+/// Window over which the sequence exhaustion rate is measured.
+pub const EXHAUSTION_WINDOW: Duration = Duration::from_secs(10);
+/// Default exhaustion rate above which the instance reports not ready: half of
+/// all milliseconds.
+pub const DEFAULT_MAX_EXHAUSTIONS_PER_SEC: f64 = 500.0;
+
+/// Why an instance isn't ready to issue IDs.
+#[derive(Debug, Serialize, Deserialize, PartialEq)]
+pub struct ReadinessReason {
+    /// `startup`, `clock` or `sequence`.
+    pub check: String,
+    pub message: String,
+}
+
+impl ReadinessReason {
+    fn new(check: &str, message: String) -> Self {
+        Self {
+            check: check.to_string(),
+            message,
+        }
+    }
+}
+
+struct ExhaustionSample {
+    at: Instant,
+    count: u64,
+    per_sec: f64,
+}
+
+/// Decides whether the generator can currently issue IDs.
+pub struct Readiness {
+    /// Timestamp of the high-water mark loaded at startup, which the clock
+    /// has to pass before any ID can be issued.
+    startup_mark: Option<u64>,
+    started: AtomicBool,
+    exhaustion: Mutex<ExhaustionSample>,
+    pub exhaustion_window: Duration,
+    pub max_exhaustions_per_sec: f64,
+}
+
+impl Readiness {
+    pub fn new(generator: &SnowflakeGenerator) -> Self {
+        let mark = generator.last_timestamp();
+        Self {
+            startup_mark: (mark > 0).then_some(mark),
+            started: AtomicBool::new(false),
+            exhaustion: Mutex::new(ExhaustionSample {
+                at: Instant::now(),
+                count: generator.sequence_exhaustions(),
+                per_sec: 0.0,
+            }),
+            exhaustion_window: EXHAUSTION_WINDOW,
+            max_exhaustions_per_sec: DEFAULT_MAX_EXHAUSTIONS_PER_SEC,
+        }
+    }
+
+    /// Returns the reasons the generator can't issue IDs right now, if any.
+    pub fn check(&self, generator: &SnowflakeGenerator) -> Vec<ReadinessReason> {
+        let mut reasons = Vec::new();
+        let current = generator.current_timestamp();
+
+        if !self.started.load(Ordering::Acquire) {
+            match self.startup_mark {
+                Some(mark) if current <= mark => reasons.push(ReadinessReason::new(
+                    "startup",
+                    format!(
+                        "Waiting for the clock to pass the persisted high-water mark at {}, {} ms ahead",
+                        id_generator::time::format_rfc3339(generator.epoch_ms() + mark),
+                        mark + 1 - current
+                    ),
+                )),
+                _ => self.started.store(true, Ordering::Release),
+            }
+        }
+
+        let lead = generator.lead_ms();
+        let max_lead = generator.drift_policy().max_lead_ms();
+        if self.started.load(Ordering::Acquire) && lead > max_lead {
+            reasons.push(ReadinessReason::new(
+                "clock",
+                format!(
+                    "Clock is {} ms behind the last issued timestamp, {} ms allowed",
+                    lead, max_lead
+                ),
+            ));
+        }
+
+        let per_sec = self.exhaustion_rate(generator);
+        if per_sec > self.max_exhaustions_per_sec {
+            reasons.push(ReadinessReason::new(
+                "sequence",
+                format!(
+                    "Sequence exhausted {:.0} times per second, more than {:.0}",
+                    per_sec, self.max_exhaustions_per_sec
+                ),
+            ));
+        }
+
+        reasons
+    }
+
+    /// Exhaustions per second over the last full window.
+    fn exhaustion_rate(&self, generator: &SnowflakeGenerator) -> f64 {
+        let mut sample = self.exhaustion.lock().unwrap_or_else(|e| e.into_inner());
+        let elapsed = sample.at.elapsed();
+        if elapsed >= self.exhaustion_window {
+            let count = generator.sequence_exhaustions();
+            sample.per_sec = (count - sample.count) as f64 / elapsed.as_secs_f64().max(f64::EPSILON);
+            sample.at = Instant::now();
+            sample.count = count;
+        }
+        sample.per_sec
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use id_generator::clock::ManualClock;
+    use id_generator::{GeneratorOptions, HighWaterMarkOptions, UNIX_EPOCH_OFFSET};
+    use std::sync::Arc;
+
+    fn manual_generator(options: GeneratorOptions) -> (Arc<ManualClock>, SnowflakeGenerator) {
+        let clock = Arc::new(ManualClock::new(UNIX_EPOCH_OFFSET + 1_000_000));
+        let generator = SnowflakeGenerator::with_clock(1, options, clock.clone()).unwrap();
+        (clock, generator)
+    }
+
+    fn checks(reasons: &[ReadinessReason]) -> Vec<&str> {
+        reasons.iter().map(|r| r.check.as_str()).collect()
+    }
+
+    #[test]
+    fn test_ready_by_default() {
+        let (_, generator) = manual_generator(GeneratorOptions::default());
+        let readiness = Readiness::new(&generator);
+        generator.next_id().unwrap();
+
+        assert!(readiness.check(&generator).is_empty());
+    }
+
+    #[test]
+    fn test_not_ready_while_clock_behind() {
+        let (clock, generator) = manual_generator(GeneratorOptions::default());
+        let readiness = Readiness::new(&generator);
+        generator.next_id().unwrap();
+
+        clock.rewind(50);
+        let reasons = readiness.check(&generator);
+        assert_eq!(checks(&reasons), ["clock"]);
+        assert!(reasons[0].message.contains("50 ms behind"));
+
+        clock.advance(50);
+        assert!(readiness.check(&generator).is_empty());
+    }
+
+    #[test]
+    fn test_not_ready_until_clock_passes_high_water_mark() {
+        let path = std::env::temp_dir().join(format!("id-generator-ready-{}", std::process::id()));
+        let mark = UNIX_EPOCH_OFFSET + 1_000_100;
+        std::fs::write(&path, mark.to_string()).unwrap();
+
+        let options = GeneratorOptions {
+            high_water_mark: Some(HighWaterMarkOptions::new(&path)),
+            ..Default::default()
+        };
+        let (clock, generator) = manual_generator(options);
+        let readiness = Readiness::new(&generator);
+
+        let reasons = readiness.check(&generator);
+        assert_eq!(checks(&reasons), ["startup"]);
+        assert!(reasons[0].message.contains("101 ms ahead"));
+
+        clock.advance(101);
+        assert!(readiness.check(&generator).is_empty());
+        std::fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn test_not_ready_when_sequence_repeatedly_exhausted() {
+        let (clock, generator) = manual_generator(GeneratorOptions::default());
+        let mut readiness = Readiness::new(&generator);
+        readiness.exhaustion_window = Duration::from_millis(1);
+        readiness.max_exhaustions_per_sec = 1.0;
+
+        generator.next_ids(generator.layout().sequence_mask() + 1).unwrap();
+        generator.next_id().unwrap_err();
+        clock.advance(1);
+        std::thread::sleep(Duration::from_millis(2));
+
+        assert_eq!(checks(&readiness.check(&generator)), ["sequence"]);
+
+        // No further exhaustion over the next window
+        std::thread::sleep(Duration::from_millis(2));
+        assert!(readiness.check(&generator).is_empty());
+    }
+}
@@ -0,0 +1,154 @@
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// Milliseconds since the Unix epoch according to the wall clock.
+pub fn unix_millis() -> u64 {
+    let since_the_epoch = SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default();
+    TryInto::<u64>::try_into(since_the_epoch.as_millis()).unwrap_or(0)
+}
+
+/// Formats Unix milliseconds as `YYYY-MM-DDTHH:MM:SS.mmmZ`.
+pub fn format_rfc3339(unix_ms: u64) -> String {
+    let secs = unix_ms / 1000;
+    let days = secs / 86_400;
+    let rem = secs % 86_400;
+
+    // Civil-from-days conversion (proleptic Gregorian calendar), see
+    // https://howardhinnant.github.io/date_algorithms.html#civil_from_days
+    let z = days + 719_468;
+    let era = z / 146_097;
+    let doe = z - era * 146_097;
+    let yoe = (doe - doe / 1460 + doe / 36_524 - doe / 146_096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = doy - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = yoe + era * 400 + u64::from(month <= 2);
+
+    format!(
+        "{:04}-{:02}-{:02}T{:02}:{:02}:{:02}.{:03}Z",
+        year,
+        month,
+        day,
+        rem / 3600,
+        (rem % 3600) / 60,
+        rem % 60,
+        unix_ms % 1000
+    )
+}
+
+/// Parses a UTC date (`YYYY-MM-DD`) or date-time (`YYYY-MM-DDTHH:MM:SS[.mmm]Z`)
+/// into Unix milliseconds. Dates before 1970 and non-UTC offsets are rejected.
+pub fn parse_rfc3339(s: &str) -> Option<u64> {
+    let (date, time) = match s.split_once(['T', 't', ' ']) {
+        Some((date, time)) => (date, Some(time)),
+        None => (s, None),
+    };
+
+    let mut parts = date.splitn(3, '-');
+    let year: u64 = parse_digits(parts.next()?, 4)?;
+    let month: u64 = parse_digits(parts.next()?, 2)?;
+    let day: u64 = parse_digits(parts.next()?, 2)?;
+    if year < 1970 || !(1..=12).contains(&month) || day < 1 || day > days_in_month(year, month) {
+        return None;
+    }
+
+    let mut millis_of_day = 0;
+    if let Some(time) = time {
+        let time = time.strip_suffix(['Z', 'z'])?;
+        let (hms, fraction) = match time.split_once('.') {
+            Some((hms, fraction)) => (hms, Some(fraction)),
+            None => (time, None),
+        };
+
+        let mut parts = hms.splitn(3, ':');
+        let hour: u64 = parse_digits(parts.next()?, 2)?;
+        let minute: u64 = parse_digits(parts.next()?, 2)?;
+        let second: u64 = parse_digits(parts.next()?, 2)?;
+        if hour > 23 || minute > 59 || second > 59 {
+            return None;
+        }
+
+        let millis = match fraction {
+            Some(f) if !f.is_empty() && f.len() <= 3 && f.bytes().all(|b| b.is_ascii_digit()) => {
+                format!("{:0<3}", f).parse::<u64>().ok()?
+            }
+            Some(_) => return None,
+            None => 0,
+        };
+
+        millis_of_day = ((hour * 60 + minute) * 60 + second) * 1000 + millis;
+    }
+
+    // Days-from-civil conversion, the inverse of the one in `format_rfc3339`
+    let y = if month <= 2 { year - 1 } else { year };
+    let era = y / 400;
+    let yoe = y - era * 400;
+    let mp = if month > 2 { month - 3 } else { month + 9 };
+    let doy = (153 * mp + 2) / 5 + day - 1;
+    let doe = yoe * 365 + yoe / 4 - yoe / 100 + doy;
+    let days = era * 146_097 + doe - 719_468;
+
+    Some(days * 86_400_000 + millis_of_day)
+}
+
+fn parse_digits(s: &str, len: usize) -> Option<u64> {
+    if s.len() != len || !s.bytes().all(|b| b.is_ascii_digit()) {
+        return None;
+    }
+    s.parse().ok()
+}
+
+fn days_in_month(year: u64, month: u64) -> u64 {
+    match month {
+        2 if year.is_multiple_of(4) && (!year.is_multiple_of(100) || year.is_multiple_of(400)) => 29,
+        2 => 28,
+        4 | 6 | 9 | 11 => 30,
+        _ => 31,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_format_rfc3339() {
+        assert_eq!(format_rfc3339(0), "1970-01-01T00:00:00.000Z");
+        assert_eq!(format_rfc3339(1705065354064), "2024-01-12T13:15:54.064Z");
+        assert_eq!(format_rfc3339(951_782_400_000), "2000-02-29T00:00:00.000Z");
+    }
+
+    #[test]
+    fn test_parse_rfc3339_date() {
+        assert_eq!(parse_rfc3339("1970-01-01"), Some(0));
+        assert_eq!(parse_rfc3339("2020-01-01"), Some(1_577_836_800_000));
+        assert_eq!(parse_rfc3339("2000-02-29"), Some(951_782_400_000));
+    }
+
+    #[test]
+    fn test_parse_rfc3339_date_time() {
+        assert_eq!(parse_rfc3339("2024-01-12T13:15:54.064Z"), Some(1705065354064));
+        assert_eq!(parse_rfc3339("2010-11-04T01:42:54.657Z"), Some(1288834974657));
+        assert_eq!(parse_rfc3339("2010-11-04T01:42:54.6Z"), Some(1288834974600));
+        assert_eq!(parse_rfc3339("2020-01-01T00:00:00Z"), Some(1_577_836_800_000));
+    }
+
+    #[test]
+    fn test_parse_rfc3339_rejects_invalid() {
+        assert_eq!(parse_rfc3339(""), None);
+        assert_eq!(parse_rfc3339("2020-13-01"), None);
+        assert_eq!(parse_rfc3339("2019-02-29"), None);
+        assert_eq!(parse_rfc3339("1969-12-31"), None);
+        assert_eq!(parse_rfc3339("2020-01-01T00:00:00"), None);
+        assert_eq!(parse_rfc3339("2020-01-01T00:00:00+02:00"), None);
+        assert_eq!(parse_rfc3339("2020-01-01T24:00:00Z"), None);
+        assert_eq!(parse_rfc3339("2020-01-01T00:00:00.1234Z"), None);
+    }
+
+    #[test]
+    fn test_parse_format_roundtrip() {
+        for ms in [0, 1288834974657, 1705065354064, 4_102_444_800_000] {
+            assert_eq!(parse_rfc3339(&format_rfc3339(ms)), Some(ms));
+        }
+    }
+}
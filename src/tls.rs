@@ -0,0 +1,436 @@
+use std::any::Any;
+use std::fs::File;
+use std::io::BufReader;
+use std::path::Path;
+use std::sync::{Arc, Mutex, RwLock};
+use std::time::{Duration, SystemTime};
+
+use actix_tls::accept::rustls_0_23::TlsStream;
+use actix_web::dev::Extensions;
+use actix_web::rt::net::TcpStream;
+use id_generator::metrics::{TLS_CERTIFICATE_EXPIRY, TLS_RELOADS};
+use rustls::client::danger::HandshakeSignatureValid;
+use rustls::crypto::CryptoProvider;
+use rustls::pki_types::{CertificateDer, UnixTime};
+use rustls::server::danger::{ClientCertVerified, ClientCertVerifier};
+use rustls::server::{ClientHello, ResolvesServerCert, WebPkiClientVerifier};
+use rustls::sign::CertifiedKey;
+use rustls::{DigitallySignedStruct, DistinguishedName, RootCertStore, ServerConfig, SignatureScheme};
+use x509_parser::prelude::{FromDer, X509Certificate};
+
+use crate::config::{ClientAuth, TlsConfig};
+
+/// Subject of a verified client certificate, stored as connection data by
+/// [`on_connect`] and read with `HttpRequest::conn_data`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ClientIdentity {
+    /// Distinguished name in RFC 4514 order, e.g. `CN=billing, O=Example`.
+    pub subject: String,
+}
+
+impl ClientIdentity {
+    pub fn from_certificate(cert: &CertificateDer<'_>) -> Option<Self> {
+        let (_, parsed) = X509Certificate::from_der(cert).ok()?;
+        Some(Self {
+            subject: parsed.subject().to_string(),
+        })
+    }
+}
+
+/// Records the client certificate of mutual TLS connections. Plain TCP and
+/// Unix socket connections are left alone.
+pub fn on_connect(connection: &dyn Any, extensions: &mut Extensions) {
+    let Some(stream) = connection.downcast_ref::<TlsStream<TcpStream>>() else {
+        return;
+    };
+    let (_, session) = stream.get_ref();
+    let identity = session
+        .peer_certificates()
+        .and_then(|certs| certs.first())
+        .and_then(ClientIdentity::from_certificate);
+    if let Some(identity) = identity {
+        extensions.insert(identity);
+    }
+}
+
+/// Modification time and length, compared to notice a file being replaced.
+type FileStamp = Option<(SystemTime, u64)>;
+
+fn stamp(path: &Path) -> FileStamp {
+    let metadata = std::fs::metadata(path).ok()?;
+    Some((metadata.modified().ok()?, metadata.len()))
+}
+
+/// The server certificate and client CA, shared by every TLS listener and
+/// swapped in place when the files change, so reloading doesn't drop
+/// connections or need a restart.
+pub struct TlsCertificates {
+    config: TlsConfig,
+    provider: Arc<CryptoProvider>,
+    certified_key: RwLock<Arc<CertifiedKey>>,
+    verifier: RwLock<Arc<dyn ClientCertVerifier>>,
+    stamps: Mutex<Vec<FileStamp>>,
+}
+
+impl TlsCertificates {
+    pub fn load(config: TlsConfig) -> Result<Arc<Self>, String> {
+        let provider = Arc::new(rustls::crypto::ring::default_provider());
+        let stamps = Mutex::new(Self::stamps(&config));
+        let certified_key = load_certified_key(&config, &provider)?;
+        let verifier = load_verifier(&config, &provider)?;
+        record_expiry(&certified_key);
+
+        Ok(Arc::new(Self {
+            config,
+            provider,
+            certified_key: RwLock::new(Arc::new(certified_key)),
+            verifier: RwLock::new(verifier),
+            stamps,
+        }))
+    }
+
+    /// Whether clients are asked for certificates.
+    pub fn mutual(&self) -> bool {
+        self.config.client_ca_file.is_some()
+    }
+
+    fn stamps(config: &TlsConfig) -> Vec<FileStamp> {
+        [Some(&config.cert_file), Some(&config.key_file), config.client_ca_file.as_ref()]
+            .into_iter()
+            .flatten()
+            .map(|path| stamp(path))
+            .collect()
+    }
+
+    /// A rustls config that always serves the current certificate and
+    /// verifies clients against the current CA bundle.
+    pub fn server_config(self: &Arc<Self>) -> Result<ServerConfig, String> {
+        let builder = ServerConfig::builder_with_provider(self.provider.clone())
+            .with_safe_default_protocol_versions()
+            .map_err(|e| e.to_string())?;
+        let builder = if self.config.client_ca_file.is_some() {
+            builder.with_client_cert_verifier(Arc::new(ReloadingVerifier(self.clone())))
+        } else {
+            builder.with_no_client_auth()
+        };
+        Ok(builder.with_cert_resolver(Arc::new(ReloadingResolver(self.clone()))))
+    }
+
+    /// Reads the files again. On failure the current certificates stay in use.
+    pub fn reload(&self) -> Result<(), String> {
+        let result = load_certified_key(&self.config, &self.provider)
+            .and_then(|certified_key| Ok((certified_key, load_verifier(&self.config, &self.provider)?)));
+        let (certified_key, verifier) = match result {
+            Ok(loaded) => loaded,
+            Err(e) => {
+                TLS_RELOADS.with_label_values(&["error"]).inc();
+                return Err(e);
+            }
+        };
+
+        record_expiry(&certified_key);
+        *self.certified_key.write().unwrap() = Arc::new(certified_key);
+        *self.verifier.write().unwrap() = verifier;
+        TLS_RELOADS.with_label_values(&["success"]).inc();
+        Ok(())
+    }
+
+    /// Reloads if any of the files changed since they were last read.
+    /// Returns whether a reload was attempted.
+    pub fn reload_if_changed(&self) -> Result<bool, String> {
+        let stamps = Self::stamps(&self.config);
+        {
+            // A failed reload isn't retried until the files change again, as
+            // retrying a broken file would fail the same way
+            let mut last = self.stamps.lock().unwrap();
+            if *last == stamps {
+                return Ok(false);
+            }
+            *last = stamps;
+        }
+        self.reload().map(|()| true)
+    }
+
+    /// Checks the files every `tls.reload_interval_secs` until the server
+    /// stops. Does nothing when reloading is disabled.
+    pub async fn watch(self: Arc<Self>) {
+        if self.config.reload_interval_secs == 0 {
+            return;
+        }
+        let mut interval = tokio::time::interval(Duration::from_secs(self.config.reload_interval_secs));
+        interval.tick().await;
+
+        loop {
+            interval.tick().await;
+            match self.reload_if_changed() {
+                Ok(true) => println!("Reloaded TLS certificate {}", self.config.cert_file.display()),
+                Ok(false) => {}
+                Err(e) => eprintln!("Failed to reload TLS certificates, keeping the previous ones: {}", e),
+            }
+        }
+    }
+}
+
+fn load_certified_key(config: &TlsConfig, provider: &CryptoProvider) -> Result<CertifiedKey, String> {
+    let certs = read_certs(&config.cert_file)?;
+    let key_file = &config.key_file;
+    let key = File::open(key_file)
+        .map_err(|e| e.to_string())
+        .and_then(|file| rustls_pemfile::private_key(&mut BufReader::new(file)).map_err(|e| e.to_string()))
+        .and_then(|key| key.ok_or_else(|| "no private key found".to_string()))
+        .map_err(|e| format!("{}: {}", key_file.display(), e))?;
+
+    CertifiedKey::from_der(certs, key, provider)
+        .map_err(|e| format!("{} and {}: {}", config.cert_file.display(), key_file.display(), e))
+}
+
+fn record_expiry(certified_key: &CertifiedKey) {
+    if let Ok((_, cert)) = X509Certificate::from_der(&certified_key.cert[0]) {
+        TLS_CERTIFICATE_EXPIRY.set(cert.validity().not_after.timestamp() as f64);
+    }
+}
+
+fn load_verifier(config: &TlsConfig, provider: &Arc<CryptoProvider>) -> Result<Arc<dyn ClientCertVerifier>, String> {
+    let Some(ca_file) = &config.client_ca_file else {
+        return Ok(WebPkiClientVerifier::no_client_auth());
+    };
+
+    let mut roots = RootCertStore::empty();
+    for cert in read_certs(ca_file)? {
+        roots.add(cert).map_err(|e| format!("{}: {}", ca_file.display(), e))?;
+    }
+    let builder = WebPkiClientVerifier::builder_with_provider(Arc::new(roots), provider.clone());
+    let builder = match config.client_auth {
+        ClientAuth::Optional => builder.allow_unauthenticated(),
+        ClientAuth::Required => builder,
+    };
+    builder.build().map_err(|e| format!("{}: {}", ca_file.display(), e))
+}
+
+fn read_certs(path: &Path) -> Result<Vec<CertificateDer<'static>>, String> {
+    let certs = File::open(path)
+        .map_err(|e| e.to_string())
+        .and_then(|file| {
+            rustls_pemfile::certs(&mut BufReader::new(file))
+                .collect::<Result<Vec<_>, _>>()
+                .map_err(|e| e.to_string())
+        })
+        .map_err(|e| format!("{}: {}", path.display(), e))?;
+
+    if certs.is_empty() {
+        return Err(format!("{}: no certificates found", path.display()));
+    }
+    Ok(certs)
+}
+
+#[derive(Debug)]
+struct ReloadingResolver(Arc<TlsCertificates>);
+
+impl ResolvesServerCert for ReloadingResolver {
+    fn resolve(&self, _client_hello: ClientHello<'_>) -> Option<Arc<CertifiedKey>> {
+        Some(self.0.certified_key.read().unwrap().clone())
+    }
+}
+
+/// Delegates to the verifier for the current CA bundle.
+#[derive(Debug)]
+struct ReloadingVerifier(Arc<TlsCertificates>);
+
+impl ReloadingVerifier {
+    fn current(&self) -> Arc<dyn ClientCertVerifier> {
+        self.0.verifier.read().unwrap().clone()
+    }
+}
+
+impl ClientCertVerifier for ReloadingVerifier {
+    fn client_auth_mandatory(&self) -> bool {
+        self.0.config.client_auth == ClientAuth::Required
+    }
+
+    // Hints only help clients with several certificates pick one, and can't
+    // be borrowed from a bundle that may be swapped out
+    fn root_hint_subjects(&self) -> &[DistinguishedName] {
+        &[]
+    }
+
+    fn verify_client_cert(
+        &self,
+        end_entity: &CertificateDer<'_>,
+        intermediates: &[CertificateDer<'_>],
+        now: UnixTime,
+    ) -> Result<ClientCertVerified, rustls::Error> {
+        self.current().verify_client_cert(end_entity, intermediates, now)
+    }
+
+    fn verify_tls12_signature(
+        &self,
+        message: &[u8],
+        cert: &CertificateDer<'_>,
+        dss: &DigitallySignedStruct,
+    ) -> Result<HandshakeSignatureValid, rustls::Error> {
+        self.current().verify_tls12_signature(message, cert, dss)
+    }
+
+    fn verify_tls13_signature(
+        &self,
+        message: &[u8],
+        cert: &CertificateDer<'_>,
+        dss: &DigitallySignedStruct,
+    ) -> Result<HandshakeSignatureValid, rustls::Error> {
+        self.current().verify_tls13_signature(message, cert, dss)
+    }
+
+    fn supported_verify_schemes(&self) -> Vec<SignatureScheme> {
+        self.current().supported_verify_schemes()
+    }
+}
+
+impl std::fmt::Debug for TlsCertificates {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("TlsCertificates").field("config", &self.config).finish_non_exhaustive()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rcgen::{BasicConstraints, CertificateParams, DnType, ExtendedKeyUsagePurpose, IsCa, KeyPair};
+    use std::path::PathBuf;
+
+    struct Issuer {
+        cert: rcgen::Certificate,
+        key: KeyPair,
+    }
+
+    fn ca(name: &str) -> Issuer {
+        let key = KeyPair::generate().unwrap();
+        let mut params = CertificateParams::new(Vec::new()).unwrap();
+        params.distinguished_name.push(DnType::CommonName, name);
+        params.is_ca = IsCa::Ca(BasicConstraints::Unconstrained);
+        Issuer {
+            cert: params.self_signed(&key).unwrap(),
+            key,
+        }
+    }
+
+    /// Returns the certificate and key PEM for a leaf signed by `issuer`.
+    fn issue(issuer: &Issuer, name: &str, purpose: ExtendedKeyUsagePurpose) -> (rcgen::Certificate, String) {
+        let key = KeyPair::generate().unwrap();
+        let mut params = CertificateParams::new(vec![name.to_string()]).unwrap();
+        params.distinguished_name.push(DnType::CommonName, name);
+        params.distinguished_name.push(DnType::OrganizationName, "Example");
+        params.extended_key_usages = vec![purpose];
+        (params.signed_by(&key, &issuer.cert, &issuer.key).unwrap(), key.serialize_pem())
+    }
+
+    fn temp_dir(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("id-generator-tls-{}-{}", std::process::id(), name));
+        std::fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    /// Writes a server certificate for `name` and the CA bundle into `dir`.
+    fn write_files(dir: &Path, issuer: &Issuer, name: &str) -> TlsConfig {
+        let (cert, key) = issue(issuer, name, ExtendedKeyUsagePurpose::ServerAuth);
+        std::fs::write(dir.join("tls.crt"), cert.pem()).unwrap();
+        std::fs::write(dir.join("tls.key"), key).unwrap();
+        std::fs::write(dir.join("ca.crt"), issuer.cert.pem()).unwrap();
+        TlsConfig {
+            cert_file: dir.join("tls.crt"),
+            key_file: dir.join("tls.key"),
+            client_ca_file: Some(dir.join("ca.crt")),
+            client_auth: ClientAuth::Required,
+            reload_interval_secs: 1,
+        }
+    }
+
+    fn served_subject(certificates: &TlsCertificates) -> String {
+        let certified_key = certificates.certified_key.read().unwrap().clone();
+        ClientIdentity::from_certificate(&certified_key.cert[0]).unwrap().subject
+    }
+
+    #[test]
+    fn test_load_errors_name_the_file() {
+        let dir = temp_dir("errors");
+        let issuer = ca("Test CA");
+        let mut config = write_files(&dir, &issuer, "first.example");
+        assert!(TlsCertificates::load(config.clone()).unwrap().server_config().is_ok());
+
+        // Key from a different certificate
+        let (_, other_key) = issue(&issuer, "other.example", ExtendedKeyUsagePurpose::ServerAuth);
+        std::fs::write(&config.key_file, other_key).unwrap();
+        let err = TlsCertificates::load(config.clone()).unwrap_err();
+        assert!(err.contains("tls.key"), "{}", err);
+
+        std::fs::write(&config.cert_file, "").unwrap();
+        let err = TlsCertificates::load(config.clone()).unwrap_err();
+        assert!(err.ends_with("tls.crt: no certificates found"), "{}", err);
+
+        config.client_ca_file = Some(dir.join("missing.crt"));
+        write_files(&dir, &issuer, "first.example");
+        let err = TlsCertificates::load(config).unwrap_err();
+        assert!(err.contains("missing.crt"), "{}", err);
+
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn test_reload_when_files_change() {
+        let dir = temp_dir("reload");
+        let issuer = ca("Test CA");
+        let config = write_files(&dir, &issuer, "first.example");
+        let certificates = TlsCertificates::load(config.clone()).unwrap();
+        assert!(served_subject(&certificates).contains("CN=first.example"));
+        assert_eq!(certificates.reload_if_changed(), Ok(false));
+
+        write_files(&dir, &issuer, "second.example");
+        assert_eq!(certificates.reload_if_changed(), Ok(true));
+        assert!(served_subject(&certificates).contains("CN=second.example"));
+        assert_eq!(certificates.reload_if_changed(), Ok(false));
+
+        // A broken file keeps the previous certificate in use
+        std::fs::write(&config.key_file, "not a key").unwrap();
+        assert!(certificates.reload_if_changed().is_err());
+        assert!(served_subject(&certificates).contains("CN=second.example"));
+        assert_eq!(certificates.reload_if_changed(), Ok(false), "Only retried once the files change again");
+
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn test_client_verifier_follows_ca_bundle() {
+        let dir = temp_dir("verifier");
+        let first_ca = ca("First CA");
+        let second_ca = ca("Second CA");
+        let mut config = write_files(&dir, &first_ca, "server.example");
+        let (first_client, _) = issue(&first_ca, "billing", ExtendedKeyUsagePurpose::ClientAuth);
+        let (second_client, _) = issue(&second_ca, "billing", ExtendedKeyUsagePurpose::ClientAuth);
+
+        let certificates = TlsCertificates::load(config.clone()).unwrap();
+        let verifier = ReloadingVerifier(certificates.clone());
+        let verify = |cert: &rcgen::Certificate| verifier.verify_client_cert(cert.der(), &[], UnixTime::now()).is_ok();
+        assert!(verifier.client_auth_mandatory());
+        assert!(verify(&first_client));
+        assert!(!verify(&second_client));
+
+        std::fs::write(config.client_ca_file.as_ref().unwrap(), second_ca.cert.pem()).unwrap();
+        certificates.reload().unwrap();
+        assert!(!verify(&first_client));
+        assert!(verify(&second_client));
+
+        config.client_auth = ClientAuth::Optional;
+        let certificates = TlsCertificates::load(config).unwrap();
+        assert!(!ReloadingVerifier(certificates).client_auth_mandatory());
+
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn test_client_identity() {
+        let (cert, _) = issue(&ca("Test CA"), "billing", ExtendedKeyUsagePurpose::ClientAuth);
+        let identity = ClientIdentity::from_certificate(cert.der()).unwrap();
+        assert_eq!(identity.subject, "CN=billing, O=Example");
+
+        assert_eq!(ClientIdentity::from_certificate(&CertificateDer::from(vec![1, 2, 3])), None);
+    }
+}